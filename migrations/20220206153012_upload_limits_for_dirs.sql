@@ -0,0 +1,7 @@
+-- Add migration script here
+
+ALTER TABLE emote_dir ADD COLUMN max_upload_bytes BIGINT;
+ALTER TABLE emote_dir ADD COLUMN max_upload_width INT;
+ALTER TABLE emote_dir ADD COLUMN max_upload_height INT;
+ALTER TABLE emote_dir ADD COLUMN max_upload_frames INT;
+ALTER TABLE emote_dir ADD COLUMN max_upload_total_pixels BIGINT;
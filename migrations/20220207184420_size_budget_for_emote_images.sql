@@ -0,0 +1,15 @@
+-- Add migration script here
+
+ALTER TABLE emote_image ADD COLUMN derivative_key TEXT;
+ALTER TABLE emote_image ADD COLUMN byte_budget INT;
+ALTER TABLE emote_image ADD COLUMN encode_quality INT;
+ALTER TABLE emote_image ADD COLUMN encode_colours INT;
+ALTER TABLE emote_image ADD COLUMN encode_frame_step INT;
+ALTER TABLE emote_image ADD COLUMN encode_scale DOUBLE PRECISION;
+
+-- everything before this was a plain width
+UPDATE emote_image SET derivative_key = width || 'x' WHERE original = false;
+
+-- two images can now have the same size with different settings
+ALTER TABLE emote_image DROP CONSTRAINT unique_size_per_emote;
+ALTER TABLE emote_image ADD CONSTRAINT unique_derivative_per_emote UNIQUE (emote_uuid, derivative_key);
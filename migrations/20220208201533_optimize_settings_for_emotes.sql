@@ -0,0 +1,8 @@
+-- Add migration script here
+
+ALTER TABLE emote ADD COLUMN optimize BOOLEAN;
+ALTER TABLE emote ADD COLUMN optimize_colours INT;
+ALTER TABLE emote ADD COLUMN optimize_dither BOOLEAN;
+ALTER TABLE emote ADD COLUMN optimize_dedup_frames BOOLEAN;
+ALTER TABLE emote ADD COLUMN optimize_transparent_diff BOOLEAN;
+ALTER TABLE emote ADD COLUMN optimize_palette_png BOOLEAN;
@@ -0,0 +1,6 @@
+-- Add migration script here
+
+ALTER TABLE emote_image ADD COLUMN content_hash TEXT;
+ALTER TABLE emote_image ADD COLUMN perceptual_hash BIGINT;
+
+CREATE INDEX emote_image_content_hash ON emote_image (content_hash);
@@ -0,0 +1,7 @@
+-- Add migration script here
+
+ALTER TABLE emote ADD COLUMN trim BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE emote ADD COLUMN pad_square BOOLEAN NOT NULL DEFAULT false;
+
+ALTER TABLE emote_dir ADD COLUMN default_trim BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE emote_dir ADD COLUMN default_pad_square BOOLEAN NOT NULL DEFAULT false;
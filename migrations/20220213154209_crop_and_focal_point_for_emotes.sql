@@ -0,0 +1,14 @@
+-- Add migration script here
+
+ALTER TABLE emote ADD COLUMN crop_left INTEGER;
+ALTER TABLE emote ADD COLUMN crop_top INTEGER;
+ALTER TABLE emote ADD COLUMN crop_width INTEGER;
+ALTER TABLE emote ADD COLUMN crop_height INTEGER;
+ALTER TABLE emote ADD COLUMN focal_x INTEGER;
+ALTER TABLE emote ADD COLUMN focal_y INTEGER;
+
+ALTER TABLE emote ADD CONSTRAINT emote_crop_complete CHECK (
+    (crop_left IS NULL AND crop_top IS NULL AND crop_width IS NULL AND crop_height IS NULL)
+    OR (crop_left >= 0 AND crop_top >= 0 AND crop_width > 0 AND crop_height > 0)
+);
+ALTER TABLE emote ADD CONSTRAINT emote_focal_point_complete CHECK ((focal_x IS NULL) = (focal_y IS NULL));
@@ -0,0 +1,6 @@
+-- Add migration script here
+
+ALTER TABLE emote_image ADD COLUMN blurhash TEXT;
+ALTER TABLE emote_image ADD COLUMN average_colour TEXT;
+ALTER TABLE emote_image ADD COLUMN dominant_colour TEXT;
+ALTER TABLE emote_image ADD COLUMN animated BOOLEAN;
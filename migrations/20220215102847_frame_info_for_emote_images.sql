@@ -0,0 +1,6 @@
+-- Add migration script here
+
+ALTER TABLE emote_image ADD COLUMN frame_count INTEGER;
+ALTER TABLE emote_image ADD COLUMN frame_delays INTEGER[];
+ALTER TABLE emote_image ADD COLUMN duration INTEGER;
+ALTER TABLE emote_image ADD COLUMN loop_count INTEGER;
@@ -0,0 +1,6 @@
+-- Add migration script here
+
+CREATE TYPE SCALE_KERNEL AS ENUM ('nearest', 'linear', 'cubic', 'lanczos');
+
+-- lanczos is what every emote was resized with before
+ALTER TABLE emote ADD COLUMN scale_kernel SCALE_KERNEL NOT NULL DEFAULT 'lanczos';
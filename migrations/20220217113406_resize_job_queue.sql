@@ -0,0 +1,18 @@
+-- Add migration script here
+
+CREATE TYPE RESIZE_JOB_STATE AS ENUM ('queued', 'running', 'done', 'failed');
+
+-- One per resized emote image, the options are the image's derivative_key
+CREATE TABLE IF NOT EXISTS resize_job (
+       uuid UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+       emote_image_uuid UUID REFERENCES emote_image (uuid) ON DELETE CASCADE UNIQUE NOT NULL,
+       state RESIZE_JOB_STATE NOT NULL DEFAULT 'queued',
+       attempts INT NOT NULL DEFAULT 0,
+       last_error TEXT,
+       -- queued jobs wait until then, it's pushed back after every failed attempt
+       run_after TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+       create_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp,
+       modify_time TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX resize_job_queued ON resize_job (run_after) WHERE state = 'queued';
@@ -0,0 +1,16 @@
+-- Add migration script here
+
+-- The PIPELINE_VERSION a resized image was made with, NULL for originals and images made before this
+ALTER TABLE emote_image ADD COLUMN pipeline_version INTEGER;
+
+-- A batch of derivatives being made again, its progress is the state of its jobs
+CREATE TABLE IF NOT EXISTS reprocess_run (
+       uuid UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+       -- what the images will be made with
+       pipeline_version INTEGER NOT NULL,
+       total INTEGER NOT NULL,
+       create_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp
+);
+
+-- the last run that queued the job
+ALTER TABLE resize_job ADD COLUMN reprocess_run_uuid UUID REFERENCES reprocess_run (uuid) ON DELETE SET NULL;
@@ -0,0 +1,14 @@
+-- Add migration script here
+
+-- Stored bytes, named by the sha256 of what's stored so identical images are only stored once
+CREATE TABLE IF NOT EXISTS blob (
+       hash TEXT PRIMARY KEY,
+       size BIGINT NOT NULL,
+       -- how many emote_image rows point at it, the bytes are deleted when it gets to 0
+       ref_count INTEGER NOT NULL DEFAULT 0,
+       create_time TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT current_timestamp
+);
+
+-- NULL for images stored before blobs, those are stored under their own UUID
+ALTER TABLE emote_image ADD COLUMN blob_hash TEXT REFERENCES blob (hash);
+CREATE INDEX emote_image_blob_hash ON emote_image (blob_hash);
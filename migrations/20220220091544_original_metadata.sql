@@ -0,0 +1,5 @@
+-- Add migration script here
+
+-- Whether the stored original carries anything besides the pixels and its colour profile. Only originals that don't
+-- get served as they are, NULL (not checked) counts as yes.
+ALTER TABLE emote_image ADD COLUMN has_metadata BOOLEAN;
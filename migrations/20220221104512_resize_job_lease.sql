@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- A running job belongs to whichever worker claimed it until then, the worker keeps pushing it back while it's
+-- alive. Once it's passed, another worker can take the job over.
+ALTER TABLE resize_job ADD COLUMN locked_until TIMESTAMP WITH TIME ZONE;
+
+-- whatever was running before leases has nobody to keep it
+UPDATE resize_job SET locked_until = current_timestamp WHERE state = 'running';
+
+CREATE INDEX resize_job_running ON resize_job (locked_until) WHERE state = 'running';
@@ -0,0 +1,9 @@
+-- Add migration script here
+
+-- Same as hash_distance() in image_hash.rs, so looking for duplicates doesn't have to load every hash
+CREATE FUNCTION hash_distance(a BIGINT, b BIGINT) RETURNS INTEGER AS $$
+       SELECT length(replace((a # b)::BIT(64)::TEXT, '0', ''))
+$$ LANGUAGE SQL IMMUTABLE STRICT;
+
+-- duplicates are only ever looked for among originals
+CREATE INDEX emote_image_original_content_hash ON emote_image (content_hash) WHERE original;
@@ -1,4 +1,7 @@
+use crate::image::{DuplicatePolicy, OptimizeSettings, UploadLimits, UpscalePolicy};
 use crate::storage::{LocalStorageProviderConfig, S3StorageProviderConfig};
+use crate::types::ReconcilerConfig;
+use crate::worker_pool::WorkerPoolConfig;
 use anyhow::Context;
 use lazy_static::lazy_static;
 use serde::Deserialize;
@@ -26,6 +29,28 @@ pub struct EmotesConfig {
     #[serde(default = "default_bind")]
     pub http_bind: String,
     pub storage_provider: EmotesConfigStorageProvider,
+    #[serde(default)]
+    pub resizer_backend: EmotesConfigResizerBackend,
+    #[serde(default)]
+    pub upload_limits: UploadLimits,
+    #[serde(default)]
+    pub optimize: OptimizeSettings,
+    #[serde(default)]
+    pub duplicates: DuplicatePolicy,
+    // resized images never have metadata, this also strips it from the stored original (the orientation is kept)
+    #[serde(default)]
+    pub strip_original_metadata: bool,
+    #[serde(default)]
+    pub upscale: UpscalePolicy,
+    // how many resized images an emote can get from people asking for new sizes by URL
+    #[serde(default = "default_max_derivatives_per_emote")]
+    pub max_derivatives_per_emote: i64,
+    // for image work and storage IO, which would otherwise block requests
+    #[serde(default)]
+    pub workers: WorkerPoolConfig,
+    // cleans up after crashes and failed uploads
+    #[serde(default)]
+    pub reconciler: ReconcilerConfig,
 }
 
 // TODO move the config structs to the actual storage files
@@ -36,6 +61,37 @@ pub enum EmotesConfigStorageProvider {
     S3(S3StorageProviderConfig),
 }
 
+// Each backend also needs its cargo feature ("vips" or "image") enabled
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum EmotesConfigResizerBackend {
+    Vips,
+    Image,
+}
+
+impl EmotesConfigResizerBackend {
+    pub fn compiled_in(&self) -> bool {
+        match self {
+            EmotesConfigResizerBackend::Vips => cfg!(feature = "vips"),
+            EmotesConfigResizerBackend::Image => cfg!(feature = "image"),
+        }
+    }
+}
+
+impl Default for EmotesConfigResizerBackend {
+    fn default() -> Self {
+        if cfg!(feature = "vips") {
+            EmotesConfigResizerBackend::Vips
+        } else {
+            EmotesConfigResizerBackend::Image
+        }
+    }
+}
+
 fn default_bind() -> String {
     "127.0.0.1:8080".to_owned()
 }
+
+fn default_max_derivatives_per_emote() -> i64 {
+    64
+}
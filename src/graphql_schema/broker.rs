@@ -0,0 +1,65 @@
+use futures_channel::mpsc::{channel, Receiver, Sender};
+use log::{error, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::PgPool;
+use std::sync::Mutex;
+
+// Hands everything that's published to every subscriber that's still listening. Publishing is a NOTIFY on the
+// broker's channel and every instance listens for them (see listen_for_events()), so subscribers hear about
+// changes from any of them, like a resize job another instance picked up.
+pub struct Broker<T> {
+    pub channel: &'static str,
+    subscribers: Mutex<Vec<Sender<T>>>,
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> Broker<T> {
+    // how much a subscriber can fall behind before it's dropped, so a client that stopped reading can't pile up
+    // everything that's published since
+    const BUFFER: usize = 64;
+
+    pub fn new(channel: &'static str) -> Self {
+        Self {
+            channel,
+            subscribers: Mutex::new(vec![]),
+        }
+    }
+
+    // It's only telling subscribers about something that already happened, so failing to is logged and that's it
+    pub async fn publish(&self, pool: &PgPool, value: &T) {
+        let payload = match serde_json::to_string(value) {
+            Ok(payload) => payload,
+            Err(e) => return error!("Couldn't serialize a {} event: {}", self.channel, e),
+        };
+        // not query!, it can't describe pg_notify() returning void
+        if let Err(e) = sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(self.channel)
+            .bind(payload)
+            .execute(pool)
+            .await
+        {
+            error!("Couldn't publish a {} event: {}", self.channel, e);
+        }
+    }
+
+    // a notification that came in on the broker's channel
+    pub fn deliver(&self, payload: &str) {
+        let value: T = match serde_json::from_str(payload) {
+            Ok(value) => value,
+            Err(e) => return warn!("Ignoring a malformed {} event: {}", self.channel, e),
+        };
+        // a closed subscription's receiver is gone and a full one's isn't keeping up, both get dropped here
+        let mut subscribers = self.subscribers.lock().unwrap();
+        *subscribers = std::mem::take(&mut *subscribers)
+            .into_iter()
+            .filter_map(|mut subscriber| {
+                subscriber.try_send(value.clone()).ok().map(|_| subscriber)
+            })
+            .collect();
+    }
+
+    pub fn subscribe(&self) -> Receiver<T> {
+        let (sender, receiver) = channel(Self::BUFFER);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+}
@@ -1,3 +1,5 @@
+pub mod broker;
 pub mod guards;
 pub mod mutation;
 pub mod query;
+pub mod subscription;
@@ -5,9 +5,11 @@ use sqlx::PgPool;
 use std::sync::Arc;
 use uuid::Uuid;
 
+use crate::config::EMOTES_CONFIG;
 use crate::graphql_schema::guards::{
     AdminGuard, Column, FirstRunGuard, Table, UserDirPrivilegedGuard, UserOwnsGuard,
 };
+use crate::image::{Animation, DisplayOptions, OutputOptions, ScaleKernel, Transforms};
 use crate::types::*;
 
 pub struct Mutation;
@@ -164,6 +166,54 @@ impl Mutation {
         Mutation::delete_helper(result).await // we can use the delete helper since it just checks how many rows were changed. TODO change the delete helper's name
     }
 
+    // Leaving a limit out (or null) goes back to the limit in the config file
+    #[graphql(guard = "UserDirPrivilegedGuard::new(dir_uuid).or(AdminGuard)")]
+    async fn set_dir_upload_limits(
+        &self,
+        ctx: &Context<'_>,
+        dir_uuid: Uuid,
+        max_bytes: Option<i64>,
+        max_width: Option<i32>,
+        max_height: Option<i32>,
+        max_frames: Option<i32>,
+        max_total_pixels: Option<i64>,
+    ) -> Result<EmoteDir> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query_as!(
+            EmoteDir,
+            "UPDATE emote_dir SET max_upload_bytes = ($1), max_upload_width = ($2), max_upload_height = ($3), max_upload_frames = ($4), max_upload_total_pixels = ($5) WHERE uuid = ($6) RETURNING *",
+            max_bytes,
+            max_width,
+            max_height,
+            max_frames,
+            max_total_pixels,
+            dir_uuid
+        )
+        .fetch_one(&**pool)
+        .await?)
+    }
+
+    // what uploads to this dir get when they don't say whether to trim or pad
+    #[graphql(guard = "UserDirPrivilegedGuard::new(dir_uuid).or(AdminGuard)")]
+    async fn set_dir_normalization_defaults(
+        &self,
+        ctx: &Context<'_>,
+        dir_uuid: Uuid,
+        trim: bool,
+        pad_square: bool,
+    ) -> Result<EmoteDir> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(sqlx::query_as!(
+            EmoteDir,
+            "UPDATE emote_dir SET default_trim = ($1), default_pad_square = ($2) WHERE uuid = ($3) RETURNING *",
+            trim,
+            pad_square,
+            dir_uuid
+        )
+        .fetch_one(&**pool)
+        .await?)
+    }
+
     #[graphql(guard = "UserOwnsGuard::new(Table::EmoteDir, Column::UUID(dir_uuid)).or(AdminGuard)")]
     async fn upload_emote(
         &self,
@@ -172,10 +222,27 @@ impl Mutation {
         slug: String,
         emote_file: Upload,
         emote_type: EmoteType,
+        trim: Option<bool>,
+        pad_square: Option<bool>,
+        crop: Option<CropInput>,
+        focal_point: Option<FocalPointInput>,
+        scale_kernel: Option<ScaleKernel>,
     ) -> Result<Emote> {
         let pool = ctx.data::<Arc<PgPool>>()?;
         let file_value = emote_file.value(ctx)?;
-        Emote::insert(Arc::clone(&pool), dir_uuid, slug, file_value, emote_type).await
+        Emote::insert(
+            Arc::clone(&pool),
+            dir_uuid,
+            slug,
+            file_value,
+            emote_type,
+            trim,
+            pad_square,
+            crop,
+            focal_point,
+            scale_kernel,
+        )
+        .await
     }
 
     // It will cascade and delete all emote images
@@ -189,17 +256,191 @@ impl Mutation {
         Mutation::delete_helper(result).await
     }
 
+    // Leaving a setting out (or null) goes back to the setting in the config file. The emote's resized images are made again.
+    #[graphql(guard = "UserOwnsGuard::new(Table::Emote, Column::UUID(emote_uuid)).or(AdminGuard)")]
+    async fn set_emote_optimization(
+        &self,
+        ctx: &Context<'_>,
+        emote_uuid: Uuid,
+        enabled: Option<bool>,
+        colours: Option<i32>,
+        dither: Option<bool>,
+        dedup_frames: Option<bool>,
+        transparent_diff: Option<bool>,
+        palette_png: Option<bool>,
+    ) -> Result<Emote> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        if let Some(colours) = colours {
+            if colours < 2 || colours > 256 {
+                return Err("colours must be between 2 and 256".into());
+            }
+        }
+
+        let emote = sqlx::query_as!(
+            Emote,
+            "UPDATE emote SET optimize = ($1), optimize_colours = ($2), optimize_dither = ($3), optimize_dedup_frames = ($4), optimize_transparent_diff = ($5), optimize_palette_png = ($6) WHERE uuid = ($7) RETURNING emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time, emote.optimize, emote.optimize_colours, emote.optimize_dither, emote.optimize_dedup_frames, emote.optimize_transparent_diff, emote.optimize_palette_png, emote.trim, emote.pad_square, emote.crop_left, emote.crop_top, emote.crop_width, emote.crop_height, emote.focal_x, emote.focal_y, emote.scale_kernel as \"scale_kernel!: ScaleKernel\"",
+            enabled,
+            colours,
+            dither,
+            dedup_frames,
+            transparent_diff,
+            palette_png,
+            emote_uuid
+        )
+        .fetch_one(&**pool)
+        .await?;
+
+        EmoteImage::regenerate_derivatives(Arc::clone(&pool), emote_uuid).await?;
+
+        Ok(emote)
+    }
+
+    // The original is kept untouched, so this can be changed any time. The emote's resized images are made again.
+    #[graphql(guard = "UserOwnsGuard::new(Table::Emote, Column::UUID(emote_uuid)).or(AdminGuard)")]
+    async fn set_emote_normalization(
+        &self,
+        ctx: &Context<'_>,
+        emote_uuid: Uuid,
+        trim: bool,
+        pad_square: bool,
+    ) -> Result<Emote> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let emote = sqlx::query_as!(
+            Emote,
+            "UPDATE emote SET trim = ($1), pad_square = ($2) WHERE uuid = ($3) RETURNING emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time, emote.optimize, emote.optimize_colours, emote.optimize_dither, emote.optimize_dedup_frames, emote.optimize_transparent_diff, emote.optimize_palette_png, emote.trim, emote.pad_square, emote.crop_left, emote.crop_top, emote.crop_width, emote.crop_height, emote.focal_x, emote.focal_y, emote.scale_kernel as \"scale_kernel!: ScaleKernel\"",
+            trim,
+            pad_square,
+            emote_uuid
+        )
+        .fetch_one(&**pool)
+        .await?;
+
+        EmoteImage::regenerate_derivatives(Arc::clone(&pool), emote_uuid).await?;
+
+        Ok(emote)
+    }
+
+    // Leaving either out clears it. The emote's resized images are made again.
+    #[graphql(guard = "UserOwnsGuard::new(Table::Emote, Column::UUID(emote_uuid)).or(AdminGuard)")]
+    async fn set_emote_crop(
+        &self,
+        ctx: &Context<'_>,
+        emote_uuid: Uuid,
+        crop: Option<CropInput>,
+        focal_point: Option<FocalPointInput>,
+    ) -> Result<Emote> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        check_crop(crop, focal_point)?;
+
+        let emote = sqlx::query_as!(
+            Emote,
+            "UPDATE emote SET crop_left = ($1), crop_top = ($2), crop_width = ($3), crop_height = ($4), focal_x = ($5), focal_y = ($6) WHERE uuid = ($7) RETURNING emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time, emote.optimize, emote.optimize_colours, emote.optimize_dither, emote.optimize_dedup_frames, emote.optimize_transparent_diff, emote.optimize_palette_png, emote.trim, emote.pad_square, emote.crop_left, emote.crop_top, emote.crop_width, emote.crop_height, emote.focal_x, emote.focal_y, emote.scale_kernel as \"scale_kernel!: ScaleKernel\"",
+            crop.map(|x| x.left),
+            crop.map(|x| x.top),
+            crop.map(|x| x.width),
+            crop.map(|x| x.height),
+            focal_point.map(|x| x.x),
+            focal_point.map(|x| x.y),
+            emote_uuid
+        )
+        .fetch_one(&**pool)
+        .await?;
+
+        EmoteImage::regenerate_derivatives(Arc::clone(&pool), emote_uuid).await?;
+
+        Ok(emote)
+    }
+
+    // Nearest is for pixel art. The emote's resized images are made again.
+    #[graphql(guard = "UserOwnsGuard::new(Table::Emote, Column::UUID(emote_uuid)).or(AdminGuard)")]
+    async fn set_emote_scale_kernel(
+        &self,
+        ctx: &Context<'_>,
+        emote_uuid: Uuid,
+        scale_kernel: ScaleKernel,
+    ) -> Result<Emote> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let emote = sqlx::query_as!(
+            Emote,
+            "UPDATE emote SET scale_kernel = ($1) WHERE uuid = ($2) RETURNING emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time, emote.optimize, emote.optimize_colours, emote.optimize_dither, emote.optimize_dedup_frames, emote.optimize_transparent_diff, emote.optimize_palette_png, emote.trim, emote.pad_square, emote.crop_left, emote.crop_top, emote.crop_width, emote.crop_height, emote.focal_x, emote.focal_y, emote.scale_kernel as \"scale_kernel!: ScaleKernel\"",
+            scale_kernel as ScaleKernel,
+            emote_uuid
+        )
+        .fetch_one(&**pool)
+        .await?;
+
+        EmoteImage::regenerate_derivatives(Arc::clone(&pool), emote_uuid).await?;
+
+        Ok(emote)
+    }
+
+    // the emote's URL changes with it, slugs are unique across every dir
+    #[graphql(guard = "UserOwnsGuard::new(Table::Emote, Column::UUID(emote_uuid)).or(AdminGuard)")]
+    async fn rename_emote(
+        &self,
+        ctx: &Context<'_>,
+        emote_uuid: Uuid,
+        slug: String,
+    ) -> Result<Emote> {
+        check_slug(&slug)?;
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let old_slug = match Emote::by_uuid(Arc::clone(&pool), emote_uuid).await? {
+            Some(emote) => emote.slug,
+            None => return Err("emote does not exist".into()),
+        };
+
+        let emote = sqlx::query_as!(
+            Emote,
+            "UPDATE emote SET slug = ($1), modify_time = current_timestamp WHERE uuid = ($2) RETURNING emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time, emote.optimize, emote.optimize_colours, emote.optimize_dither, emote.optimize_dedup_frames, emote.optimize_transparent_diff, emote.optimize_palette_png, emote.trim, emote.pad_square, emote.crop_left, emote.crop_top, emote.crop_width, emote.crop_height, emote.focal_x, emote.focal_y, emote.scale_kernel as \"scale_kernel!: ScaleKernel\"",
+            slug,
+            emote_uuid
+        )
+        .fetch_one(&**pool)
+        .await?;
+
+        if emote.slug != old_slug {
+            EmoteDirChange::publish(&pool, &emote, EmoteDirChangeKind::Renamed, Some(old_slug))
+                .await;
+        }
+        Ok(emote)
+    }
+
     // manually dispatch resize
     #[graphql(guard = "UserOwnsGuard::new(Table::Emote, Column::UUID(emote_uuid)).or(AdminGuard)")]
     async fn dispatch_emote_image_resize(
         &self,
         ctx: &Context<'_>,
         emote_uuid: Uuid,
-        width: i32,
-        height: Option<i32>,
+        width: u32,
+        height: Option<u32>,
+        fit_bytes: Option<u32>,
+        transforms: Option<String>,
+        animation: Option<String>,
+        cover: Option<bool>,
+        kernel: Option<ScaleKernel>,
+        output: Option<String>,
     ) -> Result<bool> {
         let pool = ctx.data::<Arc<PgPool>>()?;
-        EmoteImage::resize_image(Arc::clone(&pool), emote_uuid, width, height).await
+        let options = DisplayOptions {
+            byte_budget: fit_bytes,
+            transforms: Transforms::parse(&transforms.unwrap_or_default())?,
+            animation: Animation::parse(&animation.unwrap_or_default())?,
+            cover: cover.unwrap_or(false),
+            kernel,
+            output: OutputOptions::parse(&output.unwrap_or_default())?,
+            ..DisplayOptions::sized(width, height)
+        };
+        let emote = match Emote::by_uuid(Arc::clone(&pool), emote_uuid).await? {
+            Some(emote) => emote,
+            None => return Err("emote does not exist".into()),
+        };
+        match EmoteImage::serve(Arc::clone(&pool), &emote, options).await? {
+            // nothing to make
+            Serve::Original(_) => Ok(false),
+            Serve::Resized(options) => {
+                EmoteImage::resize_image(Arc::clone(&pool), emote_uuid, options).await
+            }
+        }
     }
 
     #[graphql(guard = "UserOwnsGuard::new(Table::EmoteImage, Column::UUID(uuid)).or(AdminGuard)")]
@@ -209,4 +450,84 @@ impl Mutation {
 
         Mutation::delete_helper(result).await
     }
+
+    // one-off fix for images saved with the wrong content type, returns the number of images repaired
+    #[graphql(guard = "AdminGuard")]
+    async fn repair_emote_image_content_types(&self, ctx: &Context<'_>) -> Result<u64> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        EmoteImage::repair_content_types(Arc::clone(&pool)).await
+    }
+
+    // one-off for originals uploaded before duplicate detection, returns the number of images hashed
+    #[graphql(guard = "AdminGuard")]
+    async fn backfill_emote_image_hashes(&self, ctx: &Context<'_>) -> Result<u64> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        EmoteImage::backfill_hashes(Arc::clone(&pool)).await
+    }
+
+    // one-off for originals uploaded before placeholders, returns the number of images filled in
+    #[graphql(guard = "AdminGuard")]
+    async fn backfill_emote_placeholders(&self, ctx: &Context<'_>) -> Result<u64> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        EmoteImage::backfill_placeholders(Arc::clone(&pool)).await
+    }
+
+    // one-off for images made before frame info was recorded, returns the number of images filled in
+    #[graphql(guard = "AdminGuard")]
+    async fn backfill_emote_image_frame_info(&self, ctx: &Context<'_>) -> Result<u64> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        EmoteImage::backfill_frame_info(Arc::clone(&pool)).await
+    }
+
+    // one-off for images stored before blobs, returns the number of images moved into one
+    #[graphql(guard = "AdminGuard")]
+    async fn backfill_emote_image_blobs(&self, ctx: &Context<'_>) -> Result<u64> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        EmoteImage::backfill_blobs(Arc::clone(&pool)).await
+    }
+
+    // puts a failed resize job back in the queue with all its attempts
+    #[graphql(guard = "AdminGuard")]
+    async fn retry_resize_job(&self, ctx: &Context<'_>, uuid: Uuid) -> Result<ResizeJob> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        Ok(ResizeJob::retry(Arc::clone(&pool), uuid)
+            .await?
+            .ok_or("There's no failed resize job with this UUID")?)
+    }
+
+    // returns the number of jobs requeued
+    #[graphql(guard = "AdminGuard")]
+    async fn retry_failed_resize_jobs(&self, ctx: &Context<'_>) -> Result<u64> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        ResizeJob::retry_failed(Arc::clone(&pool)).await
+    }
+
+    // Makes resized images again from their originals in the background, eg. after PIPELINE_VERSION is bumped.
+    // Watch how it's going with the run's progress.
+    #[graphql(guard = "AdminGuard")]
+    async fn reprocess_derivatives(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<ReprocessFilter>,
+    ) -> Result<ReprocessRun> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        ReprocessRun::start(Arc::clone(&pool), &filter.unwrap_or_default()).await
+    }
+
+    // finds images and files that don't line up and fixes them, only reports them on a dry run
+    #[graphql(guard = "AdminGuard")]
+    async fn reconcile_storage(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = true)] dry_run: bool,
+        grace_minutes: Option<i64>,
+    ) -> Result<ReconcileReport> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        ReconcileReport::run(
+            Arc::clone(&pool),
+            dry_run,
+            grace_minutes.unwrap_or(EMOTES_CONFIG.reconciler.grace_minutes),
+        )
+        .await
+    }
 }
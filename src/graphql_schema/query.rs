@@ -1,10 +1,15 @@
+use anyhow::anyhow;
 use async_graphql::*;
 use sqlx::PgPool;
+use std::collections::HashMap;
 use std::sync::Arc;
 use uuid::Uuid;
 
-use crate::graphql_schema::guards::{AdminGuard, Column, Table, UserOwnsGuard};
+use crate::config::EMOTES_CONFIG;
+use crate::graphql_schema::guards::{AdminGuard, Column, Table, UserOwnership, UserOwnsGuard};
+use crate::image::{upload_error, ImageHashes, ImageType, ImageTypeHandler};
 use crate::types::*;
+use crate::worker_pool::WORKER_POOL;
 
 pub struct Query;
 
@@ -158,4 +163,152 @@ impl Query {
             .fetch_all(&**pool)
             .await?)
     }
+
+    // eg. the failed ones, to see why and retry them
+    #[graphql(guard = "AdminGuard")]
+    async fn resize_jobs(
+        &self,
+        ctx: &Context<'_>,
+        state: Option<ResizeJobState>,
+    ) -> Result<Vec<ResizeJob>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        ResizeJob::all(Arc::clone(&pool), state).await
+    }
+    #[graphql(guard = "AdminGuard")]
+    async fn resize_job(&self, ctx: &Context<'_>, uuid: Uuid) -> Result<Option<ResizeJob>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        ResizeJob::by_uuid(Arc::clone(&pool), uuid).await
+    }
+
+    #[graphql(guard = "AdminGuard")]
+    async fn reprocess_runs(&self, ctx: &Context<'_>) -> Result<Vec<ReprocessRun>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        ReprocessRun::all(Arc::clone(&pool)).await
+    }
+    #[graphql(guard = "AdminGuard")]
+    async fn reprocess_run(&self, ctx: &Context<'_>, uuid: Uuid) -> Result<Option<ReprocessRun>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        ReprocessRun::by_uuid(Arc::clone(&pool), uuid).await
+    }
+
+    // emotes across every dir that look like the same picture, closest first
+    #[graphql(guard = "AdminGuard")]
+    async fn duplicate_emotes(
+        &self,
+        ctx: &Context<'_>,
+        max_distance: Option<u32>,
+    ) -> Result<Vec<EmoteDuplicate>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let max_distance = max_distance.unwrap_or(EMOTES_CONFIG.duplicates.max_distance);
+
+        let pairs = EmoteImage::all_duplicates(Arc::clone(&pool), max_distance).await?;
+        let emote_uuids: Vec<Uuid> = pairs
+            .iter()
+            .flat_map(|(emote_image, other, _)| vec![emote_image.emote_uuid, other.emote_uuid])
+            .collect();
+        let emotes: HashMap<Uuid, Emote> = Emote::by_uuids(Arc::clone(&pool), &emote_uuids)
+            .await?
+            .into_iter()
+            .map(|emote| (emote.uuid, emote))
+            .collect();
+
+        let mut duplicates = vec![];
+        for (emote_image, other, distance) in pairs {
+            let emote = emotes.get(&emote_image.emote_uuid);
+            let duplicate_of = emotes.get(&other.emote_uuid);
+            if let (Some(emote), Some(duplicate_of)) = (emote, duplicate_of) {
+                duplicates.push(EmoteDuplicate {
+                    emote: emote.clone(),
+                    duplicate_of: duplicate_of.clone(),
+                    distance,
+                    exact: emote_image.content_hash.is_some()
+                        && emote_image.content_hash == other.content_hash,
+                });
+            }
+        }
+        Ok(duplicates)
+    }
+
+    // Emotes the caller can see that look like an uploaded file or an existing emote, closest first.
+    // Meant for checking whether an emote already exists before uploading it.
+    async fn similar_emotes(
+        &self,
+        ctx: &Context<'_>,
+        file: Option<Upload>,
+        emote_uuid: Option<Uuid>,
+        #[graphql(default = 10)] limit: usize,
+        #[graphql(default = 16)] max_distance: u32,
+    ) -> Result<Vec<SimilarEmote>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let emote_user = ctx.data::<EmoteUser>()?;
+
+        let hashes = match (file, emote_uuid) {
+            (Some(file), None) => {
+                let upload_value = file.value(ctx)?;
+                let limits = &EMOTES_CONFIG.upload_limits;
+                let image_buffer = limits
+                    .read_upload(upload_value.content)
+                    .map_err(upload_error)?;
+                limits.check_header(&image_buffer).map_err(upload_error)?;
+
+                // the content type the client sent can't be trusted to pick a decoder
+                let content_type = match ImageType::sniff(&image_buffer) {
+                    Some(
+                        image_type @ (ImageType::JPEG
+                        | ImageType::PNG
+                        | ImageType::GIF
+                        | ImageType::WEBPStill
+                        | ImageType::WEBPAnimated),
+                    ) => image_type.content_type(),
+                    _ => return Err("Can't search by this type of image".into()),
+                };
+                WORKER_POOL
+                    .try_run(move || {
+                        let image_type_handler =
+                            ImageTypeHandler::from_content_type(content_type, image_buffer)?
+                                .ok_or_else(|| anyhow!("Can't search by this type of image"))?;
+                        Ok(ImageHashes::compute(
+                            &image_type_handler.image_buffer,
+                            &*image_type_handler.image_resizer,
+                        ))
+                    })
+                    .await?
+            }
+            (None, Some(emote_uuid)) => {
+                if !emote_user.administrator
+                    && !Emote::owned_by(ctx, &Column::UUID(emote_uuid), emote_user).await?
+                {
+                    return Err("You don't own this resource; unauthorized".into());
+                }
+                sqlx::query_as!(
+                    EmoteImage,
+                    "SELECT * FROM emote_image WHERE emote_uuid = ($1) AND original = ($2)",
+                    emote_uuid,
+                    true
+                )
+                .fetch_optional(&**pool)
+                .await?
+                .and_then(|original| original.hashes())
+                .ok_or("This emote hasn't been hashed yet")?
+            }
+            _ => return Err("Give either a file or an emote UUID".into()),
+        };
+
+        let mut similar_emotes = vec![];
+        for (emote_image, distance) in
+            EmoteImage::similar_to(Arc::clone(&pool), &hashes, emote_user, max_distance).await?
+        {
+            // an emote is always the most similar to itself
+            if Some(emote_image.emote_uuid) == emote_uuid {
+                continue;
+            }
+            if let Some(emote) = Emote::by_uuid(Arc::clone(&pool), emote_image.emote_uuid).await? {
+                similar_emotes.push(SimilarEmote { emote, distance });
+            }
+            if similar_emotes.len() >= limit {
+                break;
+            }
+        }
+        Ok(similar_emotes)
+    }
 }
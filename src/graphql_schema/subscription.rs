@@ -0,0 +1,24 @@
+use async_graphql::*;
+use futures_util::{future, Stream, StreamExt};
+use uuid::Uuid;
+
+use crate::graphql_schema::guards::{AdminGuard, Column, Table, UserOwnsGuard};
+use crate::types::*;
+
+pub struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    // the emote's resized images being queued, made, retried and finished
+    #[graphql(guard = "UserOwnsGuard::new(Table::Emote, Column::UUID(emote_uuid)).or(AdminGuard)")]
+    async fn emote_processing(&self, emote_uuid: Uuid) -> impl Stream<Item = EmoteImageProgress> {
+        EmoteImageProgress::subscribe()
+            .filter(move |progress| future::ready(progress.emote_uuid == emote_uuid))
+    }
+
+    // emotes added to, removed from or renamed in the dir
+    #[graphql(guard = "UserOwnsGuard::new(Table::EmoteDir, Column::UUID(dir_uuid)).or(AdminGuard)")]
+    async fn dir_changes(&self, dir_uuid: Uuid) -> impl Stream<Item = EmoteDirChange> {
+        EmoteDirChange::subscribe().filter(move |change| future::ready(change.dir_uuid == dir_uuid))
+    }
+}
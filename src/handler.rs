@@ -1,28 +1,34 @@
+use actix_web::http::header::{ACCEPT_RANGES, CONTENT_RANGE, LAST_MODIFIED, RANGE};
 use actix_web::HttpRequest;
 use actix_web::{web, HttpResponse};
 use async_graphql::Response;
 use async_graphql::{
     http::{playground_source, GraphQLPlaygroundConfig},
-    EmptySubscription, Schema, ServerError,
+    Data, Schema, ServerError,
 };
-use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse};
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
+use futures_util::TryStreamExt;
 use sqlx::PgPool;
 use std::sync::Arc;
 
+use crate::image::DisplayOptions;
+use crate::storage::{ByteRange, STORAGE_PROVIDER};
 use crate::types::*;
 use log::info;
 
-use crate::graphql_schema::{mutation::Mutation, query::Query};
+use crate::graphql_schema::{mutation::Mutation, query::Query, subscription::Subscription};
 
 pub async fn graphql_playground() -> HttpResponse {
     HttpResponse::Ok()
         .content_type("text/html; charset=utf8")
-        .body(playground_source(GraphQLPlaygroundConfig::new("/api")))
+        .body(playground_source(
+            GraphQLPlaygroundConfig::new("/api").subscription_endpoint("/api"),
+        ))
 }
 
 pub async fn api_graphql_handler(
     pool: web::Data<Arc<PgPool>>,
-    schema: web::Data<Schema<Query, Mutation, EmptySubscription>>,
+    schema: web::Data<Schema<Query, Mutation, Subscription>>,
     request: HttpRequest,
     graphql_request: GraphQLRequest,
 ) -> GraphQLResponse {
@@ -52,6 +58,32 @@ pub async fn api_graphql_handler(
         err_response("Missing required token field").into()
     }
 }
+// WebSockets can't send headers, so the token comes in the connection_init payload as {"Token": "..."}
+pub async fn api_graphql_ws_handler(
+    pool: web::Data<Arc<PgPool>>,
+    schema: web::Data<Schema<Query, Mutation, Subscription>>,
+    request: HttpRequest,
+    payload: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    let pool = Arc::clone(&pool);
+    GraphQLSubscription::new(Schema::clone(&*schema))
+        .on_connection_init(move |value| async move {
+            let token_str = value
+                .get("Token")
+                .and_then(|token| token.as_str())
+                .ok_or("Missing required token field")?;
+            match SerializedEmoteToken::to_emote_user(pool, token_str).await? {
+                Some(emote_user) => {
+                    let mut data = Data::default();
+                    data.insert(emote_user);
+                    Ok(data)
+                }
+                None => Err("Token is invalid; unauthorized".into()),
+            }
+        })
+        .start(&request, payload)
+}
+
 pub async fn emote_display_handler(
     request: HttpRequest,
     pool: web::Data<Arc<PgPool>>,
@@ -59,7 +91,19 @@ pub async fn emote_display_handler(
     let dir_slug = request.match_info().get("dir_slug").unwrap();
     let emote_slug = request.match_info().get("emote_slug").unwrap();
     let options = request.match_info().get("options").map(|x| x.to_owned());
-    emote_display(pool, dir_slug.to_owned(), emote_slug.to_owned(), options).await
+    let range = request
+        .headers()
+        .get(RANGE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_owned());
+    emote_display(
+        pool,
+        dir_slug.to_owned(),
+        emote_slug.to_owned(),
+        options,
+        range,
+    )
+    .await
 }
 
 async fn emote_display(
@@ -67,6 +111,7 @@ async fn emote_display(
     dir_slug: String,
     emote_slug: String,
     options: Option<String>,
+    range: Option<String>,
 ) -> HttpResponse {
     info!(
         "requested an emote:\n\tdir_slug: {}\n\temote_slug: {}\n\toptions: {:?}",
@@ -91,77 +136,159 @@ async fn emote_display(
     };
 
     if let Ok(Some(emote)) = Emote::by_slug(Arc::clone(&pool), dir_slug + "/" + &emote_slug).await {
-        let (mut width, mut height, mut multiplier) = match emote.emote_type {
-            EmoteType::Standard => (48, None, 1), // height is automatic
-            EmoteType::Sticker => (256, None, 1),
+        let default_width = match emote.emote_type {
+            EmoteType::Standard => 48, // height is automatic
+            EmoteType::Sticker => 256,
         };
 
-        // Parse options
-        // TODO rework this
-        if let Some(options) = options {
-            let options: Vec<&str> = options.split("x").collect();
-            if options.len() == 1 {
-                // just width
-                width = options[0].parse().unwrap();
-            } else if options.len() == 2 {
-                if options[0] == "" {
-                    // multiplier format: "x10"
-                    multiplier = options[1].parse().unwrap();
-                } else {
-                    width = options[0].parse().unwrap();
-                    // you could do 64xx10 and that would omit height
-                    height = if options[1] != "" {
-                        Some(options[1].parse().unwrap())
-                    } else {
-                        None
-                    };
-                }
-            } else if options.len() == 3 {
-                width = options[0].parse().unwrap();
-                height = Some(options[1].parse().unwrap());
-                multiplier = options[2].parse().unwrap();
+        let options = match options {
+            Some(options) => match DisplayOptions::parse(&options, default_width) {
+                Ok(options) => options,
+                Err(e) => return HttpResponse::BadRequest().json(EmoteMsg::new(&e.to_string())),
+            },
+            None => DisplayOptions::sized(default_width, None),
+        };
+
+        // right now, multiplier does nothing
+
+        let options = match EmoteImage::serve(Arc::clone(&pool), &emote, options).await {
+            Ok(Serve::Original(original)) => {
+                return emote_image_response(&original, range.as_deref()).await
             }
-        }
+            Ok(Serve::Resized(options)) => options,
+            Err(e) => return HttpResponse::BadRequest().json(EmoteMsg::new(&e.message)),
+        };
 
-        // this is a "for now" thing TODO delete this part when we implement resizing VipsImages by height and width
-        if let Some(_) = height {
-            return HttpResponse::InternalServerError().json(EmoteMsg::new(
-                    "Emotes cannot be resized by height yet. Please try again without the height, or wait for this feature to be implemented."
-                ));
+        if let Err(e) =
+            EmoteImage::check_derivative_limit(Arc::clone(&pool), emote.uuid, &options).await
+        {
+            return HttpResponse::BadRequest().json(EmoteMsg::new(&e.message));
         }
-        // right now, multiplier does nothing
 
-        let corresponding_emote_image =
-            EmoteImage::by_emote_and_size(Arc::clone(&pool), emote.uuid, width, height).await;
-        if let Ok(None) = corresponding_emote_image {
-            if let Ok(true) =
-                EmoteImage::resize_image(Arc::clone(&pool), emote.uuid, width, height).await
-            {
-                return HttpResponse::NotFound().json(EmoteMsg::new(
-                    "Emote was not created in that size. Emote resizer dispatched.",
-                ));
-            };
-        } else if let Ok(Some(image)) = corresponding_emote_image {
-            if image.processing {
-                return HttpResponse::NotFound()
-                    .json(EmoteMsg::new("Emote resizer is processing this emote."));
+        // whoever asks first queues it, everyone asking at the same time waits for that one resize
+        let image = match EmoteImage::insert_or_get(Arc::clone(&pool), emote.uuid, &options).await {
+            Ok((image, _)) if !image.processing => image,
+            Ok((image, _)) => {
+                match EmoteImage::wait_until_processed(Arc::clone(&pool), image.uuid).await {
+                    Ok(Some(image)) => image,
+                    Ok(None) => {
+                        return HttpResponse::NotFound().json(EmoteMsg::new(
+                            "Emote was deleted while it was being resized.",
+                        ))
+                    }
+                    Err(e) => {
+                        return HttpResponse::InternalServerError().json(EmoteMsg::new(&e.message))
+                    }
+                }
             }
+            Err(e) => return HttpResponse::InternalServerError().json(EmoteMsg::new(&e.message)),
+        };
 
-            return HttpResponse::Ok().content_type(&*image.content_type).body(
-                if let Ok(emote_bytes) = image.get_emote_bytes() {
-                    emote_bytes
-                } else {
-                    return HttpResponse::InternalServerError().json(EmoteMsg::new(
-                        "Failed to open file for emote. You should delete this emote.",
-                    ));
-                },
-            );
+        if image.processing {
+            if let Ok(Some(job)) = ResizeJob::by_emote_image(Arc::clone(&pool), image.uuid).await {
+                if job.state == ResizeJobState::Failed {
+                    return HttpResponse::InternalServerError().json(EmoteMsg::new(&format!(
+                        "Emote resizer failed to make this emote: {}",
+                        job.last_error.unwrap_or_default()
+                    )));
+                }
+            }
+            return HttpResponse::NotFound().json(EmoteMsg::new(
+                "Emote resizer is still processing this emote, try again in a bit.",
+            ));
         }
+
+        return emote_image_response(&image, range.as_deref()).await;
     }
 
     HttpResponse::NotFound().json(EmoteMsg::new("Emote not found")) // TODO use JSON
 }
 
+// Streams the image from storage as it's read, or only the part a Range header asks for
+async fn emote_image_response(image: &EmoteImage, range: Option<&str>) -> HttpResponse {
+    let missing = || {
+        HttpResponse::InternalServerError().json(EmoteMsg::new(
+            "Failed to open file for emote. You should delete this emote.",
+        ))
+    };
+    let head = match STORAGE_PROVIDER.head(image.storage_key()).await {
+        Ok(Some(head)) => head,
+        _ => return missing(),
+    };
+
+    let range = match range.map(|x| parse_range(x, head.size)) {
+        Some(Err(())) => {
+            return HttpResponse::RangeNotSatisfiable()
+                .insert_header((CONTENT_RANGE, format!("bytes */{}", head.size)))
+                .finish()
+        }
+        Some(Ok(range)) => range,
+        None => None,
+    };
+    let stream = match image.stream(range).await {
+        Ok(stream) => stream.map_err(actix_web::error::ErrorInternalServerError),
+        Err(_) => return missing(),
+    };
+
+    let mut response = match range {
+        Some(range) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header((
+                CONTENT_RANGE,
+                format!(
+                    "bytes {}-{}/{}",
+                    range.start,
+                    range.end.unwrap_or(head.size - 1),
+                    head.size
+                ),
+            ));
+            response
+        }
+        None => HttpResponse::Ok(),
+    };
+    response
+        .content_type(&*image.content_type)
+        .insert_header((ACCEPT_RANGES, "bytes"));
+    if let Some(last_modified) = head.last_modified {
+        response.insert_header((LAST_MODIFIED, last_modified.to_rfc2822()));
+    }
+    response.streaming(stream)
+}
+
+// A single "bytes=" range clamped to the size, multiple ranges aren't supported so those get the whole image.
+// Errors if the range starts past the end.
+fn parse_range(header: &str, size: u64) -> Result<Option<ByteRange>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let (start, end) = match (start.trim().parse::<u64>(), end.trim()) {
+        // the last `end` bytes
+        (Err(_), end) if start.trim().is_empty() => match end.parse::<u64>() {
+            Ok(0) => return Err(()),
+            Ok(suffix) => (size.saturating_sub(suffix), size.checked_sub(1).ok_or(())?),
+            Err(_) => return Ok(None),
+        },
+        (Ok(start), "") => (start, size.saturating_sub(1)),
+        (Ok(start), end) => match end.parse::<u64>() {
+            Ok(end) if end >= start => (start, end.min(size.saturating_sub(1))),
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    if start >= size {
+        return Err(());
+    }
+    Ok(Some(ByteRange {
+        start,
+        end: Some(end),
+    }))
+}
+
 use serde::Serialize;
 
 #[derive(Serialize)]
@@ -175,3 +302,52 @@ impl EmoteMsg {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_web::http::StatusCode;
+
+    // everyone asking for a size that doesn't exist yet waits on the one resize the first request queued
+    #[actix_web::test]
+    async fn concurrent_requests_share_one_resize() {
+        let pool = match test_support::pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        actix_web::rt::spawn(listen_for_events(Arc::clone(&pool)));
+        actix_web::rt::spawn(ResizeJob::work(Arc::clone(&pool)));
+        let emote = test_support::emote(Arc::clone(&pool), test_support::png(64, 64)).await;
+
+        let request = || {
+            emote_display(
+                web::Data::new(Arc::clone(&pool)),
+                emote.slug.clone(),
+                emote.slug.clone(),
+                Some("24".to_owned()),
+                None,
+            )
+        };
+        let (first, second) = futures_util::future::join(request(), request()).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+
+        let resized = sqlx::query!(
+            "SELECT uuid FROM emote_image WHERE emote_uuid = ($1) AND original = ($2)",
+            emote.uuid,
+            false
+        )
+        .fetch_all(&*pool)
+        .await
+        .unwrap();
+        assert_eq!(resized.len(), 1);
+        let job = ResizeJob::by_emote_image(Arc::clone(&pool), resized[0].uuid)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(job.attempts, 1);
+
+        Emote::delete(pool, emote.uuid).await.unwrap();
+    }
+}
@@ -0,0 +1,345 @@
+use anyhow::{anyhow, Result};
+
+use crate::image::frames::{decode_gif, encode_gif, set_gif_repeat, CanvasFrame};
+
+// Browsers show anything quicker than this as 100ms, so faster frames get merged instead
+const MIN_DELAY_MS: f64 = 20.0;
+// what browsers show a 0 delay as
+const ZERO_DELAY_MS: u32 = 100;
+
+// Playback changes for animated emotes, eg. "speed=2", "reverse", "pingpong", "frames=0-9" or "loop=3".
+// Ignored for stills.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Animation {
+    // 2 is twice as fast
+    pub speed: f64,
+    pub reverse: bool,
+    // forwards then backwards
+    pub ping_pong: bool,
+    // 0-based and inclusive, taken before anything else
+    pub first_frame: u32,
+    pub last_frame: Option<u32>,
+    // how many times it plays, 0 for forever. None keeps it looping forever like every other emote.
+    pub loops: Option<u16>,
+}
+
+impl Default for Animation {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            reverse: false,
+            ping_pong: false,
+            first_frame: 0,
+            last_frame: None,
+            loops: None,
+        }
+    }
+}
+
+impl Animation {
+    const MIN_SPEED: f64 = 0.1;
+    const MAX_SPEED: f64 = 10.0;
+
+    pub fn is_noop(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // "speed=2,reverse", the same grammar as the modifiers in an emote URL
+    pub fn parse(options: &str) -> Result<Self> {
+        let mut animation = Self::default();
+        for modifier in options.split(',').filter(|modifier| !modifier.is_empty()) {
+            let (key, value) = match modifier.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (modifier, None),
+            };
+            if !animation.push(key, value)? {
+                return Err(anyhow!("Unknown animation option \"{}\"", key));
+            }
+        }
+        Ok(animation)
+    }
+
+    // Sets one modifier. false if `key` isn't an animation option.
+    pub fn push(&mut self, key: &str, value: Option<&str>) -> Result<bool> {
+        match (key, value) {
+            ("speed", Some(value)) => {
+                let speed = value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|speed| (Self::MIN_SPEED..=Self::MAX_SPEED).contains(speed))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "\"speed\" should be a number from {} to {}",
+                            Self::MIN_SPEED,
+                            Self::MAX_SPEED
+                        )
+                    })?;
+                // so 2, 2.0 and 2.001 are the same derivative
+                self.speed = (speed * 100.0).round() / 100.0;
+            }
+            ("reverse", None) => self.reverse = !self.reverse,
+            ("pingpong", None) => self.ping_pong = true,
+            ("frames", Some(value)) => {
+                let invalid = || anyhow!("\"frames\" should look like frames=2-10 or frames=5-");
+                let (first, last) = value.split_once('-').ok_or_else(invalid)?;
+                self.first_frame = first.parse().map_err(|_| invalid())?;
+                self.last_frame = match last {
+                    "" => None,
+                    last => Some(last.parse().map_err(|_| invalid())?),
+                };
+                if self
+                    .last_frame
+                    .map_or(false, |last| last < self.first_frame)
+                {
+                    return Err(invalid());
+                }
+            }
+            ("loop", Some(value)) => {
+                self.loops = Some(value.parse().map_err(|_| {
+                    anyhow!("\"loop\" should be how many times to play it, or 0 for forever")
+                })?)
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // The normalized options, for telling derivatives apart. Empty when there's nothing to do.
+    pub fn key(&self) -> String {
+        let mut modifiers = vec![];
+        if self.first_frame != 0 || self.last_frame.is_some() {
+            modifiers.push(format!(
+                "frames={}-{}",
+                self.first_frame,
+                self.last_frame.map(|x| x.to_string()).unwrap_or_default()
+            ));
+        }
+        if self.reverse {
+            modifiers.push("reverse".to_owned());
+        }
+        if self.ping_pong {
+            modifiers.push("pingpong".to_owned());
+        }
+        if self.speed != 1.0 {
+            modifiers.push(format!("speed={}", self.speed));
+        }
+        if let Some(loops) = self.loops {
+            modifiers.push(format!("loop={}", loops));
+        }
+        modifiers.join(",")
+    }
+
+    // The NETSCAPE loop count, how many more times it repeats after the first. Some(0) is forever, None plays it once.
+    pub fn gif_repeat(&self) -> Option<u16> {
+        match self.loops {
+            None | Some(0) => Some(0),
+            Some(1) => None,
+            Some(loops) => Some(loops - 1),
+        }
+    }
+
+    // Picks, orders and times the frames. `frames` come with their delays in ms, so do the ones that come out.
+    pub fn arrange<T: Clone>(&self, frames: Vec<(T, u32)>) -> Vec<(T, u32)> {
+        let frame_count = frames.len() as u32;
+        if frame_count == 0 {
+            return frames;
+        }
+        // at least one frame is always left, even if the range is past the end
+        let last = self.last_frame.unwrap_or(u32::MAX).min(frame_count - 1);
+        let first = self.first_frame.min(last);
+        let mut frames: Vec<(T, u32)> = frames
+            .into_iter()
+            .skip(first as usize)
+            .take((last - first + 1) as usize)
+            .collect();
+
+        if self.reverse {
+            frames.reverse();
+        }
+        if self.ping_pong && frames.len() > 2 {
+            // the two ends aren't shown twice in a row
+            let back: Vec<(T, u32)> = frames[1..frames.len() - 1].iter().rev().cloned().collect();
+            frames.extend(back);
+        }
+
+        if self.speed == 1.0 {
+            return frames;
+        }
+        let mut timed: Vec<(T, f64)> = vec![];
+        for (frame, delay) in frames {
+            let delay = if delay == 0 { ZERO_DELAY_MS } else { delay } as f64 / self.speed;
+            match timed.last_mut() {
+                // the last frame would go by too quickly to be shown, so this one is dropped and its time goes there
+                Some((_, last_delay)) if *last_delay < MIN_DELAY_MS => *last_delay += delay,
+                _ => timed.push((frame, delay)),
+            }
+        }
+        timed
+            .into_iter()
+            .map(|(frame, delay)| (frame, delay.round().max(MIN_DELAY_MS) as u32))
+            .collect()
+    }
+
+    // For resizers that can only hand back a finished GIF
+    pub fn apply_to_gif(&self, buffer: Vec<u8>) -> Result<Vec<u8>> {
+        let (width, height, frames) = decode_gif(&buffer)?;
+        let frames: Vec<CanvasFrame> = self
+            .arrange(
+                frames
+                    .into_iter()
+                    .map(|frame| {
+                        let delay_ms = frame.delay as u32 * 10;
+                        (frame.pixels, delay_ms)
+                    })
+                    .collect(),
+            )
+            .into_iter()
+            .map(|(pixels, delay_ms)| CanvasFrame {
+                pixels,
+                delay: ((delay_ms + 5) / 10) as u16,
+            })
+            .collect();
+        Ok(set_gif_repeat(
+            encode_gif(width, height, &frames)?,
+            self.gif_repeat(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::frames::gif_repeat;
+
+    fn arranged(options: &str, delays: &[u32]) -> Vec<(usize, u32)> {
+        Animation::parse(options)
+            .unwrap()
+            .arrange(delays.iter().copied().enumerate().collect())
+    }
+
+    #[test]
+    fn keys_are_canonical() {
+        let key = |options| Animation::parse(options).unwrap().key();
+        assert_eq!(key(""), "");
+        assert_eq!(key("reverse,reverse"), "");
+        assert_eq!(key("speed=1"), "");
+        assert_eq!(key("speed=2.001"), "speed=2");
+        assert_eq!(key("frames=2-"), "frames=2-");
+        assert_eq!(key("frames=0-"), "");
+        assert_eq!(
+            key("loop=3,speed=0.5,pingpong,reverse,frames=1-4"),
+            "frames=1-4,reverse,pingpong,speed=0.5,loop=3"
+        );
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        for options in [
+            "speed=0.25,reverse",
+            "frames=3-7,pingpong",
+            "loop=0",
+            "loop=1,speed=10",
+        ]
+        .iter()
+        {
+            let animation = Animation::parse(options).unwrap();
+            assert_eq!(
+                Animation::parse(&animation.key()).unwrap(),
+                animation,
+                "{}",
+                options
+            );
+        }
+    }
+
+    #[test]
+    fn bad_options_are_errors() {
+        for options in [
+            "speed=0",
+            "speed=11",
+            "speed=fast",
+            "speed",
+            "frames=5-2",
+            "frames=3",
+            "frames=a-b",
+            "loop=-1",
+            "bounce",
+        ]
+        .iter()
+        {
+            assert!(Animation::parse(options).is_err(), "{}", options);
+        }
+        assert!(!Animation::default().push("rotate", Some("90")).unwrap());
+    }
+
+    #[test]
+    fn loop_counts() {
+        let repeat = |options| Animation::parse(options).unwrap().gif_repeat();
+        assert_eq!(repeat(""), Some(0));
+        assert_eq!(repeat("loop=0"), Some(0));
+        assert_eq!(repeat("loop=1"), None);
+        assert_eq!(repeat("loop=3"), Some(2));
+    }
+
+    #[test]
+    fn picks_and_orders_frames() {
+        let frames = |arranged: Vec<(usize, u32)>| -> Vec<usize> {
+            arranged.into_iter().map(|(frame, _)| frame).collect()
+        };
+        let delays = [100; 4];
+        assert_eq!(frames(arranged("", &delays)), vec![0, 1, 2, 3]);
+        assert_eq!(frames(arranged("frames=1-2", &delays)), vec![1, 2]);
+        // past the end still leaves the last frame
+        assert_eq!(frames(arranged("frames=10-", &delays)), vec![3]);
+        assert_eq!(frames(arranged("reverse", &delays)), vec![3, 2, 1, 0]);
+        assert_eq!(
+            frames(arranged("pingpong", &delays)),
+            vec![0, 1, 2, 3, 2, 1]
+        );
+        assert_eq!(
+            frames(arranged("frames=1-3,reverse,pingpong", &delays)),
+            vec![3, 2, 1, 2]
+        );
+        assert_eq!(frames(arranged("pingpong", &[100, 100])), vec![0, 1]);
+        assert!(arranged("reverse", &[]).is_empty());
+    }
+
+    #[test]
+    fn speed_changes_the_delays() {
+        assert_eq!(arranged("speed=2", &[100, 40]), vec![(0, 50), (1, 20)]);
+        assert_eq!(arranged("speed=0.5", &[100, 40]), vec![(0, 200), (1, 80)]);
+        // a 0 delay is shown as 100ms
+        assert_eq!(arranged("speed=2", &[0]), vec![(0, 50)]);
+        // too quick to be shown, so every other frame goes and the whole thing takes as long as it should
+        assert_eq!(
+            arranged("speed=10", &[100, 100, 100, 100]),
+            vec![(0, 20), (2, 20)]
+        );
+    }
+
+    #[test]
+    fn applies_to_gifs() {
+        let frames: Vec<CanvasFrame> = (0..3)
+            .map(|i| CanvasFrame {
+                pixels: vec![[i * 100, 0, 0, 255]; 4],
+                delay: 10,
+            })
+            .collect();
+        let gif = encode_gif(2, 2, &frames).unwrap();
+
+        let out = Animation::parse("reverse,speed=2,loop=2")
+            .unwrap()
+            .apply_to_gif(gif)
+            .unwrap();
+        let (width, height, frames) = decode_gif(&out).unwrap();
+        assert_eq!((width, height), (2, 2));
+        assert_eq!(
+            frames
+                .iter()
+                .map(|frame| (frame.pixels[0][0], frame.delay))
+                .collect::<Vec<_>>(),
+            vec![(200, 5), (100, 5), (0, 5)]
+        );
+        assert_eq!(gif_repeat(&out), Some(1));
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::{anyhow, Result};
+
+use crate::image::{
+    Animation, EncodeSettings, OutputOptions, ResizeOptions, ScaleKernel, Transforms,
+};
+
+// What was asked for in the options part of an emote URL, eg. "64", "x2", "64x32", "64xx2", "48,fit=256k", "48,flip=h,grayscale"
+// "48,speed=2,reverse", "64x32,cover", "128,kernel=nearest" or "64,format=jpeg,q=80,bg=000000".
+// The size comes first, the modifiers after it are "key=value" pairs (or just "key" for some transforms) separated by commas.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DisplayOptions {
+    pub width: u32,
+    pub height: Option<u32>,
+    pub multiplier: Option<u32>,
+    // keep lowering the quality until the image is at most this many bytes
+    pub byte_budget: Option<u32>,
+    pub transforms: Transforms,
+    pub animation: Animation,
+    // fill width x height, cutting off what doesn't fit around the emote's focal point
+    pub cover: bool,
+    // None uses the emote's kernel
+    pub kernel: Option<ScaleKernel>,
+    pub output: OutputOptions,
+}
+
+impl DisplayOptions {
+    // nobody needs an emote bigger than this, and it keeps a request from making a huge image
+    pub const MAX_WIDTH: u32 = 2048;
+
+    pub fn sized(width: u32, height: Option<u32>) -> Self {
+        Self {
+            width,
+            height,
+            multiplier: None,
+            byte_budget: None,
+            transforms: Transforms::default(),
+            animation: Animation::default(),
+            cover: false,
+            kernel: None,
+            output: OutputOptions::default(),
+        }
+    }
+
+    pub fn parse(options: &str, default_width: u32) -> Result<Self> {
+        let mut display_options = Self::sized(default_width, None);
+
+        let mut parts = options.split(',').peekable();
+        // the size can be left out if there are only modifiers
+        if let Some(size) =
+            parts.next_if(|part| part.starts_with(|c: char| c.is_ascii_digit() || c == 'x'))
+        {
+            display_options.parse_size(size)?;
+        }
+
+        for modifier in parts {
+            let (key, value) = match modifier.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (modifier, None),
+            };
+            match (key, value) {
+                ("fit", Some(value)) => display_options.byte_budget = Some(parse_bytes(value)?),
+                ("cover", None) => display_options.cover = true,
+                ("kernel", Some(value)) => {
+                    display_options.kernel = Some(ScaleKernel::parse(value)?)
+                }
+                _ => {
+                    if !display_options.transforms.push(key, value)?
+                        && !display_options.animation.push(key, value)?
+                        && !display_options.output.push(key, value)?
+                    {
+                        return Err(anyhow!("Unknown option \"{}\"", modifier));
+                    }
+                }
+            }
+        }
+
+        if display_options.cover && display_options.height.is_none() {
+            return Err(anyhow!(
+                "\"cover\" needs a height to cover, eg. 64x32,cover"
+            ));
+        }
+
+        Ok(display_options)
+    }
+
+    // What the resizers can actually make, every way of asking for an image goes through this
+    pub fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.width > Self::MAX_WIDTH {
+            return Err(anyhow!(
+                "The width has to be between 1 and {}",
+                Self::MAX_WIDTH
+            ));
+        }
+        match self.height {
+            Some(0) => Err(anyhow!("The height can't be 0")),
+            // TODO drop this once the resizers can resize by height and width
+            Some(_) if !self.cover => Err(anyhow!(
+                "Emotes cannot be resized by height yet. Please try again without the height, add \",cover\" to crop to it, or wait for this feature to be implemented."
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn parse_size(&mut self, size: &str) -> Result<()> {
+        let number = |x: &str| {
+            x.parse::<u32>()
+                .map_err(|_| anyhow!("\"{}\" is not a valid size", size))
+        };
+
+        let size: Vec<&str> = size.split('x').collect();
+        match size[..] {
+            // just width
+            [width] => self.width = number(width)?,
+            // multiplier format: "x10"
+            ["", multiplier] => self.multiplier = Some(number(multiplier)?),
+            [width, height] => {
+                self.width = number(width)?;
+                // you could do 64xx10 and that would omit height
+                if height != "" {
+                    self.height = Some(number(height)?);
+                }
+            }
+            [width, height, multiplier] => {
+                self.width = number(width)?;
+                if height != "" {
+                    self.height = Some(number(height)?);
+                }
+                self.multiplier = Some(number(multiplier)?);
+            }
+            _ => return Err(anyhow!("\"{}\" is not a valid size", size.join("x"))),
+        }
+        Ok(())
+    }
+
+    pub fn resize_options(&self) -> ResizeOptions {
+        ResizeOptions {
+            width: self.width,
+            height: self.height,
+            multiplier: self.multiplier,
+            encode: EncodeSettings {
+                quality: self.output.starting_quality(),
+                ..EncodeSettings::default()
+            },
+            animation: self.animation,
+            kernel: self.kernel.unwrap_or_default(),
+        }
+    }
+
+    // Every distinct set of options is a distinct emote image, this is what tells them apart
+    pub fn derivative_key(&self) -> String {
+        let mut key = format!(
+            "{}x{}",
+            self.width,
+            self.height.map(|h| h.to_string()).unwrap_or_default()
+        );
+        if let Some(multiplier) = self.multiplier {
+            key += &format!("x{}", multiplier);
+        }
+        if let Some(byte_budget) = self.byte_budget {
+            key += &format!(",fit={}", byte_budget);
+        }
+        if self.cover && self.height.is_some() {
+            key += ",cover";
+        }
+        if let Some(kernel) = self.kernel {
+            key += &format!(",kernel={}", kernel.name());
+        }
+        if !self.transforms.is_noop() {
+            key += &format!(",{}", self.transforms.key());
+        }
+        if !self.animation.is_noop() {
+            key += &format!(",{}", self.animation.key());
+        }
+        if !self.output.is_noop() {
+            key += &format!(",{}", self.output.key());
+        }
+        key
+    }
+}
+
+// "262144", "256k", "256kb", "1m" or "1mb"
+fn parse_bytes(value: &str) -> Result<u32> {
+    let lower = value.to_lowercase();
+    let (number, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => lower.split_at(i),
+        None => (&lower[..], ""),
+    };
+    let unit = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        _ => return Err(anyhow!("\"{}\" is not a valid size in bytes", value)),
+    };
+
+    number
+        .parse::<u32>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .filter(|bytes| *bytes > 0)
+        .ok_or_else(|| anyhow!("\"{}\" is not a valid size in bytes", value))
+}
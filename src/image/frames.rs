@@ -0,0 +1,183 @@
+use anyhow::Result;
+use std::io::Cursor;
+
+// Decoding and encoding of whole RGBA frames, for the steps that work on what the resizer made
+
+pub struct CanvasFrame {
+    // the whole canvas as it looks with this frame, not just the part the frame drew
+    pub pixels: Vec<[u8; 4]>,
+    // in 10ms units, like GIF has it
+    pub delay: u16,
+}
+
+pub fn decode_gif(buffer: &[u8]) -> Result<(u16, u16, Vec<CanvasFrame>)> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(Cursor::new(buffer))?;
+    let (width, height) = (decoder.width(), decoder.height());
+
+    let mut canvas = vec![[0u8; 4]; width as usize * height as usize];
+    let mut frames = vec![];
+    while let Some(frame) = decoder.read_next_frame()? {
+        let before = canvas.clone();
+        let rows =
+            frame.top as usize..(frame.top as usize + frame.height as usize).min(height as usize);
+        let columns =
+            frame.left as usize..(frame.left as usize + frame.width as usize).min(width as usize);
+
+        for y in rows.clone() {
+            for x in columns.clone() {
+                let i =
+                    ((y - frame.top as usize) * frame.width as usize + x - frame.left as usize) * 4;
+                let pixel = &frame.buffer[i..i + 4];
+                if pixel[3] != 0 {
+                    canvas[y * width as usize + x] = [pixel[0], pixel[1], pixel[2], pixel[3]];
+                }
+            }
+        }
+        frames.push(CanvasFrame {
+            pixels: canvas.clone(),
+            delay: frame.delay,
+        });
+
+        match frame.dispose {
+            gif::DisposalMethod::Background => {
+                for y in rows {
+                    for x in columns.clone() {
+                        canvas[y * width as usize + x] = [0; 4];
+                    }
+                }
+            }
+            gif::DisposalMethod::Previous => canvas = before,
+            _ => (),
+        }
+    }
+
+    Ok((width, height, frames))
+}
+
+// width, height, pixels. None for APNGs, only their first frame would come out.
+pub fn decode_png(buffer: &[u8]) -> Result<Option<(u32, u32, Vec<[u8; 4]>)>> {
+    let mut decoder = png::Decoder::new(Cursor::new(buffer));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    if reader.info().animation_control().is_some() {
+        return Ok(None);
+    }
+
+    let mut raw = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut raw)?;
+    let pixels: Vec<[u8; 4]> = match info.color_type {
+        png::ColorType::Grayscale => raw.iter().map(|&l| [l, l, l, 255]).collect(),
+        png::ColorType::GrayscaleAlpha => raw
+            .chunks_exact(2)
+            .map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Rgb => raw
+            .chunks_exact(3)
+            .map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::Rgba => raw
+            .chunks_exact(4)
+            .map(|p| [p[0], p[1], p[2], p[3]])
+            .collect(),
+        // normalize_to_color8 expands palettes
+        png::ColorType::Indexed => return Ok(None),
+    };
+
+    Ok(Some((info.width, info.height, pixels)))
+}
+
+pub fn encode_png(width: u32, height: u32, pixels: &[[u8; 4]]) -> Result<Vec<u8>> {
+    let mut out_buffer = vec![];
+    {
+        let mut encoder = png::Encoder::new(&mut out_buffer, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&pixels.concat())?;
+    }
+    Ok(out_buffer)
+}
+
+// Every frame covers the whole canvas. The optimizer is what makes this small again.
+pub fn encode_gif(width: u16, height: u16, frames: &[CanvasFrame]) -> Result<Vec<u8>> {
+    let mut out_buffer = vec![];
+    {
+        let mut encoder = gif::Encoder::new(&mut out_buffer, width, height, &[])?;
+        encoder.set_repeat(gif::Repeat::Infinite)?;
+        for frame in frames {
+            let mut pixels = frame.pixels.concat();
+            let mut out_frame = gif::Frame::from_rgba_speed(width, height, &mut pixels, 10);
+            out_frame.delay = frame.delay;
+            out_frame.dispose = gif::DisposalMethod::Background;
+            encoder.write_frame(&out_frame)?;
+        }
+    }
+    Ok(out_buffer)
+}
+
+// Start and end of the NETSCAPE looping extension, and where the blocks before the first frame start
+fn gif_loop_extension(buffer: &[u8]) -> Option<(usize, Option<(usize, usize)>)> {
+    if !buffer.starts_with(b"GIF") {
+        return None;
+    }
+    let packed = *buffer.get(10)?;
+    let mut pos = 13;
+    if packed & 0x80 != 0 {
+        // global colour table
+        pos += 3 << ((packed & 0x07) + 1);
+    }
+    let blocks_start = pos;
+
+    // extensions until the first frame
+    while *buffer.get(pos)? == 0x21 {
+        let start = pos;
+        let is_loop =
+            *buffer.get(pos + 1)? == 0xff && buffer.get(pos + 2..pos + 14)? == b"\x0bNETSCAPE2.0";
+        pos += 2;
+        // sub-blocks until an empty one
+        loop {
+            let length = *buffer.get(pos)? as usize;
+            pos += 1 + length;
+            if length == 0 {
+                break;
+            }
+        }
+        if is_loop {
+            return Some((blocks_start, Some((start, pos))));
+        }
+    }
+    Some((blocks_start, None))
+}
+
+// How many times a GIF repeats after the first time, which the gif crate can't read. Some(0) is forever, None plays it once.
+pub fn gif_repeat(buffer: &[u8]) -> Option<u16> {
+    let (start, _) = gif_loop_extension(buffer)?.1?;
+    match buffer.get(start + 14..start + 18)? {
+        [3, 1, low, high] => Some(u16::from_le_bytes([*low, *high])),
+        _ => None,
+    }
+}
+
+// Swaps the loop count of a GIF for `repeat`, same meaning as gif_repeat. Anything that isn't a GIF comes back as it was.
+pub fn set_gif_repeat(buffer: Vec<u8>, repeat: Option<u16>) -> Vec<u8> {
+    let (blocks_start, extension) = match gif_loop_extension(&buffer) {
+        Some(found) => found,
+        None => return buffer,
+    };
+
+    let mut out = buffer[..blocks_start].to_vec();
+    if let Some(repeat) = repeat {
+        out.extend_from_slice(b"\x21\xff\x0bNETSCAPE2.0\x03\x01");
+        out.extend_from_slice(&repeat.to_le_bytes());
+        out.push(0);
+    }
+    match extension {
+        Some((start, end)) => {
+            out.extend_from_slice(&buffer[blocks_start..start]);
+            out.extend_from_slice(&buffer[end..]);
+        }
+        None => out.extend_from_slice(&buffer[blocks_start..]),
+    }
+    out
+}
@@ -0,0 +1,77 @@
+use anyhow::Result;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::image::ResizerBackend;
+
+// Hashes of an original image, for noticing when the same emote gets uploaded twice
+#[derive(Debug, Clone)]
+pub struct ImageHashes {
+    // sha256 of the bytes as they were uploaded
+    pub content: String,
+    // dHash of the first frame, which survives resizing and re-encoding. None if the resizer couldn't read the image.
+    pub perceptual: Option<i64>,
+}
+
+impl ImageHashes {
+    pub fn compute(buffer: &[u8], resizer: &dyn ResizerBackend) -> Self {
+        Self {
+            content: hex::encode(Sha256::digest(buffer)),
+            perceptual: perceptual_hash(resizer).ok(),
+        }
+    }
+
+    // 0 for the exact same bytes, otherwise how many bits of the perceptual hashes differ
+    pub fn distance(&self, other: &ImageHashes) -> Option<u32> {
+        if self.content == other.content {
+            return Some(0);
+        }
+        Some(hash_distance(self.perceptual?, other.perceptual?))
+    }
+}
+
+// Each of the 64 bits says whether a pixel of a 9x8 grayscale thumbnail is brighter than the one to its right
+fn perceptual_hash(resizer: &dyn ResizerBackend) -> Result<i64> {
+    let thumbnail = resizer.luma_thumbnail(9, 8)?;
+
+    let mut hash = 0u64;
+    for row in thumbnail.chunks_exact(9) {
+        for pair in row.windows(2) {
+            hash = (hash << 1) | (pair[0] > pair[1]) as u64;
+        }
+    }
+    // stored as BIGINT, only the bits matter
+    Ok(hash as i64)
+}
+
+// Number of bits that differ, 0 is the same picture and anything over ~10 is probably a different one
+fn hash_distance(a: i64, b: i64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateAction {
+    // only show them in Emote.duplicates
+    Warn,
+    // refuse the upload
+    Reject,
+}
+
+// What to do when an upload looks like an emote that's already in the same dir
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct DuplicatePolicy {
+    pub action: DuplicateAction,
+    // how many bits of the perceptual hashes can differ for two images to still count as the same
+    pub max_distance: u32,
+}
+
+impl Default for DuplicatePolicy {
+    fn default() -> Self {
+        Self {
+            action: DuplicateAction::Warn,
+            max_distance: 6,
+        }
+    }
+}
@@ -0,0 +1,691 @@
+use crate::image::{frames::gif_repeat, ImageType};
+
+// What can be learned about an image from its headers alone, without decoding any pixels
+#[derive(Debug, Clone, Copy)]
+pub struct ImageHeader {
+    pub image_type: ImageType,
+    pub width: u32,
+    pub height: u32,
+    pub frames: u32,
+}
+
+impl ImageHeader {
+    pub fn probe(buffer: &[u8]) -> Option<Self> {
+        let image_type = ImageType::sniff(buffer)?;
+        let (width, height, frames) = match image_type {
+            ImageType::PNG | ImageType::APNG => probe_png(buffer)?,
+            ImageType::GIF => probe_gif(buffer)?,
+            ImageType::JPEG => probe_jpeg(buffer)?,
+            ImageType::WEBPAnimated | ImageType::WEBPStill => probe_webp(buffer)?,
+            // the size it gets rendered at
+            ImageType::SVG => probe_svg(buffer)?,
+            ImageType::Lottie => return None,
+        };
+
+        Some(Self {
+            image_type,
+            width,
+            height,
+            frames,
+        })
+    }
+}
+
+// How an animation plays, also read from the headers alone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameInfo {
+    // in ms, one per frame, as written in the file. Stills have a single 0.
+    pub delays: Vec<u32>,
+    // how many times it plays, 0 for forever. None for stills.
+    pub loops: Option<u32>,
+}
+
+impl FrameInfo {
+    pub fn still() -> Self {
+        Self {
+            delays: vec![0],
+            loops: None,
+        }
+    }
+
+    pub fn probe(buffer: &[u8]) -> Option<Self> {
+        let frame_info = match ImageType::sniff(buffer)? {
+            ImageType::GIF => gif_frame_info(buffer)?,
+            ImageType::APNG => apng_frame_info(buffer)?,
+            ImageType::WEBPAnimated => webp_frame_info(buffer)?,
+            _ => Self::still(),
+        };
+        // an animation with a single frame is still a still
+        if frame_info.delays.len() > 1 {
+            Some(frame_info)
+        } else {
+            Some(Self::still())
+        }
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        self.delays.len() as u32
+    }
+
+    // of one loop, in ms
+    pub fn duration(&self) -> u32 {
+        self.delays.iter().sum()
+    }
+
+    // The loop count as a NETSCAPE repeat count, the same meaning as frames::gif_repeat
+    pub fn gif_repeat(&self) -> Option<u16> {
+        match self.loops {
+            Some(1) => None,
+            Some(loops) if loops > 1 => Some((loops - 1).min(u16::MAX as u32) as u16),
+            _ => Some(0),
+        }
+    }
+}
+
+fn be_u16(buffer: &[u8], at: usize) -> Option<u32> {
+    let bytes = buffer.get(at..at + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]) as u32)
+}
+
+fn be_u32(buffer: &[u8], at: usize) -> Option<u32> {
+    let bytes = buffer.get(at..at + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn le_u16(buffer: &[u8], at: usize) -> Option<u32> {
+    let bytes = buffer.get(at..at + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]) as u32)
+}
+
+fn le_u24(buffer: &[u8], at: usize) -> Option<u32> {
+    let bytes = buffer.get(at..at + 3)?;
+    Some(bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16)
+}
+
+fn le_u32(buffer: &[u8], at: usize) -> Option<u32> {
+    let bytes = buffer.get(at..at + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn probe_png(buffer: &[u8]) -> Option<(u32, u32, u32)> {
+    // IHDR is always the first chunk
+    let width = be_u32(buffer, 16)?;
+    let height = be_u32(buffer, 20)?;
+
+    // walk the chunks looking for the animation control chunk, which has the frame count
+    let mut pos = 8;
+    while let Some(length) = be_u32(buffer, pos) {
+        let chunk_type = buffer.get(pos + 4..pos + 8)?;
+        if chunk_type == b"acTL" {
+            return Some((width, height, be_u32(buffer, pos + 8)?));
+        }
+        if chunk_type == b"IDAT" || chunk_type == b"IEND" {
+            break;
+        }
+        pos += 12 + length as usize;
+    }
+
+    Some((width, height, 1))
+}
+
+fn probe_gif(buffer: &[u8]) -> Option<(u32, u32, u32)> {
+    let width = le_u16(buffer, 6)?;
+    let height = le_u16(buffer, 8)?;
+
+    let flags = *buffer.get(10)?;
+    let mut pos = 13;
+    if flags & 0x80 != 0 {
+        pos += 3 * (1 << ((flags & 0x07) + 1));
+    }
+
+    // count the image descriptors, skipping over everything else without decompressing it
+    let mut frames = 0;
+    loop {
+        match *buffer.get(pos)? {
+            // image descriptor
+            0x2c => {
+                frames += 1;
+                let flags = *buffer.get(pos + 9)?;
+                pos += 10;
+                if flags & 0x80 != 0 {
+                    pos += 3 * (1 << ((flags & 0x07) + 1));
+                }
+                pos += 1; // LZW minimum code size
+                pos = skip_gif_sub_blocks(buffer, pos)?;
+            }
+            // extension
+            0x21 => {
+                pos = skip_gif_sub_blocks(buffer, pos + 2)?;
+            }
+            // trailer
+            0x3b => break,
+            _ => return None,
+        }
+    }
+
+    Some((width, height, frames.max(1)))
+}
+
+fn skip_gif_sub_blocks(buffer: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let length = *buffer.get(pos)? as usize;
+        pos += 1;
+        if length == 0 {
+            return Some(pos);
+        }
+        pos += length;
+    }
+}
+
+fn probe_jpeg(buffer: &[u8]) -> Option<(u32, u32, u32)> {
+    let mut pos = 2;
+    loop {
+        if *buffer.get(pos)? != 0xff {
+            return None;
+        }
+        let marker = *buffer.get(pos + 1)?;
+        match marker {
+            // padding
+            0xff => {
+                pos += 1;
+                continue;
+            }
+            // markers without a length
+            0x01 | 0xd0..=0xd7 => {
+                pos += 2;
+                continue;
+            }
+            // start of frame, minus DHT, JPG and DAC which share the range
+            0xc0..=0xcf if marker != 0xc4 && marker != 0xc8 && marker != 0xcc => {
+                let height = be_u16(buffer, pos + 5)?;
+                let width = be_u16(buffer, pos + 7)?;
+                return Some((width, height, 1));
+            }
+            // start of scan or end of image before any frame header
+            0xda | 0xd9 => return None,
+            _ => {
+                pos += 2 + be_u16(buffer, pos + 2)? as usize;
+            }
+        }
+    }
+}
+
+fn probe_webp(buffer: &[u8]) -> Option<(u32, u32, u32)> {
+    let chunk_type = buffer.get(12..16)?;
+    match chunk_type {
+        b"VP8 " => {
+            // 14 bits each, after the frame tag and start code
+            let width = le_u16(buffer, 26)? & 0x3fff;
+            let height = le_u16(buffer, 28)? & 0x3fff;
+            Some((width, height, 1))
+        }
+        b"VP8L" => {
+            let bits = le_u32(buffer, 21)?;
+            let width = (bits & 0x3fff) + 1;
+            let height = ((bits >> 14) & 0x3fff) + 1;
+            Some((width, height, 1))
+        }
+        b"VP8X" => {
+            let width = le_u24(buffer, 24)? + 1;
+            let height = le_u24(buffer, 27)? + 1;
+
+            // every animation frame has its own ANMF chunk
+            let mut frames = 0;
+            let mut pos = 12;
+            while let Some(length) = le_u32(buffer, pos + 4) {
+                if buffer.get(pos..pos + 4)? == b"ANMF" {
+                    frames += 1;
+                }
+                // chunks are padded to an even length
+                pos += 8 + length as usize + (length as usize & 1);
+            }
+            Some((width, height, frames.max(1)))
+        }
+        _ => None,
+    }
+}
+
+// librsvg renders at the root element's width and height, and the viewBox stands in for any that are missing or
+// relative. Without either it goes by what's drawn, which could be anything, so that's None.
+fn probe_svg(buffer: &[u8]) -> Option<(u32, u32, u32)> {
+    let text = String::from_utf8_lossy(buffer);
+    let attributes = svg_root_attributes(&text)?;
+    let attribute = |name: &str| {
+        attributes
+            .iter()
+            .find(|(attribute, _)| *attribute == name)
+            .map(|(_, value)| value.trim())
+    };
+
+    let view_box = match attribute("viewBox") {
+        Some(view_box) => {
+            let numbers = view_box
+                .split(|c: char| c.is_ascii_whitespace() || c == ',')
+                .filter(|number| !number.is_empty())
+                .map(|number| number.parse::<f64>().ok())
+                .collect::<Option<Vec<_>>>()?;
+            match numbers[..] {
+                [_, _, width, height] => Some((width, height)),
+                _ => return None,
+            }
+        }
+        None => None,
+    };
+    // anything that's set but can't be read (an entity, em) is None rather than a guess
+    let width = match attribute("width") {
+        Some(width) if !width.ends_with('%') => svg_length(width)?,
+        _ => view_box?.0,
+    };
+    let height = match attribute("height") {
+        Some(height) if !height.ends_with('%') => svg_length(height)?,
+        _ => view_box?.1,
+    };
+
+    if !width.is_finite() || !height.is_finite() || width < 0.0 || height < 0.0 {
+        return None;
+    }
+    // huge ones saturate at u32::MAX, which no limit lets through
+    Some((width.ceil() as u32, height.ceil() as u32, 1))
+}
+
+// The attributes of the root <svg> element. Only what can come before it is skipped, so a size in a comment
+// can't be mistaken for the real one, and a DOCTYPE with entities in it isn't something we want to render anyway.
+fn svg_root_attributes(text: &str) -> Option<Vec<(&str, &str)>> {
+    let mut rest = text.trim_start_matches('\u{feff}');
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix("<?") {
+            rest = &after[after.find("?>")? + 2..];
+        } else if let Some(after) = rest.strip_prefix("<!--") {
+            rest = &after[after.find("-->")? + 3..];
+        } else if let Some(after) = rest.strip_prefix("<!DOCTYPE") {
+            let end = after.find('>')?;
+            if after[..end].contains('[') {
+                return None;
+            }
+            rest = &after[end + 1..];
+        } else if let Some(after) = rest.strip_prefix("<svg") {
+            rest = after;
+            break;
+        } else {
+            return None;
+        }
+    }
+
+    let mut attributes = vec![];
+    if !rest.starts_with(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/') {
+        return None;
+    }
+    loop {
+        rest = rest.trim_start();
+        if rest.starts_with('>') || rest.starts_with("/>") {
+            return Some(attributes);
+        }
+        let equals = rest.find('=')?;
+        let name = rest[..equals].trim();
+        let value = rest[equals + 1..].trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let end = value[1..].find(quote)? + 1;
+        attributes.push((name, &value[1..end]));
+        rest = &value[end + 1..];
+    }
+}
+
+// In px, with the absolute units at the 96 dpi CSS uses. libvips renders them at 72, so this only errs on the big side.
+fn svg_length(length: &str) -> Option<f64> {
+    let units = [
+        ("px", 1.0),
+        ("pt", 96.0 / 72.0),
+        ("pc", 16.0),
+        ("mm", 96.0 / 25.4),
+        ("cm", 96.0 / 2.54),
+        ("in", 96.0),
+    ];
+    let (number, scale) = units
+        .iter()
+        .find_map(|(unit, scale)| Some((length.strip_suffix(*unit)?, *scale)))
+        .unwrap_or((length, 1.0));
+    Some(number.trim_end().parse::<f64>().ok()? * scale)
+}
+
+fn gif_frame_info(buffer: &[u8]) -> Option<FrameInfo> {
+    let flags = *buffer.get(10)?;
+    let mut pos = 13;
+    if flags & 0x80 != 0 {
+        pos += 3 * (1 << ((flags & 0x07) + 1));
+    }
+
+    // the delay is in the graphic control extension before each image descriptor
+    let mut delays = vec![];
+    let mut delay = 0;
+    loop {
+        match *buffer.get(pos)? {
+            0x2c => {
+                delays.push(delay * 10);
+                delay = 0;
+                let flags = *buffer.get(pos + 9)?;
+                pos += 10;
+                if flags & 0x80 != 0 {
+                    pos += 3 * (1 << ((flags & 0x07) + 1));
+                }
+                pos += 1;
+                pos = skip_gif_sub_blocks(buffer, pos)?;
+            }
+            0x21 => {
+                if *buffer.get(pos + 1)? == 0xf9 {
+                    delay = le_u16(buffer, pos + 4)?;
+                }
+                pos = skip_gif_sub_blocks(buffer, pos + 2)?;
+            }
+            0x3b => break,
+            _ => return None,
+        }
+    }
+
+    let loops = match gif_repeat(buffer) {
+        Some(0) => 0,
+        Some(repeat) => repeat as u32 + 1,
+        // no NETSCAPE block, it plays once
+        None => 1,
+    };
+    Some(FrameInfo {
+        delays,
+        loops: Some(loops),
+    })
+}
+
+fn apng_frame_info(buffer: &[u8]) -> Option<FrameInfo> {
+    let mut delays = vec![];
+    let mut loops = 0;
+    let mut pos = 8;
+    while let Some(length) = be_u32(buffer, pos) {
+        match buffer.get(pos + 4..pos + 8)? {
+            b"acTL" => loops = be_u32(buffer, pos + 12)?,
+            b"fcTL" => {
+                let numerator = be_u16(buffer, pos + 28)?;
+                // a 0 denominator means hundredths
+                let denominator = match be_u16(buffer, pos + 30)? {
+                    0 => 100,
+                    denominator => denominator,
+                };
+                delays.push(numerator * 1000 / denominator);
+            }
+            b"IEND" => break,
+            _ => (),
+        }
+        pos += 12 + length as usize;
+    }
+
+    Some(FrameInfo {
+        delays,
+        loops: Some(loops),
+    })
+}
+
+fn webp_frame_info(buffer: &[u8]) -> Option<FrameInfo> {
+    let mut delays = vec![];
+    let mut loops = 0;
+    let mut pos = 12;
+    while let Some(length) = le_u32(buffer, pos + 4) {
+        match buffer.get(pos..pos + 4)? {
+            // background colour, then the loop count
+            b"ANIM" => loops = le_u16(buffer, pos + 12)?,
+            // position and size, then the duration
+            b"ANMF" => delays.push(le_u24(buffer, pos + 20)?),
+            _ => (),
+        }
+        pos += 8 + length as usize + (length as usize & 1);
+    }
+
+    Some(FrameInfo {
+        delays,
+        loops: Some(loops),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(chunk_type);
+        chunk.extend_from_slice(data);
+        // nothing here checks the CRC
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    fn png(width: u32, height: u32, frames: Option<u32>) -> Vec<u8> {
+        let mut ihdr = width.to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+        let mut buffer = b"\x89PNG\r\n\x1a\n".to_vec();
+        buffer.extend(png_chunk(b"IHDR", &ihdr));
+        if let Some(frames) = frames {
+            let mut actl = frames.to_be_bytes().to_vec();
+            actl.extend_from_slice(&0u32.to_be_bytes());
+            buffer.extend(png_chunk(b"acTL", &actl));
+        }
+        buffer.extend(png_chunk(b"IDAT", &[0; 4]));
+        buffer.extend(png_chunk(b"IEND", &[]));
+        buffer
+    }
+
+    // with a two colour global palette, and an empty image descriptor per frame
+    fn gif(width: u16, height: u16, frames: usize) -> Vec<u8> {
+        let mut buffer = b"GIF89a".to_vec();
+        buffer.extend_from_slice(&width.to_le_bytes());
+        buffer.extend_from_slice(&height.to_le_bytes());
+        buffer.extend_from_slice(&[0x80, 0, 0]);
+        buffer.extend_from_slice(&[0; 6]);
+        for _ in 0..frames {
+            // a graphic control extension, which has to be skipped over
+            buffer.extend_from_slice(&[0x21, 0xf9, 4, 0, 10, 0, 0, 0]);
+            buffer.push(0x2c);
+            buffer.extend_from_slice(&[0; 4]);
+            buffer.extend_from_slice(&width.to_le_bytes());
+            buffer.extend_from_slice(&height.to_le_bytes());
+            buffer.extend_from_slice(&[0, 2, 1, 0, 0]);
+        }
+        buffer.push(0x3b);
+        buffer
+    }
+
+    fn jpeg(width: u16, height: u16) -> Vec<u8> {
+        let mut buffer = vec![0xff, 0xd8];
+        // an APP0 segment before the frame header, with some padding before its marker
+        buffer.extend_from_slice(&[0xff, 0xff, 0xe0, 0, 16]);
+        buffer.extend_from_slice(b"JFIF\0");
+        buffer.extend_from_slice(&[1, 1, 0, 0, 1, 0, 1, 0, 0]);
+        // DHT shares the start of frame range but isn't one
+        buffer.extend_from_slice(&[0xff, 0xc4, 0, 4, 0, 0]);
+        buffer.extend_from_slice(&[0xff, 0xc2, 0, 11, 8]);
+        buffer.extend_from_slice(&height.to_be_bytes());
+        buffer.extend_from_slice(&width.to_be_bytes());
+        buffer.extend_from_slice(&[1, 1, 0x11, 0]);
+        buffer.extend_from_slice(&[0xff, 0xda]);
+        buffer
+    }
+
+    fn webp(chunks: &[(&[u8], Vec<u8>)]) -> Vec<u8> {
+        let mut body = b"WEBP".to_vec();
+        for (chunk_type, data) in chunks {
+            body.extend_from_slice(chunk_type);
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+        let mut buffer = b"RIFF".to_vec();
+        buffer.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        buffer.extend(body);
+        buffer
+    }
+
+    fn u24(value: u32) -> [u8; 3] {
+        let bytes = value.to_le_bytes();
+        [bytes[0], bytes[1], bytes[2]]
+    }
+
+    fn vp8x(width: u32, height: u32, frames: usize) -> Vec<u8> {
+        let mut header = vec![if frames > 0 { 0x02 } else { 0 }, 0, 0, 0];
+        header.extend_from_slice(&u24(width - 1));
+        header.extend_from_slice(&u24(height - 1));
+        let mut chunks: Vec<(&[u8], Vec<u8>)> = vec![(b"VP8X", header)];
+        if frames > 0 {
+            chunks.push((b"ANIM", vec![0, 0, 0, 0, 0, 0]));
+        }
+        for _ in 0..frames {
+            // position, size and duration, then a frame that isn't there (the odd length gets padded)
+            chunks.push((b"ANMF", vec![0; 17]));
+        }
+        webp(&chunks)
+    }
+
+    fn dimensions(buffer: &[u8]) -> Option<(ImageType, u32, u32, u32)> {
+        ImageHeader::probe(buffer).map(|header| {
+            (
+                header.image_type,
+                header.width,
+                header.height,
+                header.frames,
+            )
+        })
+    }
+
+    #[test]
+    fn probes_png() {
+        assert_eq!(
+            dimensions(&png(300, 200, None)),
+            Some((ImageType::PNG, 300, 200, 1))
+        );
+        assert_eq!(
+            dimensions(&png(64, 32, Some(12))),
+            Some((ImageType::APNG, 64, 32, 12))
+        );
+    }
+
+    #[test]
+    fn probes_gif() {
+        assert_eq!(
+            dimensions(&gif(40, 30, 1)),
+            Some((ImageType::GIF, 40, 30, 1))
+        );
+        assert_eq!(
+            dimensions(&gif(40, 30, 5)),
+            Some((ImageType::GIF, 40, 30, 5))
+        );
+    }
+
+    #[test]
+    fn probes_jpeg() {
+        assert_eq!(
+            dimensions(&jpeg(1920, 1080)),
+            Some((ImageType::JPEG, 1920, 1080, 1))
+        );
+    }
+
+    #[test]
+    fn probes_webp() {
+        let mut vp8 = vec![0; 10];
+        vp8[3..6].copy_from_slice(&[0x9d, 0x01, 0x2a]);
+        vp8[6..8].copy_from_slice(&640u16.to_le_bytes());
+        // the top two bits are the scale, which isn't part of the size
+        vp8[8..10].copy_from_slice(&(480u16 | 0xc000).to_le_bytes());
+        assert_eq!(
+            dimensions(&webp(&[(b"VP8 ", vp8)])),
+            Some((ImageType::WEBPStill, 640, 480, 1))
+        );
+
+        let mut vp8l = vec![0x2f];
+        vp8l.extend_from_slice(&(99u32 | 49 << 14).to_le_bytes());
+        assert_eq!(
+            dimensions(&webp(&[(b"VP8L", vp8l)])),
+            Some((ImageType::WEBPStill, 100, 50, 1))
+        );
+
+        assert_eq!(
+            dimensions(&vp8x(5000, 20, 0)),
+            Some((ImageType::WEBPStill, 5000, 20, 1))
+        );
+        assert_eq!(
+            dimensions(&vp8x(256, 128, 3)),
+            Some((ImageType::WEBPAnimated, 256, 128, 3))
+        );
+    }
+
+    #[test]
+    fn truncated_headers_are_none() {
+        // cut off inside the part the size is read from
+        assert_eq!(dimensions(&png(300, 200, None)[..22]), None);
+        assert_eq!(dimensions(&gif(40, 30, 1)[..8]), None);
+        assert_eq!(dimensions(&webp(&[(b"VP8L", vec![0x2f, 1])])), None);
+        assert_eq!(dimensions(&vp8x(256, 128, 0)[..26]), None);
+
+        // the frames can't be counted without the rest of the file
+        let gif = gif(40, 30, 5);
+        assert_eq!(dimensions(&gif[..gif.len() - 4]), None);
+        let jpeg = jpeg(1920, 1080);
+        assert_eq!(dimensions(&jpeg[..jpeg.len() - 8]), None);
+    }
+
+    fn svg_size(svg: &str) -> Option<(u32, u32)> {
+        ImageHeader::probe(svg.as_bytes()).map(|header| (header.width, header.height))
+    }
+
+    #[test]
+    fn probes_svg() {
+        assert_eq!(
+            svg_size(r#"<svg xmlns="http://www.w3.org/2000/svg" width="120" height='80px'/>"#),
+            Some((120, 80))
+        );
+        assert_eq!(
+            svg_size(r#"<svg width="1in" height="72pt"></svg>"#),
+            Some((96, 96))
+        );
+        assert_eq!(
+            svg_size(r#"<svg viewBox="0 0 24.5 12" width="100%"></svg>"#),
+            Some((25, 12))
+        );
+        assert_eq!(
+            svg_size(r#"<svg viewBox="-10,-10, 300,200" height="50"></svg>"#),
+            Some((300, 50))
+        );
+        assert_eq!(
+            svg_size(r#"<svg width="1e12" height="1"></svg>"#),
+            Some((u32::MAX, 1))
+        );
+    }
+
+    #[test]
+    fn svg_size_comes_from_the_root() {
+        let prolog = r#"<?xml version="1.0"?>
+            <!-- <svg width="1" height="1"> -->
+            <!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd">
+            <svg width="20000" height="20000"><svg width="1" height="1"/></svg>"#;
+        assert_eq!(svg_size(prolog), Some((20000, 20000)));
+    }
+
+    #[test]
+    fn unreadable_svg_sizes_are_none() {
+        // sized by whatever's drawn
+        assert_eq!(
+            svg_size(r#"<svg><rect width="99999" height="99999"/></svg>"#),
+            None
+        );
+        assert_eq!(svg_size(r#"<svg width="50%" height="50%"></svg>"#), None);
+        assert_eq!(svg_size(r#"<svg width="10em" height="10"></svg>"#), None);
+        assert_eq!(svg_size(r#"<svg viewBox="0 0 10" width="10"></svg>"#), None);
+        assert_eq!(svg_size(r#"<svg width="-5" height="10"></svg>"#), None);
+        assert_eq!(
+            svg_size(
+                r#"<!DOCTYPE svg [<!ENTITY size "99999">]><svg width="&size;" height="&size;"/>"#
+            ),
+            None
+        );
+        assert_eq!(svg_size(r#"<svg width="10" height="10"#), None);
+    }
+}
@@ -1,7 +1,20 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use log::info;
 use uuid::Uuid;
 
-use crate::{image::ImageTypeHandler, storage::STORAGE_PROVIDER};
+use crate::{
+    config::EMOTES_CONFIG,
+    image::{
+        frames::{gif_repeat, set_gif_repeat},
+        strip_metadata, DisplayOptions, EncodeSettings, FrameInfo, ImageHashes, ImageType,
+        ImageTypeHandler, Normalize, OptimizeSettings, Placeholder, ResizeOptions, ScaleKernel,
+        UploadLimits,
+    },
+};
+
+// Bump this whenever a change makes resized images come out differently (encoder settings, a new step, a fix),
+// so the ones made before can be found and made again with reprocessDerivatives
+pub const PIPELINE_VERSION: i32 = 1;
 
 // metadata about source image
 pub struct ImageProcessor {
@@ -9,33 +22,71 @@ pub struct ImageProcessor {
     pub image_height: u32,
     pub image_type_handler: ImageTypeHandler,
     pub image_uuid: Uuid,
+    // only worked out when an original is saved
+    pub image_hashes: Option<ImageHashes>,
+    // same here, None as well if the resizer couldn't make a thumbnail
+    pub placeholder: Option<Placeholder>,
 }
 
+// None of this touches storage, it's all CPU work meant for the worker pool. Callers load and save the bytes around it.
 impl ImageProcessor {
-    pub fn save(image_buffer: Vec<u8>, image_uuid: Uuid, image_content_type: &str) -> Result<Self> {
-        // TODO don't unwrap
+    pub fn from_upload(
+        image_buffer: Vec<u8>,
+        image_uuid: Uuid,
+        image_content_type: &str,
+        limits: &UploadLimits,
+    ) -> Result<Self> {
         let image_type_handler =
-            ImageTypeHandler::from_content_type(&image_content_type, image_buffer)?.unwrap(); // temporary hack for getting resizer
+            ImageTypeHandler::from_content_type(image_content_type, image_buffer)?
+                .ok_or_else(|| anyhow!("Can't process {} images", image_content_type))?;
         let (image_width, image_height) = image_type_handler.image_resizer.dimensions()?;
 
-        // in_extension for "input" extension since this function is for a "source" or "original" file
-        STORAGE_PROVIDER.save(image_uuid, &image_type_handler.image_buffer)?;
+        // the headers were already checked before decoding, but not every format has headers we can read
+        limits.check_dimensions(
+            image_width,
+            image_height,
+            image_type_handler.frame_info.frame_count(),
+        )?;
+
+        let image_hashes = ImageHashes::compute(
+            &image_type_handler.image_buffer,
+            &*image_type_handler.image_resizer,
+        );
+        let placeholder = Placeholder::compute(
+            &*image_type_handler.image_resizer,
+            image_type_handler.frame_info.frame_count(),
+        )
+        .ok();
 
         Ok(Self {
             image_width,
             image_height,
             image_type_handler,
             image_uuid,
+            image_hashes: Some(image_hashes),
+            placeholder,
         })
     }
 
-    pub fn load(image_uuid: Uuid, image_content_type: &str) -> Result<Self> {
-        // in_extension for "input" extension since this function is for a "source" or "original" file
-
-        let image_buffer = STORAGE_PROVIDER.load(image_uuid)?;
+    // what gets stored as the original
+    // the hashes are of what was uploaded, so the same upload is still a duplicate whether or not it gets stripped
+    pub fn original_bytes(&self) -> Vec<u8> {
+        if EMOTES_CONFIG.strip_original_metadata {
+            strip_metadata(&self.image_type_handler.image_buffer)
+        } else {
+            self.image_type_handler.image_buffer.clone()
+        }
+    }
 
+    // an original that's already stored
+    pub fn from_buffer(
+        image_buffer: Vec<u8>,
+        image_uuid: Uuid,
+        image_content_type: &str,
+    ) -> Result<Self> {
         let image_type_handler =
-            ImageTypeHandler::from_content_type(&image_content_type, image_buffer)?.unwrap(); // temporary hack for getting resizer
+            ImageTypeHandler::from_content_type(image_content_type, image_buffer)?
+                .ok_or_else(|| anyhow!("Can't process {} images", image_content_type))?;
         let (image_width, image_height) = image_type_handler.image_resizer.dimensions()?;
 
         Ok(Self {
@@ -43,24 +94,160 @@ impl ImageProcessor {
             image_height,
             image_type_handler,
             image_uuid,
+            image_hashes: None,
+            placeholder: None,
         })
     }
 
-    // width, height
+    // width, height, output type, the encode settings that were used in the end, how the output plays, and the output
+    // With a byte budget, the settings are turned down until the output fits. If it never fits, the smallest attempt is kept.
     pub fn resize(
         &self,
         out_uuid: Uuid,
-        out_width: u32,
-        out_height: Option<u32>,
-        out_multiplier: Option<u32>,
-    ) -> Result<(u32, u32)> {
-        let (proc_out_width, proc_out_height, proc_out_image_bytes) = self
-            .image_type_handler
-            .image_resizer
-            .resize(out_width, out_height, out_multiplier)?;
-
-        STORAGE_PROVIDER.save(out_uuid, &proc_out_image_bytes)?;
-
-        Ok((proc_out_width, proc_out_height))
+        display_options: &DisplayOptions,
+        normalize: &Normalize,
+        optimize: &OptimizeSettings,
+        kernel: ScaleKernel,
+    ) -> Result<(u32, u32, ImageType, EncodeSettings, FrameInfo, Vec<u8>)> {
+        let resizer = &self.image_type_handler.image_resizer;
+        let transforms = &display_options.transforms;
+        let output = &display_options.output;
+        let mut options = display_options.resize_options();
+        // the emote's, when the request didn't pick one
+        options.kernel = kernel;
+
+        // Covering a width x height box is done by cropping the source to that shape and resizing by width,
+        // so the resizer never sees the height. The box is filled, so there's nothing to pad either.
+        let cover = match options.height {
+            Some(height) if display_options.cover => {
+                options.height = None;
+                Some(if transforms.swaps_axes() {
+                    (height, options.width)
+                } else {
+                    (options.width, height)
+                })
+            }
+            _ => None,
+        };
+        let normalize = &if cover.is_some() {
+            Normalize {
+                pad_square: false,
+                ..*normalize
+            }
+        } else {
+            *normalize
+        };
+
+        // worked out once on the source, every attempt below crops to the same place
+        let content = if normalize.is_noop() && cover.is_none() {
+            None
+        } else {
+            let frames = if normalize.trim {
+                resizer.rgba_frames()?
+            } else {
+                vec![]
+            };
+            let content = normalize.content_box(self.image_width, self.image_height, &frames);
+            Some(match cover {
+                Some((width, height)) => normalize.cover_box(&content, width, height),
+                None => content,
+            })
+        };
+
+        let encode = |options: &ResizeOptions| -> Result<(u32, u32, Vec<u8>, ImageType)> {
+            let mut options = *options;
+            // the width asked for is the width after turning, so it's the height before it
+            if transforms.swaps_axes() {
+                let (kept_width, kept_height) = match &content {
+                    Some(content) if !normalize.pad_square => (content.width, content.height),
+                    Some(_) => (1, 1),
+                    None => (self.image_width, self.image_height),
+                };
+                options.width = ((options.width as f64 * kept_width as f64 / kept_height as f64)
+                    .round() as u32)
+                    .max(1);
+            }
+            if let Some(content) = &content {
+                // resize so the content is the requested size, the margins get cut off after
+                options.width = normalize.resize_width(self.image_width, content, options.width);
+            }
+
+            let (mut width, mut height, mut image_bytes, out_type) = resizer.resize(&options)?;
+            // the steps below write GIFs that loop forever, the resizer's loop count is put back after
+            let repeat = gif_repeat(&image_bytes);
+            if let Some(content) = &content {
+                let scale = width as f64 / self.image_width as f64;
+                let (normalized_width, normalized_height, normalized_bytes) =
+                    normalize.apply(image_bytes, out_type, content, scale)?;
+                width = normalized_width;
+                height = normalized_height;
+                image_bytes = normalized_bytes;
+            }
+            if !transforms.is_noop() {
+                let (transformed_width, transformed_height, transformed_bytes) =
+                    transforms.apply(image_bytes, out_type)?;
+                width = transformed_width;
+                height = transformed_height;
+                image_bytes = transformed_bytes;
+            }
+
+            let image_bytes = output.flatten(image_bytes, out_type)?;
+
+            // animations can't be JPEGs, they stay GIFs
+            if output.jpeg && out_type == ImageType::PNG {
+                let (jpeg_width, jpeg_height, rgb) = output.jpeg_pixels(&image_bytes)?;
+                let image_bytes =
+                    resizer.encode_jpeg(jpeg_width, jpeg_height, &rgb, options.encode.quality)?;
+                return Ok((width, height, image_bytes, ImageType::JPEG));
+            }
+
+            let mut image_bytes =
+                optimize.optimize(image_bytes, out_type, options.encode.colours)?;
+            if out_type == ImageType::GIF && repeat != Some(0) {
+                image_bytes = set_gif_repeat(image_bytes, repeat);
+            }
+            Ok((width, height, image_bytes, out_type))
+        };
+
+        let (mut proc_out_width, mut proc_out_height, mut proc_out_image_bytes, mut proc_out_type) =
+            encode(&options)?;
+
+        if let Some(byte_budget) = display_options.byte_budget {
+            while proc_out_image_bytes.len() > byte_budget as usize {
+                let overshoot = proc_out_image_bytes.len() as f64 / byte_budget as f64;
+                let next = match options
+                    .encode
+                    .turned_down(overshoot, resizer.can_drop_frames())
+                {
+                    Some(next) => next,
+                    None => {
+                        info!(
+                            "Couldn't fit image {} under {} bytes, keeping {} bytes",
+                            out_uuid,
+                            byte_budget,
+                            proc_out_image_bytes.len()
+                        );
+                        break;
+                    }
+                };
+                options.encode = next;
+
+                let (width, height, image_bytes, out_type) = encode(&options)?;
+                proc_out_width = width;
+                proc_out_height = height;
+                proc_out_image_bytes = image_bytes;
+                proc_out_type = out_type;
+            }
+        }
+
+        let frame_info = FrameInfo::probe(&proc_out_image_bytes).unwrap_or_else(FrameInfo::still);
+        Ok((
+            proc_out_width,
+            proc_out_height,
+            proc_out_type,
+            options.encode,
+            frame_info,
+            proc_out_image_bytes,
+        ))
     }
 }
@@ -1,8 +1,8 @@
-use crate::image::{resizer_backends::VipsResizerBackend, ResizerBackend};
+use crate::image::{resizer_backends, FrameInfo, ResizerBackend};
 use anyhow::Result;
 use std::sync::Arc;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ImageType {
     WEBPAnimated,
     WEBPStill,
@@ -14,29 +14,93 @@ pub enum ImageType {
     SVG,
 }
 
+impl ImageType {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageType::WEBPAnimated | ImageType::WEBPStill => "image/webp",
+            ImageType::PNG => "image/png",
+            ImageType::JPEG => "image/jpeg",
+            ImageType::GIF => "image/gif",
+            ImageType::APNG => "image/apng",
+            ImageType::Lottie => "application/json",
+            ImageType::SVG => "image/svg+xml",
+        }
+    }
+
+    // Work out the type from the magic bytes, since the content type we were given can't always be trusted
+    pub fn sniff(buffer: &[u8]) -> Option<Self> {
+        if buffer.starts_with(b"\x89PNG\r\n\x1a\n") {
+            // an APNG is a PNG with an acTL chunk somewhere before the image data
+            let actl = buffer.windows(4).position(|w| w == b"acTL");
+            let idat = buffer.windows(4).position(|w| w == b"IDAT");
+            return Some(match (actl, idat) {
+                (Some(actl), Some(idat)) if actl < idat => ImageType::APNG,
+                _ => ImageType::PNG,
+            });
+        }
+        if buffer.starts_with(b"GIF87a") || buffer.starts_with(b"GIF89a") {
+            return Some(ImageType::GIF);
+        }
+        if buffer.starts_with(&[0xff, 0xd8, 0xff]) {
+            return Some(ImageType::JPEG);
+        }
+        if buffer.len() >= 12 && &buffer[0..4] == b"RIFF" && &buffer[8..12] == b"WEBP" {
+            // the animation flag lives in the VP8X header
+            if buffer.len() >= 21 && &buffer[12..16] == b"VP8X" && buffer[20] & 0x02 != 0 {
+                return Some(ImageType::WEBPAnimated);
+            }
+            return Some(ImageType::WEBPStill);
+        }
+
+        let text = buffer.strip_prefix(b"\xef\xbb\xbf").unwrap_or(buffer);
+        let text_start = text
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .map(|pos| &text[pos..])?;
+        // anything XML-ish, so an SVG can't skip the header checks by starting with a comment
+        if [&b"<svg"[..], b"<?xml", b"<!--", b"<!DOCTYPE"]
+            .iter()
+            .any(|start| text_start.starts_with(start))
+        {
+            Some(ImageType::SVG)
+        } else if text_start.starts_with(b"{") {
+            Some(ImageType::Lottie)
+        } else {
+            None
+        }
+    }
+}
+
 pub struct ImageTypeHandler {
     pub image_type: ImageType,
     pub image_resizer: Box<dyn ResizerBackend + Send>,
     pub image_buffer: Arc<Vec<u8>>,
+    pub frame_info: FrameInfo,
 }
 
 impl ImageTypeHandler {
     // this function is not very nice ):
+    // the content type can come from the client, so anything that can't be resized is None rather than a panic
     pub fn from_content_type(content_type: &str, image_buffer: Vec<u8>) -> Result<Option<Self>> {
         let image_buffer = Arc::new(image_buffer);
-        let no_frames = match content_type {
+        // anything the headers can't be made sense of gets treated as a still, the resizer will complain if it's broken
+        let frame_info = match content_type {
             "image/jpeg" | "image/png" | "image/gif" | "image/apng" | "image/webp" => {
-                VipsResizerBackend::no_frames(Arc::clone(&image_buffer))
+                FrameInfo::probe(&image_buffer).unwrap_or_else(FrameInfo::still)
             }
-            _ => unimplemented!(),
-        }?;
+            // only what the header checks saw as an SVG gets rendered as one
+            "image/svg+xml" if ImageType::sniff(&image_buffer) == Some(ImageType::SVG) => {
+                FrameInfo::still()
+            }
+            _ => return Ok(None),
+        };
 
         let image_type = match content_type {
             "image/webp" => {
-                if no_frames > 1 {
-                    ImageType::WEBPStill
-                } else {
+                if frame_info.frame_count() > 1 {
                     ImageType::WEBPAnimated
+                } else {
+                    ImageType::WEBPStill
                 }
             }
             "image/png" => ImageType::PNG,
@@ -50,17 +114,19 @@ impl ImageTypeHandler {
             }
         };
 
-        let image_resizer: Box<dyn ResizerBackend + Send> = Box::new(match content_type {
-            "image/jpeg" | "image/png" | "image/gif" | "image/webp" => {
-                VipsResizerBackend::new(Arc::clone(&image_buffer), image_type)
+        let image_resizer: Box<dyn ResizerBackend + Send> = match content_type {
+            "image/jpeg" | "image/png" | "image/gif" | "image/apng" | "image/webp"
+            | "image/svg+xml" => {
+                resizer_backends::configured_resizer(Arc::clone(&image_buffer), image_type)?
             }
-            _ => unimplemented!(),
-        });
+            _ => return Ok(None),
+        };
 
         Ok(Some(ImageTypeHandler {
             image_type,
             image_resizer,
             image_buffer,
+            frame_info,
         }))
     }
 }
@@ -0,0 +1,344 @@
+use crate::image::ImageType;
+
+// EXIF orientation tag, 1 means the pixels are already the right way up
+const ORIENTATION_TAG: u16 = 0x0112;
+
+// 1-8 from the EXIF data of a JPEG, PNG or WebP
+pub fn exif_orientation(buffer: &[u8]) -> Option<u16> {
+    let tiff = match ImageType::sniff(buffer)? {
+        ImageType::JPEG => jpeg_segments(buffer)?
+            .into_iter()
+            .find(|(marker, data)| *marker == 0xe1 && data.starts_with(b"Exif\0\0"))
+            .map(|(_, data)| &data[6..])?,
+        ImageType::PNG | ImageType::APNG => png_chunks(buffer)?
+            .into_iter()
+            .find(|(chunk_type, _)| chunk_type == b"eXIf")
+            .map(|(_, data)| data)?,
+        ImageType::WEBPStill | ImageType::WEBPAnimated => riff_chunks(buffer)?
+            .into_iter()
+            .find(|(chunk_type, _)| chunk_type == b"EXIF")
+            .map(|(_, data)| data)?,
+        _ => return None,
+    };
+    tiff_orientation(tiff).filter(|orientation| (1..=8).contains(orientation))
+}
+
+// Drops EXIF, XMP, comments and text chunks. The colour profile stays since the colours would change without it,
+// and so does the orientation, as a tiny EXIF block of its own. Anything that can't be parsed is given back untouched.
+pub fn strip_metadata(buffer: &[u8]) -> Vec<u8> {
+    stripped(buffer).unwrap_or_else(|| buffer.to_vec())
+}
+
+// Whether handing out the file as it is would give away more than the pixels (and the colour profile).
+// An orientation counts too, browsers don't all apply it. Anything that can't be checked counts as yes.
+pub fn has_metadata(buffer: &[u8]) -> bool {
+    match ImageType::sniff(buffer) {
+        Some(ImageType::GIF) => gif_has_metadata(buffer).unwrap_or(true),
+        Some(_) if exif_orientation(buffer).map_or(false, |orientation| orientation != 1) => true,
+        Some(ImageType::JPEG)
+        | Some(ImageType::PNG)
+        | Some(ImageType::APNG)
+        | Some(ImageType::WEBPStill)
+        | Some(ImageType::WEBPAnimated) => {
+            stripped(buffer).map_or(true, |stripped| stripped != buffer)
+        }
+        _ => true,
+    }
+}
+
+fn stripped(buffer: &[u8]) -> Option<Vec<u8>> {
+    let orientation = exif_orientation(buffer).filter(|orientation| *orientation != 1);
+    match ImageType::sniff(buffer)? {
+        ImageType::JPEG => strip_jpeg(buffer, orientation),
+        ImageType::PNG | ImageType::APNG => strip_png(buffer, orientation),
+        ImageType::WEBPStill | ImageType::WEBPAnimated => strip_webp(buffer, orientation),
+        _ => None,
+    }
+}
+
+// Comments, and application extensions (XMP and such) other than the loop count
+fn gif_has_metadata(buffer: &[u8]) -> Option<bool> {
+    let skip_sub_blocks = |mut pos: usize| -> Option<usize> {
+        loop {
+            let length = *buffer.get(pos)? as usize;
+            pos += 1 + length;
+            if length == 0 {
+                return Some(pos);
+            }
+        }
+    };
+
+    let packed = *buffer.get(10)?;
+    let mut pos = 13;
+    if packed & 0x80 != 0 {
+        pos += 3 << ((packed & 0x07) + 1);
+    }
+    loop {
+        match *buffer.get(pos)? {
+            0x21 => {
+                let label = *buffer.get(pos + 1)?;
+                if label == 0xfe
+                    || (label == 0xff && buffer.get(pos + 2..pos + 14)? != b"\x0bNETSCAPE2.0")
+                {
+                    return Some(true);
+                }
+                pos = skip_sub_blocks(pos + 2)?;
+            }
+            0x2c => {
+                let packed = *buffer.get(pos + 9)?;
+                pos += 10;
+                if packed & 0x80 != 0 {
+                    pos += 3 << ((packed & 0x07) + 1);
+                }
+                // the LZW code size comes before the image data
+                pos = skip_sub_blocks(pos + 1)?;
+            }
+            0x3b => return Some(false),
+            _ => return None,
+        }
+    }
+}
+
+fn read_u16(data: &[u8], at: usize, little_endian: bool) -> Option<u16> {
+    let bytes = [*data.get(at)?, *data.get(at + 1)?];
+    Some(if little_endian {
+        u16::from_le_bytes(bytes)
+    } else {
+        u16::from_be_bytes(bytes)
+    })
+}
+
+fn read_u32(data: &[u8], at: usize, little_endian: bool) -> Option<u32> {
+    let bytes = [
+        *data.get(at)?,
+        *data.get(at + 1)?,
+        *data.get(at + 2)?,
+        *data.get(at + 3)?,
+    ];
+    Some(if little_endian {
+        u32::from_le_bytes(bytes)
+    } else {
+        u32::from_be_bytes(bytes)
+    })
+}
+
+// only IFD0 is looked at, that's where the orientation lives
+fn tiff_orientation(tiff: &[u8]) -> Option<u16> {
+    let little_endian = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let ifd = read_u32(tiff, 4, little_endian)? as usize;
+    let entries = read_u16(tiff, ifd, little_endian)? as usize;
+    for entry in 0..entries {
+        let at = ifd + 2 + entry * 12;
+        if read_u16(tiff, at, little_endian)? == ORIENTATION_TAG {
+            return read_u16(tiff, at + 8, little_endian);
+        }
+    }
+    None
+}
+
+// Big-endian TIFF with a single IFD0 entry
+fn minimal_exif(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+    tiff.extend_from_slice(&3u16.to_be_bytes()); // SHORT
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    tiff.extend_from_slice(&0u32.to_be_bytes()); // no next IFD
+    tiff
+}
+
+// (marker, data) of every segment before the image data
+fn jpeg_segments(buffer: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut segments = vec![];
+    let mut pos = 2;
+    loop {
+        if *buffer.get(pos)? != 0xff {
+            return None;
+        }
+        let marker = *buffer.get(pos + 1)?;
+        match marker {
+            0xff => pos += 1,
+            0x01 | 0xd0..=0xd7 => pos += 2,
+            // start of scan, the rest is image data
+            0xda | 0xd9 => return Some(segments),
+            _ => {
+                let length = read_u16(buffer, pos + 2, false)? as usize;
+                segments.push((marker, buffer.get(pos + 4..pos + 2 + length)?));
+                pos += 2 + length;
+            }
+        }
+    }
+}
+
+fn strip_jpeg(buffer: &[u8], orientation: Option<u16>) -> Option<Vec<u8>> {
+    let mut out = vec![0xff, 0xd8];
+    let write_segment = |out: &mut Vec<u8>, marker: u8, data: &[u8]| {
+        out.extend_from_slice(&[0xff, marker]);
+        out.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(data);
+    };
+
+    if let Some(orientation) = orientation {
+        let mut exif = b"Exif\0\0".to_vec();
+        exif.extend_from_slice(&minimal_exif(orientation));
+        write_segment(&mut out, 0xe1, &exif);
+    }
+
+    let mut pos = 2;
+    loop {
+        if *buffer.get(pos)? != 0xff {
+            return None;
+        }
+        let marker = *buffer.get(pos + 1)?;
+        match marker {
+            0xff => pos += 1,
+            0x01 | 0xd0..=0xd7 => {
+                out.extend_from_slice(&buffer[pos..pos + 2]);
+                pos += 2;
+            }
+            0xda | 0xd9 => {
+                out.extend_from_slice(&buffer[pos..]);
+                return Some(out);
+            }
+            _ => {
+                let length = read_u16(buffer, pos + 2, false)? as usize;
+                let data = buffer.get(pos + 4..pos + 2 + length)?;
+                let keep = match marker {
+                    // JFIF and Adobe are needed to get the colours right, and so is the ICC profile
+                    0xe0 | 0xee => true,
+                    0xe2 => data.starts_with(b"ICC_PROFILE\0"),
+                    // every other APPn and comments
+                    0xe1..=0xef | 0xfe => false,
+                    _ => true,
+                };
+                if keep {
+                    write_segment(&mut out, marker, data);
+                }
+                pos += 2 + length;
+            }
+        }
+    }
+}
+
+// (type, data) of every chunk
+fn png_chunks(buffer: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    let mut chunks = vec![];
+    let mut pos = 8;
+    while pos < buffer.len() {
+        let length = read_u32(buffer, pos, false)? as usize;
+        let chunk_type = buffer.get(pos + 4..pos + 8)?;
+        let data = buffer.get(pos + 8..pos + 8 + length)?;
+        chunks.push((
+            [chunk_type[0], chunk_type[1], chunk_type[2], chunk_type[3]],
+            data,
+        ));
+        pos += 12 + length;
+    }
+    Some(chunks)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn strip_png(buffer: &[u8], orientation: Option<u16>) -> Option<Vec<u8>> {
+    let write_chunk = |out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]| {
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(chunk_type);
+        out.extend_from_slice(data);
+        out.extend_from_slice(&crc32(&[&chunk_type[..], data].concat()).to_be_bytes());
+    };
+
+    let mut out = buffer[..8].to_vec();
+    let mut exif = orientation.map(minimal_exif);
+    for (chunk_type, data) in png_chunks(buffer)? {
+        match &chunk_type {
+            b"tEXt" | b"zTXt" | b"iTXt" | b"eXIf" | b"tIME" => continue,
+            // eXIf has to come before the image data
+            b"IDAT" | b"acTL" => {
+                if let Some(exif) = exif.take() {
+                    write_chunk(&mut out, b"eXIf", &exif);
+                }
+            }
+            _ => (),
+        }
+        write_chunk(&mut out, &chunk_type, data);
+    }
+    Some(out)
+}
+
+// (type, data) of every chunk inside the RIFF container
+fn riff_chunks(buffer: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    let mut chunks = vec![];
+    let mut pos = 12;
+    while pos < buffer.len() {
+        let chunk_type = buffer.get(pos..pos + 4)?;
+        let length = read_u32(buffer, pos + 4, true)? as usize;
+        let data = buffer.get(pos + 8..pos + 8 + length)?;
+        chunks.push((
+            [chunk_type[0], chunk_type[1], chunk_type[2], chunk_type[3]],
+            data,
+        ));
+        // chunks are padded to an even length
+        pos += 8 + length + (length & 1);
+    }
+    Some(chunks)
+}
+
+fn strip_webp(buffer: &[u8], orientation: Option<u16>) -> Option<Vec<u8>> {
+    let write_chunk = |out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]| {
+        out.extend_from_slice(chunk_type);
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+        if data.len() & 1 != 0 {
+            out.push(0);
+        }
+    };
+
+    let chunks = riff_chunks(buffer)?;
+    let mut out = b"RIFF\0\0\0\0WEBP".to_vec();
+    for (chunk_type, data) in &chunks {
+        match chunk_type {
+            b"EXIF" | b"XMP " => (),
+            b"VP8X" => {
+                let mut chunk = data.to_vec();
+                // a VP8X without its flags is malformed
+                let flags = chunk.get_mut(0)?;
+                // the XMP flag goes, the EXIF one stays only if we're keeping the orientation
+                *flags &= !0x04;
+                if orientation.is_none() {
+                    *flags &= !0x08;
+                }
+                write_chunk(&mut out, chunk_type, &chunk);
+            }
+            _ => write_chunk(&mut out, chunk_type, data),
+        }
+    }
+    // only extended WebPs can have EXIF, and it goes at the end
+    if let Some(orientation) = orientation {
+        if chunks.iter().any(|(chunk_type, _)| chunk_type == b"VP8X") {
+            write_chunk(&mut out, b"EXIF", &minimal_exif(orientation));
+        }
+    }
+
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(out)
+}
@@ -1,7 +1,36 @@
+mod animation;
+mod display_options;
+mod frames;
+mod image_hash;
+mod image_header;
 mod image_processor;
 mod image_type;
+mod metadata;
+mod normalize;
+mod optimizer;
+mod output_options;
+mod placeholder;
 mod resizer_backends;
+mod scale_kernel;
+mod transforms;
+mod upload_limits;
+mod upscale;
 
-pub use image_processor::ImageProcessor;
+pub use animation::Animation;
+pub use display_options::DisplayOptions;
+pub use image_hash::{DuplicateAction, DuplicatePolicy, ImageHashes};
+pub use image_header::{FrameInfo, ImageHeader};
+pub use image_processor::{ImageProcessor, PIPELINE_VERSION};
 pub use image_type::{ImageType, ImageTypeHandler};
-pub use resizer_backends::ResizerBackend;
+pub use metadata::{exif_orientation, has_metadata, strip_metadata};
+pub use normalize::{ContentBox, Normalize};
+pub use optimizer::OptimizeSettings;
+pub use output_options::OutputOptions;
+pub use placeholder::Placeholder;
+#[cfg(feature = "vips")]
+pub use resizer_backends::VipsResizerBackend;
+pub use resizer_backends::{EncodeSettings, ResizeOptions, ResizerBackend};
+pub use scale_kernel::ScaleKernel;
+pub use transforms::Transforms;
+pub use upload_limits::{upload_error, UploadLimitError, UploadLimits};
+pub use upscale::UpscalePolicy;
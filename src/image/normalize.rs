@@ -0,0 +1,457 @@
+use anyhow::{anyhow, Result};
+
+use crate::image::{
+    frames::{decode_gif, decode_png, encode_gif, encode_png, CanvasFrame},
+    ImageType,
+};
+
+// How much a pixel can differ from a solid border colour and still count as border
+const BORDER_TOLERANCE: i32 = 8;
+
+// Clean-up of an emote's picture that happens every time it's resized. The original is stored untouched,
+// so this can be changed after the upload and the resized images made again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Normalize {
+    // cut off transparent borders, or borders of one solid colour
+    pub trim: bool,
+    // centre the picture on a transparent square
+    pub pad_square: bool,
+    // only this part of the source is used, trimming happens inside it
+    pub crop: Option<ContentBox>,
+    // in source pixels, what stays in the picture when it has to be cut down to another shape
+    pub focal_point: Option<(u32, u32)>,
+}
+
+// In source pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentBox {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Normalize {
+    pub fn is_noop(&self) -> bool {
+        !self.trim && !self.pad_square && self.crop.is_none()
+    }
+
+    // The part of the source worth keeping, across all its frames. `frames` are RGBA, and only needed for trimming.
+    pub fn content_box(&self, width: u32, height: u32, frames: &[Vec<u8>]) -> ContentBox {
+        if width == 0 || height == 0 {
+            return ContentBox {
+                left: 0,
+                top: 0,
+                width,
+                height,
+            };
+        }
+        // the crop was chosen before the image was looked at, so it might not fit
+        let whole = match self.crop {
+            Some(crop) => {
+                let left = crop.left.min(width - 1);
+                let top = crop.top.min(height - 1);
+                ContentBox {
+                    left,
+                    top,
+                    width: crop.width.max(1).min(width - left),
+                    height: crop.height.max(1).min(height - top),
+                }
+            }
+            None => ContentBox {
+                left: 0,
+                top: 0,
+                width,
+                height,
+            },
+        };
+        if !self.trim || frames.is_empty() {
+            return whole;
+        }
+
+        let pixel = |frame: &[u8], x: u32, y: u32| {
+            let i = ((y * width + x) * 4) as usize;
+            [frame[i], frame[i + 1], frame[i + 2], frame[i + 3]]
+        };
+
+        // the border is transparent, or whatever colour all four corners agree on
+        let first = &frames[0];
+        let (last_x, last_y) = (whole.left + whole.width - 1, whole.top + whole.height - 1);
+        let corners = [
+            pixel(first, whole.left, whole.top),
+            pixel(first, last_x, whole.top),
+            pixel(first, whole.left, last_y),
+            pixel(first, last_x, last_y),
+        ];
+        let is_border: Box<dyn Fn([u8; 4]) -> bool> = if corners[0][3] == 0 {
+            Box::new(|p: [u8; 4]| p[3] == 0)
+        } else if corners.iter().all(|corner| *corner == corners[0]) {
+            let border = corners[0];
+            Box::new(move |p: [u8; 4]| {
+                (0..4).all(|c| (p[c] as i32 - border[c] as i32).abs() <= BORDER_TOLERANCE)
+            })
+        } else {
+            return whole;
+        };
+
+        let (mut left, mut top, mut right, mut bottom) = (width, height, 0, 0);
+        for frame in frames {
+            for y in whole.top..whole.top + whole.height {
+                for x in whole.left..whole.left + whole.width {
+                    if !is_border(pixel(frame, x, y)) {
+                        left = left.min(x);
+                        top = top.min(y);
+                        right = right.max(x + 1);
+                        bottom = bottom.max(y + 1);
+                    }
+                }
+            }
+        }
+
+        // nothing but border, there's nothing sensible to trim to
+        if right == 0 {
+            return whole;
+        }
+        ContentBox {
+            left,
+            top,
+            width: right - left,
+            height: bottom - top,
+        }
+    }
+
+    // The biggest part of `content` that's the same shape as a width x height box, as close to the focal point's
+    // middle as it can be (or the middle of the content without one)
+    pub fn cover_box(&self, content: &ContentBox, width: u32, height: u32) -> ContentBox {
+        let aspect = width.max(1) as f64 / height.max(1) as f64;
+        let (cover_width, cover_height) = if content.width as f64 / content.height as f64 > aspect {
+            (
+                ((content.height as f64 * aspect).round() as u32)
+                    .max(1)
+                    .min(content.width),
+                content.height,
+            )
+        } else {
+            (
+                content.width,
+                ((content.width as f64 / aspect).round() as u32)
+                    .max(1)
+                    .min(content.height),
+            )
+        };
+
+        let (focal_x, focal_y) = self.focal_point.unwrap_or((
+            content.left + content.width / 2,
+            content.top + content.height / 2,
+        ));
+        let centred_on = |focal: u32, start: u32, length: u32, cover_length: u32| {
+            focal
+                .saturating_sub(cover_length / 2)
+                .max(start)
+                .min(start + length - cover_length)
+        };
+        ContentBox {
+            left: centred_on(focal_x, content.left, content.width, cover_width),
+            top: centred_on(focal_y, content.top, content.height, cover_height),
+            width: cover_width,
+            height: cover_height,
+        }
+    }
+
+    // The width to ask the resizer for, so that the content comes out `out_width` wide
+    // (or as wide as the square when padding)
+    pub fn resize_width(&self, in_width: u32, content: &ContentBox, out_width: u32) -> u32 {
+        let content_side = if self.pad_square {
+            content.width.max(content.height)
+        } else {
+            content.width
+        };
+        ((in_width as f64 * out_width as f64 / content_side as f64).round() as u32).max(1)
+    }
+
+    // Crops what the resizer made down to the content, then pads it. `scale` is how much smaller
+    // the resizer made the source. Returns the new width, height and buffer.
+    pub fn apply(
+        &self,
+        buffer: Vec<u8>,
+        image_type: ImageType,
+        content: &ContentBox,
+        scale: f64,
+    ) -> Result<(u32, u32, Vec<u8>)> {
+        match image_type {
+            ImageType::GIF => {
+                let (width, height, frames) = decode_gif(&buffer)?;
+                let (out_width, out_height, crop) =
+                    self.geometry(width as u32, height as u32, content, scale);
+                let frames: Vec<CanvasFrame> = frames
+                    .into_iter()
+                    .map(|frame| CanvasFrame {
+                        pixels: place(&frame.pixels, width as u32, &crop, out_width, out_height),
+                        delay: frame.delay,
+                    })
+                    .collect();
+                Ok((
+                    out_width,
+                    out_height,
+                    encode_gif(out_width as u16, out_height as u16, &frames)?,
+                ))
+            }
+            ImageType::PNG => {
+                let (width, height, pixels) = match decode_png(&buffer)? {
+                    Some(decoded) => decoded,
+                    None => return Err(anyhow!("can't normalize an animated PNG")),
+                };
+                let (out_width, out_height, crop) = self.geometry(width, height, content, scale);
+                let pixels = place(&pixels, width, &crop, out_width, out_height);
+                Ok((
+                    out_width,
+                    out_height,
+                    encode_png(out_width, out_height, &pixels)?,
+                ))
+            }
+            _ => Err(anyhow!("can't normalize {}", image_type.content_type())),
+        }
+    }
+
+    // Size of the output, and where the content is in the resized image
+    fn geometry(
+        &self,
+        width: u32,
+        height: u32,
+        content: &ContentBox,
+        scale: f64,
+    ) -> (u32, u32, ContentBox) {
+        let left = ((content.left as f64 * scale).round() as u32).min(width - 1);
+        let top = ((content.top as f64 * scale).round() as u32).min(height - 1);
+        let crop = ContentBox {
+            left,
+            top,
+            width: ((content.width as f64 * scale).round() as u32)
+                .max(1)
+                .min(width - left),
+            height: ((content.height as f64 * scale).round() as u32)
+                .max(1)
+                .min(height - top),
+        };
+
+        if self.pad_square {
+            let side = crop.width.max(crop.height);
+            (side, side, crop)
+        } else {
+            (crop.width, crop.height, crop)
+        }
+    }
+}
+
+// Copies the `crop` part of an image into the middle of a transparent out_width x out_height one
+fn place(
+    pixels: &[[u8; 4]],
+    width: u32,
+    crop: &ContentBox,
+    out_width: u32,
+    out_height: u32,
+) -> Vec<[u8; 4]> {
+    let mut out = vec![[0u8; 4]; (out_width * out_height) as usize];
+    let (offset_x, offset_y) = ((out_width - crop.width) / 2, (out_height - crop.height) / 2);
+    for y in 0..crop.height {
+        for x in 0..crop.width {
+            out[((offset_y + y) * out_width + offset_x + x) as usize] =
+                pixels[((crop.top + y) * width + crop.left + x) as usize];
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+    const RED: [u8; 4] = [255, 0, 0, 255];
+
+    fn content_box(left: u32, top: u32, width: u32, height: u32) -> ContentBox {
+        ContentBox {
+            left,
+            top,
+            width,
+            height,
+        }
+    }
+
+    // a width x height RGBA frame of `border`, with `inside` filling `content`
+    fn frame(
+        width: u32,
+        height: u32,
+        border: [u8; 4],
+        inside: [u8; 4],
+        content: ContentBox,
+    ) -> Vec<u8> {
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let within = (content.left..content.left + content.width).contains(&x)
+                    && (content.top..content.top + content.height).contains(&y);
+                if within {
+                    inside
+                } else {
+                    border
+                }
+            })
+            .collect()
+    }
+
+    fn trim() -> Normalize {
+        Normalize {
+            trim: true,
+            ..Normalize::default()
+        }
+    }
+
+    #[test]
+    fn trims_transparent_borders() {
+        let frames = [frame(10, 8, CLEAR, RED, content_box(2, 3, 4, 2))];
+        assert_eq!(trim().content_box(10, 8, &frames), content_box(2, 3, 4, 2));
+        // without trimming it's all kept
+        assert_eq!(
+            Normalize::default().content_box(10, 8, &frames),
+            content_box(0, 0, 10, 8)
+        );
+    }
+
+    #[test]
+    fn trims_solid_borders() {
+        let mut frames = [frame(10, 8, WHITE, RED, content_box(1, 1, 3, 3))];
+        // close enough to white to still be border
+        frames[0][(6 * 10 + 8) * 4] = 250;
+        assert_eq!(trim().content_box(10, 8, &frames), content_box(1, 1, 3, 3));
+        // but not this
+        frames[0][(6 * 10 + 8) * 4] = 200;
+        assert_eq!(trim().content_box(10, 8, &frames), content_box(1, 1, 8, 6));
+    }
+
+    #[test]
+    fn keeps_everything_without_a_border() {
+        // the corners don't agree on a colour
+        let mut frames = [frame(10, 8, WHITE, RED, content_box(2, 2, 2, 2))];
+        frames[0][..4].copy_from_slice(&RED);
+        assert_eq!(trim().content_box(10, 8, &frames), content_box(0, 0, 10, 8));
+        // all border
+        let frames = [frame(10, 8, CLEAR, CLEAR, content_box(0, 0, 0, 0))];
+        assert_eq!(trim().content_box(10, 8, &frames), content_box(0, 0, 10, 8));
+        assert_eq!(trim().content_box(0, 8, &[]), content_box(0, 0, 0, 8));
+    }
+
+    #[test]
+    fn trims_to_every_frame() {
+        let frames = [
+            frame(10, 8, CLEAR, RED, content_box(1, 1, 2, 2)),
+            frame(10, 8, CLEAR, RED, content_box(5, 4, 2, 2)),
+        ];
+        assert_eq!(trim().content_box(10, 8, &frames), content_box(1, 1, 6, 5));
+    }
+
+    #[test]
+    fn crops_fit_the_source() {
+        let crop = |crop| Normalize {
+            crop: Some(crop),
+            ..Normalize::default()
+        };
+        assert_eq!(
+            crop(content_box(8, 6, 10, 10)).content_box(10, 8, &[]),
+            content_box(8, 6, 2, 2)
+        );
+        assert_eq!(
+            crop(content_box(20, 20, 0, 0)).content_box(10, 8, &[]),
+            content_box(9, 7, 1, 1)
+        );
+
+        // only what's inside the crop gets trimmed to
+        let mut frames = [frame(10, 8, CLEAR, RED, content_box(3, 3, 2, 2))];
+        frames[0][..4].copy_from_slice(&RED);
+        let normalize = Normalize {
+            crop: Some(content_box(2, 2, 6, 6)),
+            ..trim()
+        };
+        assert_eq!(
+            normalize.content_box(10, 8, &frames),
+            content_box(3, 3, 2, 2)
+        );
+    }
+
+    #[test]
+    fn covers_from_the_middle() {
+        let normalize = Normalize::default();
+        let content = content_box(0, 0, 100, 50);
+        assert_eq!(
+            normalize.cover_box(&content, 1, 1),
+            content_box(25, 0, 50, 50)
+        );
+        assert_eq!(
+            normalize.cover_box(&content, 1, 2),
+            content_box(38, 0, 25, 50)
+        );
+        assert_eq!(
+            normalize.cover_box(&content, 4, 1),
+            content_box(0, 13, 100, 25)
+        );
+        assert_eq!(
+            normalize.cover_box(&content_box(10, 20, 40, 40), 2, 1),
+            content_box(10, 30, 40, 20)
+        );
+    }
+
+    #[test]
+    fn covers_around_the_focal_point() {
+        let focal = |x, y| Normalize {
+            focal_point: Some((x, y)),
+            ..Normalize::default()
+        };
+        let content = content_box(0, 0, 100, 50);
+        assert_eq!(
+            focal(40, 10).cover_box(&content, 1, 1),
+            content_box(15, 0, 50, 50)
+        );
+        // pushed back inside the content at the edges
+        assert_eq!(
+            focal(95, 10).cover_box(&content, 1, 1),
+            content_box(50, 0, 50, 50)
+        );
+        assert_eq!(
+            focal(0, 0).cover_box(&content, 1, 1),
+            content_box(0, 0, 50, 50)
+        );
+    }
+
+    #[test]
+    fn resize_width_fits_the_content() {
+        let content = content_box(10, 0, 20, 40);
+        assert_eq!(Normalize::default().resize_width(100, &content, 32), 160);
+        let pad = Normalize {
+            pad_square: true,
+            ..Normalize::default()
+        };
+        assert_eq!(pad.resize_width(100, &content, 32), 80);
+    }
+
+    #[test]
+    fn pads_to_a_square() {
+        let png = encode_png(4, 2, &[RED, RED, RED, WHITE, WHITE, WHITE, WHITE, WHITE]).unwrap();
+        let normalize = Normalize {
+            pad_square: true,
+            ..Normalize::default()
+        };
+        let (width, height, out) = normalize
+            .apply(png, ImageType::PNG, &content_box(0, 0, 3, 1), 1.0)
+            .unwrap();
+        assert_eq!((width, height), (3, 3));
+        assert_eq!(
+            decode_png(&out).unwrap(),
+            Some((
+                3,
+                3,
+                vec![CLEAR, CLEAR, CLEAR, RED, RED, RED, CLEAR, CLEAR, CLEAR]
+            ))
+        );
+    }
+}
@@ -0,0 +1,373 @@
+use anyhow::Result;
+use color_quant::NeuQuant;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::image::{
+    frames::{decode_gif, decode_png, CanvasFrame},
+    ImageType,
+};
+use crate::types::Emote;
+
+// A second pass over what the resizer encoded, along the lines of gifsicle and pngquant.
+// Set globally in the config, and each emote can override any of it.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct OptimizeSettings {
+    pub enabled: bool,
+    // palette size to quantize to, 2-256
+    pub colours: u32,
+    // Floyd-Steinberg dithering when quantizing, smoother gradients but bigger files
+    pub dither: bool,
+    // merge identical frames that follow each other into one longer frame
+    pub dedup_frames: bool,
+    // only store the pixels of a GIF frame that changed since the frame before it
+    pub transparent_diff: bool,
+    // turn PNGs into palette PNGs. This one is lossy for images with lots of colours, so it's off by default
+    pub palette_png: bool,
+}
+
+impl Default for OptimizeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            colours: 256,
+            dither: true,
+            dedup_frames: true,
+            transparent_diff: true,
+            palette_png: false,
+        }
+    }
+}
+
+impl OptimizeSettings {
+    pub fn overridden_by(&self, emote: &Emote) -> Self {
+        Self {
+            enabled: emote.optimize.unwrap_or(self.enabled),
+            colours: emote
+                .optimize_colours
+                .map(|colours| colours.max(2).min(256) as u32)
+                .unwrap_or(self.colours),
+            dither: emote.optimize_dither.unwrap_or(self.dither),
+            dedup_frames: emote.optimize_dedup_frames.unwrap_or(self.dedup_frames),
+            transparent_diff: emote
+                .optimize_transparent_diff
+                .unwrap_or(self.transparent_diff),
+            palette_png: emote.optimize_palette_png.unwrap_or(self.palette_png),
+        }
+    }
+
+    // `max_colours` comes from the size budget, which may want fewer colours than the settings do.
+    // The original buffer is given back if optimizing didn't make it any smaller.
+    pub fn optimize(
+        &self,
+        buffer: Vec<u8>,
+        image_type: ImageType,
+        max_colours: u32,
+    ) -> Result<Vec<u8>> {
+        if !self.enabled {
+            return Ok(buffer);
+        }
+
+        let colours = self.colours.min(max_colours).max(2).min(256);
+        let optimized = match image_type {
+            ImageType::GIF => self.optimize_gif(&buffer, colours)?,
+            ImageType::PNG if self.palette_png || colours < 256 => {
+                match self.optimize_png(&buffer, colours)? {
+                    Some(optimized) => optimized,
+                    None => return Ok(buffer),
+                }
+            }
+            _ => return Ok(buffer),
+        };
+
+        Ok(if optimized.len() < buffer.len() {
+            optimized
+        } else {
+            buffer
+        })
+    }
+
+    fn optimize_gif(&self, buffer: &[u8], colours: u32) -> Result<Vec<u8>> {
+        let (width, height, mut frames) = decode_gif(buffer)?;
+        if self.dedup_frames {
+            frames = dedup_frames(frames);
+        }
+
+        // every frame gets its own palette, with one slot left over for transparency
+        let quantized: Vec<Quantized> = frames
+            .iter()
+            .map(|frame| {
+                let pixels: Vec<Option<[u8; 4]>> = frame
+                    .pixels
+                    .iter()
+                    .map(|pixel| {
+                        if pixel[3] >= 128 {
+                            Some([pixel[0], pixel[1], pixel[2], 255])
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                quantize(&pixels, width as usize, colours - 1, self.dither)
+            })
+            .collect();
+        // what each frame looks like once it's on screen
+        let shown: Vec<Vec<Option<[u8; 4]>>> = quantized
+            .iter()
+            .map(|q| {
+                q.indices
+                    .iter()
+                    .map(|index| index.map(|index| q.palette[index as usize]))
+                    .collect()
+            })
+            .collect();
+
+        let mut out_buffer = vec![];
+        {
+            let mut encoder = gif::Encoder::new(&mut out_buffer, width, height, &[])?;
+            encoder.set_repeat(gif::Repeat::Infinite)?;
+
+            // what's on the canvas before a frame gets drawn, None if it's empty
+            let mut canvas: Option<&Vec<Option<[u8; 4]>>> = None;
+            for (i, frame) in frames.iter().enumerate() {
+                // GIF frames can only draw over what's already there, so when the next frame
+                // makes something transparent again, the canvas has to be cleared after this one
+                let clear_after = match shown.get(i + 1) {
+                    Some(next) => next
+                        .iter()
+                        .zip(&shown[i])
+                        .any(|(next, this)| next.is_none() && this.is_some()),
+                    None => false,
+                };
+                let dispose = if !self.transparent_diff || clear_after {
+                    gif::DisposalMethod::Background
+                } else {
+                    gif::DisposalMethod::Keep
+                };
+
+                // pixels that already look right can be left transparent
+                let drawn: Vec<bool> = match canvas {
+                    Some(canvas) => shown[i].iter().zip(canvas).map(|(s, c)| s != c).collect(),
+                    None => shown[i].iter().map(|s| s.is_some()).collect(),
+                };
+                // clearing only clears the frame's own rectangle, so that has to be the whole canvas
+                let (left, top, right, bottom) = if self.transparent_diff && clear_after {
+                    (0, 0, width as usize, height as usize)
+                } else {
+                    bounding_box(&drawn, width as usize)
+                };
+
+                let transparent_index = quantized[i].palette.len() as u8;
+                let mut indices = Vec::with_capacity((right - left) * (bottom - top));
+                for y in top..bottom {
+                    for x in left..right {
+                        let p = y * width as usize + x;
+                        indices.push(match quantized[i].indices[p] {
+                            Some(index) if drawn[p] => index,
+                            _ => transparent_index,
+                        });
+                    }
+                }
+                let mut palette: Vec<u8> = quantized[i]
+                    .palette
+                    .iter()
+                    .flat_map(|colour| colour[..3].to_vec())
+                    .collect();
+                palette.extend_from_slice(&[0, 0, 0]);
+
+                let mut out_frame = gif::Frame::from_palette_pixels(
+                    (right - left) as u16,
+                    (bottom - top) as u16,
+                    &indices,
+                    &palette,
+                    Some(transparent_index),
+                );
+                out_frame.left = left as u16;
+                out_frame.top = top as u16;
+                out_frame.delay = frame.delay;
+                out_frame.dispose = dispose;
+                encoder.write_frame(&out_frame)?;
+
+                canvas = match dispose {
+                    gif::DisposalMethod::Background => None,
+                    _ => Some(&shown[i]),
+                };
+            }
+        }
+
+        Ok(out_buffer)
+    }
+
+    // None for APNGs, those are left alone
+    fn optimize_png(&self, buffer: &[u8], colours: u32) -> Result<Option<Vec<u8>>> {
+        let (width, height, pixels) = match decode_png(buffer)? {
+            Some(decoded) => decoded,
+            None => return Ok(None),
+        };
+        let pixels: Vec<Option<[u8; 4]>> = pixels.into_iter().map(Some).collect();
+
+        let quantized = quantize(&pixels, width as usize, colours, self.dither);
+        let bitdepth = match quantized.palette.len() {
+            0..=2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 8,
+        };
+
+        // pack the indices into rows, most significant bits first
+        let pixels_per_byte = 8 / bitdepth;
+        let mut data = vec![];
+        for row in quantized.indices.chunks(width as usize) {
+            for pixels in row.chunks(pixels_per_byte) {
+                let mut byte = 0u8;
+                for (i, index) in pixels.iter().enumerate() {
+                    byte |= index.unwrap_or(0) << (8 - bitdepth * (i + 1));
+                }
+                data.push(byte);
+            }
+        }
+
+        let palette: Vec<u8> = quantized
+            .palette
+            .iter()
+            .flat_map(|colour| colour[..3].to_vec())
+            .collect();
+        let mut trns: Vec<u8> = quantized.palette.iter().map(|colour| colour[3]).collect();
+        // tRNS can leave off the opaque entries at the end
+        while trns.last() == Some(&255) {
+            trns.pop();
+        }
+
+        let mut out_buffer = vec![];
+        {
+            let mut encoder = png::Encoder::new(&mut out_buffer, width, height);
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(match bitdepth {
+                1 => png::BitDepth::One,
+                2 => png::BitDepth::Two,
+                4 => png::BitDepth::Four,
+                _ => png::BitDepth::Eight,
+            });
+            encoder.set_compression(png::Compression::Best);
+            encoder.set_palette(palette);
+            if !trns.is_empty() {
+                encoder.set_trns(trns);
+            }
+            encoder.write_header()?.write_image_data(&data)?;
+        }
+
+        Ok(Some(out_buffer))
+    }
+}
+
+fn dedup_frames(frames: Vec<CanvasFrame>) -> Vec<CanvasFrame> {
+    let mut deduped: Vec<CanvasFrame> = vec![];
+    for frame in frames {
+        match deduped.last_mut() {
+            Some(last) if last.pixels == frame.pixels => {
+                last.delay = last.delay.saturating_add(frame.delay)
+            }
+            _ => deduped.push(frame),
+        }
+    }
+    deduped
+}
+
+// left, top, right, bottom (exclusive) of the pixels that are set. At least 1x1, since GIF frames can't be empty.
+fn bounding_box(set: &[bool], width: usize) -> (usize, usize, usize, usize) {
+    let (mut left, mut top, mut right, mut bottom) = (usize::MAX, usize::MAX, 0, 0);
+    for (i, _) in set.iter().enumerate().filter(|(_, set)| **set) {
+        let (x, y) = (i % width, i / width);
+        left = left.min(x);
+        top = top.min(y);
+        right = right.max(x + 1);
+        bottom = bottom.max(y + 1);
+    }
+    if right == 0 {
+        (0, 0, 1, 1)
+    } else {
+        (left, top, right, bottom)
+    }
+}
+
+struct Quantized {
+    palette: Vec<[u8; 4]>,
+    // None where the pixel was None
+    indices: Vec<Option<u8>>,
+}
+
+// Picks a palette of at most `colours` colours for the pixels that are Some. If there are few enough colours
+// already they're all kept as they are, so running this over something that was already quantized loses nothing.
+fn quantize(pixels: &[Option<[u8; 4]>], width: usize, colours: u32, dither: bool) -> Quantized {
+    let mut exact: HashMap<[u8; 4], u8> = HashMap::new();
+    for pixel in pixels.iter().flatten() {
+        if !exact.contains_key(pixel) {
+            if exact.len() as u32 >= colours {
+                exact.clear();
+                break;
+            }
+            exact.insert(*pixel, exact.len() as u8);
+        }
+    }
+
+    if !exact.is_empty() || pixels.iter().all(|pixel| pixel.is_none()) {
+        let mut palette = vec![[0; 4]; exact.len()];
+        for (colour, index) in &exact {
+            palette[*index as usize] = *colour;
+        }
+        return Quantized {
+            palette,
+            indices: pixels
+                .iter()
+                .map(|pixel| pixel.map(|pixel| exact[&pixel]))
+                .collect(),
+        };
+    }
+
+    let samples: Vec<u8> = pixels.iter().flatten().flat_map(|p| p.to_vec()).collect();
+    let quantizer = NeuQuant::new(10, colours as usize, &samples);
+    let palette: Vec<[u8; 4]> = (0..colours as usize)
+        .map_while(|i| quantizer.lookup(i))
+        .collect();
+
+    // Floyd-Steinberg, the error of every pixel is pushed onto the ones right and below it
+    let mut errors = vec![[0f32; 4]; pixels.len()];
+    let mut indices = Vec::with_capacity(pixels.len());
+    for (i, pixel) in pixels.iter().enumerate() {
+        let pixel = match pixel {
+            Some(pixel) => pixel,
+            None => {
+                indices.push(None);
+                continue;
+            }
+        };
+
+        let mut wanted = [0u8; 4];
+        for c in 0..4 {
+            wanted[c] = (pixel[c] as f32 + errors[i][c]).round().max(0.0).min(255.0) as u8;
+        }
+        let index = quantizer.index_of(&wanted).min(palette.len() - 1);
+        indices.push(Some(index as u8));
+
+        if dither {
+            let (x, y) = (i % width, i / width);
+            let mut spread = |x: usize, y: usize, amount: f32| {
+                let j = y * width + x;
+                if x < width && j < pixels.len() {
+                    for c in 0..4 {
+                        errors[j][c] += (wanted[c] as f32 - palette[index][c] as f32) * amount;
+                    }
+                }
+            };
+            spread(x + 1, y, 7.0 / 16.0);
+            if x > 0 {
+                spread(x - 1, y + 1, 3.0 / 16.0);
+            }
+            spread(x, y + 1, 5.0 / 16.0);
+            spread(x + 1, y + 1, 1.0 / 16.0);
+        }
+    }
+
+    Quantized { palette, indices }
+}
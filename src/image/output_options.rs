@@ -0,0 +1,268 @@
+use anyhow::{anyhow, Result};
+
+use crate::image::{
+    frames::{decode_gif, decode_png, encode_gif, encode_png, CanvasFrame},
+    ImageType,
+};
+
+// How the resized image gets written out, eg. "q=80", "bg=ff00ff" or "format=jpeg"
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputOptions {
+    // 1-100, for palette PNGs and JPEGs. None is lossless, or 85 for JPEGs.
+    pub quality: Option<u8>,
+    // transparency is flattened onto this
+    pub background: Option<[u8; 3]>,
+    // stills only, animations stay GIFs. Transparency goes onto the background, or white.
+    pub jpeg: bool,
+}
+
+impl OutputOptions {
+    const JPEG_QUALITY: u8 = 85;
+
+    pub fn is_noop(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // "q=80,bg=ffffff", the same grammar as the modifiers in an emote URL
+    pub fn parse(options: &str) -> Result<Self> {
+        let mut output = Self::default();
+        for modifier in options.split(',').filter(|modifier| !modifier.is_empty()) {
+            let (key, value) = match modifier.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (modifier, None),
+            };
+            if !output.push(key, value)? {
+                return Err(anyhow!("Unknown output option \"{}\"", key));
+            }
+        }
+        Ok(output)
+    }
+
+    // Sets one modifier. false if `key` isn't an output option.
+    pub fn push(&mut self, key: &str, value: Option<&str>) -> Result<bool> {
+        match (key, value) {
+            ("q", Some(value)) => {
+                self.quality = Some(
+                    value
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|quality| (1..=100).contains(quality))
+                        .ok_or_else(|| anyhow!("\"q\" should be a number from 1 to 100"))?,
+                )
+            }
+            ("bg", Some(value)) => self.background = Some(parse_colour(value)?),
+            ("format", Some("jpeg")) | ("format", Some("jpg")) => self.jpeg = true,
+            ("format", Some("auto")) => self.jpeg = false,
+            ("format", _) => {
+                return Err(anyhow!("\"format\" should be format=jpeg or format=auto"))
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // The normalized options, for telling derivatives apart. Empty when there's nothing to do.
+    pub fn key(&self) -> String {
+        let mut modifiers = vec![];
+        if let Some(quality) = self.quality {
+            modifiers.push(format!("q={}", quality));
+        }
+        if let Some([r, g, b]) = self.background {
+            modifiers.push(format!("bg={:02x}{:02x}{:02x}", r, g, b));
+        }
+        if self.jpeg {
+            modifiers.push("format=jpeg".to_owned());
+        }
+        modifiers.join(",")
+    }
+
+    // what the encoder starts from, the size budget can still turn it down
+    pub fn starting_quality(&self) -> u8 {
+        match self.quality {
+            Some(quality) => quality,
+            None if self.jpeg => Self::JPEG_QUALITY,
+            None => 100,
+        }
+    }
+
+    // Puts every pixel onto the background, so nothing is see-through anymore
+    pub fn flatten(&self, buffer: Vec<u8>, image_type: ImageType) -> Result<Vec<u8>> {
+        let background = match self.background {
+            Some(background) => background,
+            None => return Ok(buffer),
+        };
+
+        match image_type {
+            ImageType::GIF => {
+                let (width, height, frames) = decode_gif(&buffer)?;
+                let frames: Vec<CanvasFrame> = frames
+                    .into_iter()
+                    .map(|frame| CanvasFrame {
+                        pixels: flatten_pixels(frame.pixels, background),
+                        delay: frame.delay,
+                    })
+                    .collect();
+                encode_gif(width, height, &frames)
+            }
+            ImageType::PNG => {
+                let (width, height, pixels) = match decode_png(&buffer)? {
+                    Some(decoded) => decoded,
+                    None => return Err(anyhow!("can't flatten an animated PNG")),
+                };
+                encode_png(width, height, &flatten_pixels(pixels, background))
+            }
+            _ => Err(anyhow!("can't flatten {}", image_type.content_type())),
+        }
+    }
+
+    // width, height and the RGB bytes of a still PNG, ready for a JPEG encoder
+    pub fn jpeg_pixels(&self, buffer: &[u8]) -> Result<(u32, u32, Vec<u8>)> {
+        let (width, height, pixels) = match decode_png(buffer)? {
+            Some(decoded) => decoded,
+            None => return Err(anyhow!("can't make a JPEG from an animated PNG")),
+        };
+        let background = self.background.unwrap_or([255, 255, 255]);
+        Ok((
+            width,
+            height,
+            flatten_pixels(pixels, background)
+                .into_iter()
+                .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+                .collect(),
+        ))
+    }
+}
+
+fn flatten_pixels(mut pixels: Vec<[u8; 4]>, background: [u8; 3]) -> Vec<[u8; 4]> {
+    for pixel in pixels.iter_mut() {
+        let alpha = pixel[3] as u32;
+        for (c, background) in pixel.iter_mut().zip(&background) {
+            *c = ((*c as u32 * alpha + *background as u32 * (255 - alpha) + 127) / 255) as u8;
+        }
+        pixel[3] = 255;
+    }
+    pixels
+}
+
+// "ff00ff", or "f0f" for short. No # since that would end the URL.
+fn parse_colour(value: &str) -> Result<[u8; 3]> {
+    let invalid = || anyhow!("\"bg\" should be a hex colour like bg=ff00ff");
+    if !value.is_ascii() {
+        return Err(invalid());
+    }
+    let value = match value.len() {
+        3 => value.chars().flat_map(|c| [c, c]).collect(),
+        6 => value.to_owned(),
+        _ => return Err(invalid()),
+    };
+    let channel = |i: usize| u8::from_str_radix(&value[i..i + 2], 16).map_err(|_| invalid());
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_canonical() {
+        let key = |options| OutputOptions::parse(options).unwrap().key();
+        assert_eq!(key(""), "");
+        assert_eq!(key("format=auto"), "");
+        assert_eq!(key("format=jpg,q=80"), "q=80,format=jpeg");
+        assert_eq!(key("bg=F0F"), "bg=ff00ff");
+        assert_eq!(key("bg=102030,q=1"), "q=1,bg=102030");
+        assert!(OutputOptions::parse("format=jpeg,format=auto")
+            .unwrap()
+            .is_noop());
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        for options in ["q=100", "bg=000000,format=jpeg", "q=5,bg=abc"].iter() {
+            let output = OutputOptions::parse(options).unwrap();
+            assert_eq!(
+                OutputOptions::parse(&output.key()).unwrap(),
+                output,
+                "{}",
+                options
+            );
+        }
+    }
+
+    #[test]
+    fn bad_options_are_errors() {
+        for options in [
+            "q=0",
+            "q=101",
+            "q=high",
+            "q",
+            "bg=ff00f",
+            "bg=gggggg",
+            "bg=ÿÿ",
+            "bg",
+            "format=webp",
+            "format",
+            "sharpen",
+        ]
+        .iter()
+        {
+            assert!(OutputOptions::parse(options).is_err(), "{}", options);
+        }
+        assert!(!OutputOptions::default().push("speed", Some("2")).unwrap());
+    }
+
+    #[test]
+    fn starting_quality() {
+        let quality = |options| OutputOptions::parse(options).unwrap().starting_quality();
+        assert_eq!(quality(""), 100);
+        assert_eq!(quality("format=jpeg"), 85);
+        assert_eq!(quality("q=40"), 40);
+        assert_eq!(quality("q=40,format=jpeg"), 40);
+    }
+
+    #[test]
+    fn flattens_onto_the_background() {
+        assert_eq!(
+            flatten_pixels(
+                vec![[255, 0, 0, 255], [255, 0, 0, 0], [255, 0, 0, 128]],
+                [0, 0, 255]
+            ),
+            vec![[255, 0, 0, 255], [0, 0, 255, 255], [128, 0, 127, 255]]
+        );
+    }
+
+    #[test]
+    fn flattens_pngs() {
+        let png = encode_png(2, 1, &[[0, 0, 0, 0], [10, 20, 30, 255]]).unwrap();
+        assert_eq!(
+            OutputOptions::default()
+                .flatten(png.clone(), ImageType::PNG)
+                .unwrap(),
+            png
+        );
+
+        let options = OutputOptions::parse("bg=ffffff").unwrap();
+        let flat = options.flatten(png.clone(), ImageType::PNG).unwrap();
+        assert_eq!(
+            decode_png(&flat).unwrap(),
+            Some((2, 1, vec![[255, 255, 255, 255], [10, 20, 30, 255]]))
+        );
+        assert!(options.flatten(png, ImageType::JPEG).is_err());
+    }
+
+    #[test]
+    fn jpeg_pixels_default_to_white() {
+        let png = encode_png(2, 1, &[[0, 0, 0, 0], [10, 20, 30, 255]]).unwrap();
+        assert_eq!(
+            OutputOptions::default().jpeg_pixels(&png).unwrap(),
+            (2, 1, vec![255, 255, 255, 10, 20, 30])
+        );
+        assert_eq!(
+            OutputOptions::parse("bg=000")
+                .unwrap()
+                .jpeg_pixels(&png)
+                .unwrap(),
+            (2, 1, vec![0, 0, 0, 10, 20, 30])
+        );
+    }
+}
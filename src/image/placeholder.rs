@@ -0,0 +1,183 @@
+use anyhow::Result;
+
+use crate::image::ResizerBackend;
+
+// Side of the thumbnail everything is worked out from, blurhash doesn't need more
+const THUMBNAIL_SIZE: u32 = 32;
+// blurhash components in each direction, emotes are roughly square
+const COMPONENTS: u32 = 4;
+// pixels less opaque than this don't count towards the colours
+const MIN_ALPHA: u8 = 128;
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+// What a client can show while the emote loads, worked out once from the original
+#[derive(Debug, Clone)]
+pub struct Placeholder {
+    pub blurhash: String,
+    // "#rrggbb"
+    pub average_colour: String,
+    // the most common colour, which stands out more than the average of a colourful emote
+    pub dominant_colour: String,
+    pub animated: bool,
+}
+
+impl Placeholder {
+    pub fn compute(resizer: &dyn ResizerBackend, frame_count: u32) -> Result<Self> {
+        let thumbnail: Vec<[u8; 4]> = resizer
+            .rgba_thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)?
+            .chunks_exact(4)
+            .map(|p| [p[0], p[1], p[2], p[3]])
+            .collect();
+
+        Ok(Self {
+            blurhash: blurhash(&thumbnail),
+            average_colour: hex_colour(average_colour(&thumbnail)),
+            dominant_colour: hex_colour(dominant_colour(&thumbnail)),
+            animated: frame_count > 1,
+        })
+    }
+}
+
+fn hex_colour([r, g, b]: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+// Of the pixels that can actually be seen. Black if there aren't any.
+fn average_colour(pixels: &[[u8; 4]]) -> [u8; 3] {
+    let (mut sum, mut weight) = ([0u64; 3], 0u64);
+    for pixel in pixels.iter().filter(|p| p[3] >= MIN_ALPHA) {
+        for (total, c) in sum.iter_mut().zip(pixel) {
+            *total += *c as u64 * pixel[3] as u64;
+        }
+        weight += pixel[3] as u64;
+    }
+    if weight == 0 {
+        return [0; 3];
+    }
+    [
+        (sum[0] / weight) as u8,
+        (sum[1] / weight) as u8,
+        (sum[2] / weight) as u8,
+    ]
+}
+
+// Average of the most crowded bucket when the colours are cut down to 4 bits per channel
+fn dominant_colour(pixels: &[[u8; 4]]) -> [u8; 3] {
+    let bucket = |p: &[u8; 4]| {
+        ((p[0] >> 4) as usize) << 8 | ((p[1] >> 4) as usize) << 4 | (p[2] >> 4) as usize
+    };
+
+    let mut counts = vec![0u32; 4096];
+    for pixel in pixels.iter().filter(|p| p[3] >= MIN_ALPHA) {
+        counts[bucket(pixel)] += 1;
+    }
+    let most_common = match (0..counts.len()).max_by_key(|i| counts[*i]) {
+        Some(i) if counts[i] > 0 => i,
+        _ => return [0; 3],
+    };
+
+    let in_bucket: Vec<[u8; 4]> = pixels
+        .iter()
+        .filter(|p| p[3] >= MIN_ALPHA && bucket(p) == most_common)
+        .copied()
+        .collect();
+    average_colour(&in_bucket)
+}
+
+fn srgb_to_linear(value: u8) -> f64 {
+    let v = value as f64 / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> u32 {
+    let v = value.clamp(0.0, 1.0);
+    if v <= 0.0031308 {
+        (v * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * v.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+fn base83(value: u32, length: u32, out: &mut String) {
+    for i in 1..=length {
+        let digit = (value / 83u32.pow(length - i)) % 83;
+        out.push(BASE83[digit as usize] as char);
+    }
+}
+
+// https://github.com/woltapp/blurhash/blob/master/Algorithm.md
+// Blurhash has no alpha, so transparent parts are put on white first
+fn blurhash(pixels: &[[u8; 4]]) -> String {
+    let size = THUMBNAIL_SIZE as usize;
+    let linear: Vec<[f64; 3]> = pixels
+        .iter()
+        .map(|p| {
+            let alpha = p[3] as f64 / 255.0;
+            let on_white = |c: u8| (c as f64 * alpha + 255.0 * (1.0 - alpha)).round() as u8;
+            [
+                srgb_to_linear(on_white(p[0])),
+                srgb_to_linear(on_white(p[1])),
+                srgb_to_linear(on_white(p[2])),
+            ]
+        })
+        .collect();
+
+    let mut factors: Vec<[f64; 3]> = vec![];
+    for j in 0..COMPONENTS {
+        for i in 0..COMPONENTS {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+            for y in 0..size {
+                for x in 0..size {
+                    let basis = (std::f64::consts::PI * i as f64 * x as f64 / size as f64).cos()
+                        * (std::f64::consts::PI * j as f64 * y as f64 / size as f64).cos();
+                    for (total, c) in factor.iter_mut().zip(&linear[y * size + x]) {
+                        *total += basis * c;
+                    }
+                }
+            }
+            let scale = normalisation / (size * size) as f64;
+            factors.push([factor[0] * scale, factor[1] * scale, factor[2] * scale]);
+        }
+    }
+
+    let mut hash = String::new();
+    base83((COMPONENTS - 1) + (COMPONENTS - 1) * 9, 1, &mut hash);
+
+    let (dc, ac) = factors.split_first().unwrap();
+    let max_value = if ac.is_empty() {
+        base83(0, 1, &mut hash);
+        1.0
+    } else {
+        let actual_max = ac
+            .iter()
+            .flat_map(|factor| factor.iter())
+            .fold(0.0f64, |max, v| max.max(v.abs()));
+        let quantised = ((actual_max * 166.0 - 0.5).floor() as i64).clamp(0, 82) as u32;
+        base83(quantised, 1, &mut hash);
+        (quantised + 1) as f64 / 166.0
+    };
+
+    base83(
+        (linear_to_srgb(dc[0]) << 16) + (linear_to_srgb(dc[1]) << 8) + linear_to_srgb(dc[2]),
+        4,
+        &mut hash,
+    );
+    for factor in ac {
+        let quantise = |v: f64| {
+            let v = v / max_value;
+            ((v.signum() * v.abs().sqrt() * 9.0 + 9.5).floor() as i64).clamp(0, 18) as u32
+        };
+        base83(
+            quantise(factor[0]) * 19 * 19 + quantise(factor[1]) * 19 + quantise(factor[2]),
+            2,
+            &mut hash,
+        );
+    }
+    hash
+}
@@ -0,0 +1,344 @@
+use crate::image::{
+    exif_orientation, resizer_backends::ResizeOptions, Animation, FrameInfo, ImageType,
+    ResizerBackend, ScaleKernel,
+};
+use ::image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        jpeg::{JpegDecoder, JpegEncoder},
+        png::PngDecoder,
+        webp::WebPDecoder,
+    },
+    imageops::{self, FilterType},
+    io::Reader as ImageReader,
+    AnimationDecoder, ColorType, Delay, DynamicImage, Frame, ImageDecoder, ImageOutputFormat,
+    RgbaImage,
+};
+use anyhow::{anyhow, Result};
+use std::io::Cursor;
+use std::sync::Arc;
+
+// Resizer built only on pure-Rust crates, so deployments can drop the libvips dependency
+pub struct ImageResizerBackend {
+    in_buffer: Arc<Vec<u8>>,
+    in_type: ImageType,
+}
+
+impl ImageResizerBackend {
+    // All the frames of the source, each one composited onto the full canvas
+    fn frames(&self) -> Result<Vec<Frame>> {
+        let cursor = Cursor::new(&self.in_buffer[..]);
+        Ok(match self.in_type {
+            ImageType::GIF => GifDecoder::new(cursor)?.into_frames().collect_frames()?,
+            ImageType::WEBPAnimated | ImageType::WEBPStill => {
+                let decoder = WebPDecoder::new(cursor)?;
+                if decoder.has_animation() {
+                    decoder.into_frames().collect_frames()?
+                } else {
+                    vec![Frame::new(self.still()?)]
+                }
+            }
+            ImageType::APNG => PngDecoder::new(cursor)?
+                .apng()
+                .into_frames()
+                .collect_frames()?,
+            ImageType::PNG | ImageType::JPEG => vec![Frame::new(self.still()?)],
+            _ => {
+                return Err(anyhow!(
+                    "the image resizer backend can't read this image type"
+                ))
+            }
+        })
+    }
+
+    // A still image the right way up and in sRGB
+    fn still(&self) -> Result<RgbaImage> {
+        let cursor = Cursor::new(&self.in_buffer[..]);
+        // the WebP decoder can't give us its ICC profile
+        let (image, icc_profile) = match self.in_type {
+            ImageType::JPEG => {
+                let mut decoder = JpegDecoder::new(cursor)?;
+                let icc_profile = decoder.icc_profile();
+                (DynamicImage::from_decoder(decoder)?, icc_profile)
+            }
+            ImageType::PNG => {
+                let mut decoder = PngDecoder::new(cursor)?;
+                let icc_profile = decoder.icc_profile();
+                (DynamicImage::from_decoder(decoder)?, icc_profile)
+            }
+            _ => (::image::load_from_memory(&self.in_buffer)?, None),
+        };
+
+        let mut image = image.into_rgba8();
+        if let Some(icc_profile) = icc_profile {
+            to_srgb(&mut image, &icc_profile);
+        }
+        Ok(orient(image, self.orientation()))
+    }
+
+    // only stills get rotated, nobody puts EXIF in an animation
+    fn orientation(&self) -> u16 {
+        match self.in_type {
+            ImageType::JPEG | ImageType::PNG | ImageType::WEBPStill => {
+                exif_orientation(&self.in_buffer).unwrap_or(1)
+            }
+            _ => 1,
+        }
+    }
+
+    // first frame squashed to exactly width x height
+    fn thumbnail(&self, width: u32, height: u32) -> Result<RgbaImage> {
+        let first_frame = self
+            .frames()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("image has no frames"))?;
+        Ok(imageops::resize(
+            first_frame.buffer(),
+            width,
+            height,
+            FilterType::Triangle,
+        ))
+    }
+
+    fn animated(&self) -> bool {
+        match self.in_type {
+            ImageType::GIF | ImageType::WEBPAnimated | ImageType::APNG => true,
+            _ => false,
+        }
+    }
+}
+
+fn filter(kernel: ScaleKernel) -> FilterType {
+    match kernel {
+        ScaleKernel::Nearest => FilterType::Nearest,
+        ScaleKernel::Linear => FilterType::Triangle,
+        ScaleKernel::Cubic => FilterType::CatmullRom,
+        ScaleKernel::Lanczos => FilterType::Lanczos3,
+    }
+}
+
+// Converts from the colour space of an embedded ICC profile. Profiles qcms can't read are left alone.
+fn to_srgb(image: &mut RgbaImage, icc_profile: &[u8]) {
+    let input = match qcms::Profile::new_from_slice(icc_profile, false) {
+        Some(input) => input,
+        None => return,
+    };
+    let output = qcms::Profile::new_sRGB();
+    if let Some(transform) = qcms::Transform::new(
+        &input,
+        &output,
+        qcms::DataType::RGBA8,
+        qcms::Intent::Perceptual,
+    ) {
+        transform.apply(image);
+    }
+}
+
+// Turns the pixels the way the EXIF orientation says they should be shown
+fn orient(image: RgbaImage, orientation: u16) -> RgbaImage {
+    match orientation {
+        2 => imageops::flip_horizontal(&image),
+        3 => imageops::rotate180(&image),
+        4 => imageops::flip_vertical(&image),
+        5 => imageops::flip_horizontal(&imageops::rotate90(&image)),
+        6 => imageops::rotate90(&image),
+        7 => imageops::flip_horizontal(&imageops::rotate270(&image)),
+        8 => imageops::rotate270(&image),
+        _ => image,
+    }
+}
+
+fn delay_ms(frame: &Frame) -> u32 {
+    let (numer, denom) = frame.delay().numer_denom_ms();
+    numer / denom.max(1)
+}
+
+// Speed, direction and frame range of an animation
+fn arrange_frames(frames: Vec<Frame>, animation: &Animation) -> Vec<Frame> {
+    animation
+        .arrange(
+            frames
+                .into_iter()
+                .map(|frame| {
+                    let delay_ms = delay_ms(&frame);
+                    (frame, delay_ms)
+                })
+                .collect(),
+        )
+        .into_iter()
+        .map(|(frame, delay_ms)| replace_delay(frame, delay_ms))
+        .collect()
+}
+
+// Keeps every `frame_step`th frame, each one lasting as long as the frames it replaces so the speed doesn't change
+fn drop_frames(frames: Vec<Frame>, frame_step: u32) -> Vec<Frame> {
+    if frame_step <= 1 {
+        return frames;
+    }
+
+    let mut kept: Vec<Frame> = vec![];
+    let mut kept_delay_ms = 0;
+    for (i, frame) in frames.into_iter().enumerate() {
+        let delay_ms = delay_ms(&frame);
+        if i as u32 % frame_step == 0 {
+            if let Some(last) = kept.pop() {
+                kept.push(replace_delay(last, kept_delay_ms));
+            }
+            kept_delay_ms = 0;
+            kept.push(frame);
+        }
+        kept_delay_ms += delay_ms;
+    }
+    if let Some(last) = kept.pop() {
+        kept.push(replace_delay(last, kept_delay_ms));
+    }
+    kept
+}
+
+fn replace_delay(frame: Frame, delay_ms: u32) -> Frame {
+    let (left, top) = (frame.left(), frame.top());
+    Frame::from_parts(
+        frame.into_buffer(),
+        left,
+        top,
+        Delay::from_numer_denom_ms(delay_ms, 1),
+    )
+}
+
+// Snaps every pixel to a palette of at most `colours` colours
+fn quantize(buffer: &mut RgbaImage, colours: u32) {
+    let quantizer = color_quant::NeuQuant::new(10, colours as usize, buffer.as_raw());
+    for pixel in buffer.pixels_mut() {
+        let index = quantizer.index_of(&pixel.0);
+        if let Some(colour) = quantizer.lookup(index) {
+            pixel.0 = colour;
+        }
+    }
+}
+
+impl ResizerBackend for ImageResizerBackend {
+    fn new(in_buffer: Arc<Vec<u8>>, in_type: ImageType) -> Self {
+        Self { in_buffer, in_type }
+    }
+    fn resize(&self, options: &ResizeOptions) -> Result<(u32, u32, Vec<u8>, ImageType)> {
+        // TODO use the height, DisplayOptions::validate keeps it from getting here for now
+        if options.height.is_some() {
+            return Err(anyhow!("resizing by height isn't supported yet"));
+        }
+        let (in_width, in_height) = self.dimensions()?;
+        let out_width = options.out_width(in_width);
+        // keep the aspect ratio the same way vips' thumbnail does
+        let out_height =
+            ((in_height as f64 * out_width as f64 / in_width as f64).round() as u32).max(1);
+
+        let frames = if self.animated() && !options.animation.is_noop() {
+            arrange_frames(self.frames()?, &options.animation)
+        } else {
+            self.frames()?
+        };
+        let resized_frames: Vec<Frame> = drop_frames(frames, options.encode.frame_step)
+            .into_iter()
+            .map(|frame| {
+                let delay = frame.delay();
+                let mut resized = imageops::resize(
+                    frame.buffer(),
+                    out_width,
+                    out_height,
+                    filter(options.kernel),
+                );
+                if options.encode.colours < 256 {
+                    quantize(&mut resized, options.encode.colours);
+                }
+                Frame::from_parts(resized, 0, 0, delay)
+            })
+            .collect();
+
+        let mut out_buffer = vec![];
+        let out_type = if self.animated() {
+            // with nothing asked for, it loops the way the source does
+            let repeat = if options.animation.is_noop() {
+                FrameInfo::probe(&self.in_buffer)
+                    .unwrap_or_else(FrameInfo::still)
+                    .gif_repeat()
+            } else {
+                options.animation.gif_repeat()
+            };
+            {
+                let mut encoder = GifEncoder::new(&mut out_buffer);
+                // Finite(0) leaves the looping out, so it plays once
+                encoder.set_repeat(match repeat {
+                    Some(0) => Repeat::Infinite,
+                    Some(repeat) => Repeat::Finite(repeat),
+                    None => Repeat::Finite(0),
+                })?;
+                encoder.encode_frames(resized_frames)?;
+            }
+            ImageType::GIF
+        } else {
+            let frame = resized_frames
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("image has no frames"))?;
+            frame
+                .into_buffer()
+                .write_to(&mut Cursor::new(&mut out_buffer), ImageOutputFormat::Png)?;
+            ImageType::PNG
+        };
+
+        Ok((out_width, out_height, out_buffer, out_type))
+    }
+
+    fn can_drop_frames(&self) -> bool {
+        self.animated()
+    }
+
+    fn dimensions(&self) -> Result<(u32, u32)> {
+        let (width, height) = ImageReader::new(Cursor::new(&self.in_buffer[..]))
+            .with_guessed_format()?
+            .into_dimensions()?;
+        // 5-8 are turned a quarter
+        Ok(if self.orientation() >= 5 {
+            (height, width)
+        } else {
+            (width, height)
+        })
+    }
+
+    fn rgba_frames(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .frames()?
+            .into_iter()
+            .map(|frame| frame.into_buffer().into_raw())
+            .collect())
+    }
+
+    fn luma_thumbnail(&self, width: u32, height: u32) -> Result<Vec<u8>> {
+        let thumbnail = self.thumbnail(width, height)?;
+
+        Ok(thumbnail
+            .pixels()
+            .map(|pixel| {
+                let alpha = pixel[3] as f32 / 255.0;
+                let luma =
+                    0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32;
+                (luma * alpha + 255.0 * (1.0 - alpha)).round() as u8
+            })
+            .collect())
+    }
+
+    fn rgba_thumbnail(&self, width: u32, height: u32) -> Result<Vec<u8>> {
+        Ok(self.thumbnail(width, height)?.into_raw())
+    }
+
+    fn encode_jpeg(&self, width: u32, height: u32, rgb: &[u8], quality: u8) -> Result<Vec<u8>> {
+        let mut out_buffer = vec![];
+        JpegEncoder::new_with_quality(&mut out_buffer, quality).encode(
+            rgb,
+            width,
+            height,
+            ColorType::Rgb8,
+        )?;
+        Ok(out_buffer)
+    }
+}
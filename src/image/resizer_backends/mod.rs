@@ -1,27 +1,132 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
-use crate::image::ImageType;
+use crate::config::{EmotesConfigResizerBackend, EMOTES_CONFIG};
+use crate::image::{Animation, ImageType, ScaleKernel};
 use std::sync::Arc;
 
+#[derive(Clone, Copy, Debug)]
+pub struct ResizeOptions {
+    pub width: u32,
+    pub height: Option<u32>,
+    pub multiplier: Option<u32>,
+    pub encode: EncodeSettings,
+    pub animation: Animation,
+    pub kernel: ScaleKernel,
+}
+
+impl ResizeOptions {
+    // What an `in_width` wide source really gets scaled to, after the encode scale and the kernel's snapping
+    pub fn out_width(&self, in_width: u32) -> u32 {
+        let out_width = ((self.width as f64 * self.encode.scale).round() as u32).max(1);
+        self.kernel.snap_width(in_width, out_width)
+    }
+}
+
+// How hard the encoder is allowed to squeeze the output. The defaults are lossless (apart from GIF's palette),
+// the size budget search turns these down until the output is small enough.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EncodeSettings {
+    // 1-100, for palette PNGs and lossy formats
+    pub quality: u8,
+    // palette size, 2-256
+    pub colours: u32,
+    // keep every nth frame of an animation
+    pub frame_step: u32,
+    // applied on top of the requested size
+    pub scale: f64,
+}
+
+impl Default for EncodeSettings {
+    fn default() -> Self {
+        Self {
+            quality: 100,
+            colours: 256,
+            frame_step: 1,
+            scale: 1.0,
+        }
+    }
+}
+
+impl EncodeSettings {
+    const MIN_COLOURS: u32 = 32;
+    const MAX_FRAME_STEP: u32 = 4;
+    const MIN_SCALE: f64 = 0.25;
+
+    // The next thing to try when the output was `overshoot` times bigger than it's allowed to be.
+    // Palette and quality go first since they're the least noticeable, dimensions go last.
+    pub fn turned_down(&self, overshoot: f64, can_drop_frames: bool) -> Option<Self> {
+        let mut next = *self;
+        if self.colours > Self::MIN_COLOURS {
+            next.colours = (self.colours / 2).max(Self::MIN_COLOURS);
+            next.quality = self.quality.saturating_sub(15).max(40);
+        } else if can_drop_frames && self.frame_step < Self::MAX_FRAME_STEP {
+            next.frame_step += 1;
+        } else if self.scale > Self::MIN_SCALE {
+            // the byte size goes roughly with the area, so shrink both sides by the square root
+            next.scale = (self.scale * (1.0 / overshoot).sqrt().min(0.9)).max(Self::MIN_SCALE);
+        } else {
+            return None;
+        }
+        Some(next)
+    }
+
+    // palette bit depth for the number of colours
+    pub fn bitdepth(&self) -> u32 {
+        let mut bitdepth = 1;
+        while (1 << bitdepth) < self.colours && bitdepth < 8 {
+            bitdepth += 1;
+        }
+        bitdepth
+    }
+}
+
 pub trait ResizerBackend {
     fn new(in_buffer: Arc<Vec<u8>>, in_type: ImageType) -> Self
     where
         Self: Sized;
 
-    // width, height, data array
-    fn resize(
-        &self,
-        out_width: u32,
-        out_height: Option<u32>,
-        out_multiplier: Option<u32>,
-    ) -> Result<(u32, u32, Vec<u8>)>;
+    // width, height, data array, type of the data array
+    fn resize(&self, options: &ResizeOptions) -> Result<(u32, u32, Vec<u8>, ImageType)>;
 
     fn dimensions(&self) -> Result<(u32, u32)>;
-    fn no_frames(in_buffer: Arc<Vec<u8>>) -> Result<u32>
-    where
-        Self: Sized; // separate since circular dependency on no_frames to create new ResizerBackend otherwise
+    // every frame at full size as RGBA, one buffer per frame
+    fn rgba_frames(&self) -> Result<Vec<Vec<u8>>>;
+    // first frame squashed to exactly width x height, flattened onto white, one grayscale byte per pixel
+    fn luma_thumbnail(&self, width: u32, height: u32) -> Result<Vec<u8>>;
+    // first frame squashed to exactly width x height as RGBA
+    fn rgba_thumbnail(&self, width: u32, height: u32) -> Result<Vec<u8>>;
+    // whether EncodeSettings::frame_step does anything
+    fn can_drop_frames(&self) -> bool {
+        false
+    }
+    // `rgb` is 3 bytes per pixel, there's no alpha in a JPEG
+    fn encode_jpeg(&self, width: u32, height: u32, rgb: &[u8], quality: u8) -> Result<Vec<u8>>;
 }
 
+#[cfg(feature = "image")]
+mod image_backend;
+#[cfg(feature = "vips")]
 mod vips_backend;
 
+#[cfg(test)]
+mod tests;
+
+#[cfg(feature = "image")]
+pub use image_backend::ImageResizerBackend;
+#[cfg(feature = "vips")]
 pub use vips_backend::VipsResizerBackend;
+
+// The backend is picked in the config file, but it has to be compiled in with its cargo feature as well
+pub fn configured_resizer(
+    in_buffer: Arc<Vec<u8>>,
+    in_type: ImageType,
+) -> Result<Box<dyn ResizerBackend + Send>> {
+    Ok(match EMOTES_CONFIG.resizer_backend {
+        #[cfg(feature = "vips")]
+        EmotesConfigResizerBackend::Vips => Box::new(VipsResizerBackend::new(in_buffer, in_type)),
+        #[cfg(feature = "image")]
+        EmotesConfigResizerBackend::Image => Box::new(ImageResizerBackend::new(in_buffer, in_type)),
+        #[allow(unreachable_patterns)]
+        backend => return Err(anyhow!("{:?} resizer backend is not compiled in", backend)),
+    })
+}
@@ -0,0 +1,279 @@
+// The same checks against every resizer backend that's compiled in, they should all come out the same way
+use std::sync::Arc;
+
+use crate::image::{
+    frames::{encode_gif, set_gif_repeat, CanvasFrame},
+    Animation, EncodeSettings, FrameInfo, ImageHeader, ImageType, ResizeOptions, ResizerBackend,
+    ScaleKernel,
+};
+use crate::test_support::png;
+
+fn backends(buffer: Vec<u8>, in_type: ImageType) -> Vec<(&'static str, Box<dyn ResizerBackend>)> {
+    let buffer = Arc::new(buffer);
+    #[allow(unused_mut)]
+    let mut backends: Vec<(&'static str, Box<dyn ResizerBackend>)> = vec![];
+    #[cfg(feature = "vips")]
+    backends.push((
+        "vips",
+        Box::new(super::VipsResizerBackend::new(Arc::clone(&buffer), in_type)),
+    ));
+    #[cfg(feature = "image")]
+    backends.push((
+        "image",
+        Box::new(super::ImageResizerBackend::new(
+            Arc::clone(&buffer),
+            in_type,
+        )),
+    ));
+    backends
+}
+
+// one flat colour per frame, `delays` in 10ms units and `repeat` like frames::gif_repeat
+fn gif(width: u16, height: u16, delays: &[u16], repeat: Option<u16>) -> Vec<u8> {
+    let frames: Vec<CanvasFrame> = delays
+        .iter()
+        .enumerate()
+        .map(|(i, &delay)| CanvasFrame {
+            pixels: vec![
+                [(i * 80) as u8, 0, 255 - (i * 80) as u8, 255];
+                width as usize * height as usize
+            ],
+            delay,
+        })
+        .collect();
+    set_gif_repeat(encode_gif(width, height, &frames).unwrap(), repeat)
+}
+
+fn options(width: u32) -> ResizeOptions {
+    ResizeOptions {
+        width,
+        height: None,
+        multiplier: None,
+        encode: EncodeSettings::default(),
+        animation: Animation::default(),
+        kernel: ScaleKernel::default(),
+    }
+}
+
+#[test]
+fn dimensions_are_the_sources() {
+    for (name, backend) in backends(png(120, 60), ImageType::PNG) {
+        assert_eq!(backend.dimensions().unwrap(), (120, 60), "{}", name);
+    }
+    for (name, backend) in backends(gif(40, 20, &[10, 10], Some(0)), ImageType::GIF) {
+        assert_eq!(backend.dimensions().unwrap(), (40, 20), "{}", name);
+    }
+}
+
+#[test]
+fn stills_keep_their_aspect_ratio() {
+    for (name, backend) in backends(png(120, 60), ImageType::PNG) {
+        let (width, height, out_buffer, out_type) = backend.resize(&options(30)).unwrap();
+        assert_eq!((width, height), (30, 15), "{}", name);
+        assert_eq!(out_type, ImageType::PNG, "{}", name);
+
+        let header = ImageHeader::probe(&out_buffer).unwrap();
+        assert_eq!((header.width, header.height), (30, 15), "{}", name);
+        assert_eq!(header.image_type, ImageType::PNG, "{}", name);
+    }
+}
+
+#[test]
+fn animations_keep_their_aspect_ratio() {
+    for kernel in [ScaleKernel::Lanczos, ScaleKernel::Nearest].iter() {
+        for (name, backend) in backends(gif(40, 20, &[10, 20, 30], Some(0)), ImageType::GIF) {
+            let options = ResizeOptions {
+                kernel: *kernel,
+                ..options(20)
+            };
+            let (width, height, out_buffer, out_type) = backend.resize(&options).unwrap();
+            assert_eq!((width, height), (20, 10), "{} {:?}", name, kernel);
+            assert_eq!(out_type, ImageType::GIF, "{} {:?}", name, kernel);
+
+            let header = ImageHeader::probe(&out_buffer).unwrap();
+            assert_eq!(
+                (header.width, header.height),
+                (20, 10),
+                "{} {:?}",
+                name,
+                kernel
+            );
+            assert_eq!(header.frames, 3, "{} {:?}", name, kernel);
+        }
+    }
+}
+
+#[test]
+fn animations_keep_their_frame_delays() {
+    for kernel in [ScaleKernel::Lanczos, ScaleKernel::Nearest].iter() {
+        for (name, backend) in backends(gif(40, 20, &[10, 20, 30], Some(0)), ImageType::GIF) {
+            let options = ResizeOptions {
+                kernel: *kernel,
+                ..options(20)
+            };
+            let (_, _, out_buffer, _) = backend.resize(&options).unwrap();
+            let frame_info = FrameInfo::probe(&out_buffer).unwrap();
+            assert_eq!(
+                frame_info.delays,
+                vec![100, 200, 300],
+                "{} {:?}",
+                name,
+                kernel
+            );
+        }
+    }
+}
+
+#[test]
+fn animations_keep_their_loop_count() {
+    // forever, once and three times
+    for repeat in [Some(0), None, Some(2)].iter() {
+        let source = gif(40, 20, &[10, 10], *repeat);
+        let source_loops = FrameInfo::probe(&source).unwrap().loops;
+        for kernel in [ScaleKernel::Lanczos, ScaleKernel::Nearest].iter() {
+            for (name, backend) in backends(source.clone(), ImageType::GIF) {
+                let options = ResizeOptions {
+                    kernel: *kernel,
+                    ..options(20)
+                };
+                let (_, _, out_buffer, _) = backend.resize(&options).unwrap();
+                assert_eq!(
+                    FrameInfo::probe(&out_buffer).unwrap().loops,
+                    source_loops,
+                    "{} {:?} {:?}",
+                    name,
+                    kernel,
+                    repeat
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn loop_modifier_sets_the_loop_count() {
+    for (name, backend) in backends(gif(40, 20, &[10, 10], Some(0)), ImageType::GIF) {
+        let options = ResizeOptions {
+            animation: Animation::parse("loop=3").unwrap(),
+            ..options(20)
+        };
+        let (_, _, out_buffer, _) = backend.resize(&options).unwrap();
+        assert_eq!(
+            FrameInfo::probe(&out_buffer).unwrap().loops,
+            Some(3),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn dropped_frames_keep_the_duration() {
+    for (name, backend) in backends(gif(40, 20, &[10, 20, 30, 40, 50], Some(0)), ImageType::GIF) {
+        assert!(backend.can_drop_frames(), "{}", name);
+        let options = ResizeOptions {
+            encode: EncodeSettings {
+                frame_step: 2,
+                ..EncodeSettings::default()
+            },
+            ..options(20)
+        };
+        let (_, _, out_buffer, _) = backend.resize(&options).unwrap();
+        assert_eq!(
+            FrameInfo::probe(&out_buffer).unwrap().delays,
+            vec![300, 700, 500],
+            "{}",
+            name
+        );
+    }
+    for (name, backend) in backends(png(40, 20), ImageType::PNG) {
+        assert!(!backend.can_drop_frames(), "{}", name);
+    }
+}
+
+// every step the size budget search takes from the defaults, until there's nothing left to turn down
+fn turn_downs(can_drop_frames: bool) -> Vec<EncodeSettings> {
+    let mut steps = vec![];
+    let mut encode = EncodeSettings::default();
+    while let Some(next) = encode.turned_down(4.0, can_drop_frames) {
+        steps.push(next);
+        encode = next;
+    }
+    steps
+}
+
+#[test]
+fn palette_and_quality_go_first() {
+    let steps = turn_downs(true);
+    assert_eq!(
+        steps[..3]
+            .iter()
+            .map(|encode| (
+                encode.colours,
+                encode.quality,
+                encode.frame_step,
+                encode.scale
+            ))
+            .collect::<Vec<_>>(),
+        vec![(128, 85, 1, 1.0), (64, 70, 1, 1.0), (32, 55, 1, 1.0)]
+    );
+
+    let mut encode = EncodeSettings {
+        quality: 50,
+        ..EncodeSettings::default()
+    };
+    encode = encode.turned_down(4.0, false).unwrap();
+    assert_eq!(encode.quality, 40);
+    encode = encode.turned_down(4.0, false).unwrap();
+    assert_eq!(encode.quality, 40);
+}
+
+#[test]
+fn frames_go_before_the_size() {
+    let steps = turn_downs(true);
+    assert_eq!(
+        steps[3..6]
+            .iter()
+            .map(|encode| (encode.frame_step, encode.scale))
+            .collect::<Vec<_>>(),
+        vec![(2, 1.0), (3, 1.0), (4, 1.0)]
+    );
+    assert_eq!(steps[6].scale, 0.5);
+
+    // stills skip straight to the size
+    let steps = turn_downs(false);
+    assert_eq!(steps[3].frame_step, 1);
+    assert_eq!(steps[3].scale, 0.5);
+}
+
+#[test]
+fn size_goes_last_and_stops_at_the_floor() {
+    let colours = EncodeSettings {
+        colours: 32,
+        ..EncodeSettings::default()
+    };
+    // the area goes down by the overshoot, but always by a bit
+    assert_eq!(colours.turned_down(16.0, false).unwrap().scale, 0.25);
+    assert_eq!(colours.turned_down(1.01, false).unwrap().scale, 0.9);
+    assert_eq!(colours.turned_down(100.0, false).unwrap().scale, 0.25);
+
+    let steps = turn_downs(true);
+    assert_eq!(steps.last().unwrap().scale, 0.25);
+    assert_eq!(steps.last().unwrap().turned_down(4.0, true), None);
+}
+
+#[test]
+fn palette_bitdepth() {
+    let bitdepth = |colours| {
+        EncodeSettings {
+            colours,
+            ..EncodeSettings::default()
+        }
+        .bitdepth()
+    };
+    assert_eq!(bitdepth(2), 1);
+    assert_eq!(bitdepth(3), 2);
+    assert_eq!(bitdepth(32), 5);
+    assert_eq!(bitdepth(33), 6);
+    assert_eq!(bitdepth(256), 8);
+}
@@ -1,55 +1,221 @@
-use crate::image::{ImageType, ResizerBackend};
-use anyhow::Result;
-use libvips::{ops, VipsImage};
+use crate::image::{
+    frames::{encode_gif, set_gif_repeat, CanvasFrame},
+    resizer_backends::ResizeOptions,
+    FrameInfo, ImageType, ResizerBackend, ScaleKernel,
+};
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use libvips::{ops, VipsApp, VipsImage};
 use log::info;
 use std::sync::Arc;
 
+lazy_static! {
+    static ref VIPS: VipsApp =
+        VipsApp::new("Emotes Vips Resizer", false).expect("failed to run vips");
+}
+
 pub struct VipsResizerBackend {
     in_buffer: Arc<Vec<u8>>,
     in_type: ImageType,
 }
 
 impl VipsResizerBackend {
+    // How many threads libvips splits each image over, it picks for itself until this is called
+    pub fn set_threads(threads: usize) {
+        VIPS.concurrency_set(threads.max(1) as i32);
+    }
+
     fn vips_image(&self) -> Result<VipsImage> {
-        Ok(match self.in_type {
-            ImageType::WEBPAnimated | ImageType::WEBPStill => ops::webpload_buffer(&self.in_buffer),
-            ImageType::GIF => VipsImage::new_from_buffer(&self.in_buffer, "[n=-1]"),
+        let vips_image = match self.in_type {
+            ImageType::WEBPStill => ops::webpload_buffer(&self.in_buffer),
+            // load all frames
+            ImageType::GIF | ImageType::WEBPAnimated => {
+                VipsImage::new_from_buffer(&self.in_buffer, "[n=-1]")
+            }
             ImageType::JPEG | ImageType::PNG => VipsImage::new_from_buffer(&self.in_buffer, ""),
             ImageType::SVG => ops::svgload_buffer(&self.in_buffer),
-            _ => unimplemented!(), // libvips doesn't support apngs, nor will lottie happen here
-        }?)
+            // libvips doesn't support apngs, nor will lottie happen here
+            _ => return Err(anyhow!("libvips can't read {:?} images", self.in_type)),
+        }?;
+
+        Ok(match self.in_type {
+            // phone pictures come sideways with an EXIF orientation, and often in a wider colour space than sRGB
+            ImageType::JPEG | ImageType::PNG | ImageType::WEBPStill => {
+                let vips_image = ops::autorot(&vips_image)?;
+                // fails when there's no embedded profile, then it's already sRGB as far as we know
+                match ops::icc_transform_with_opts(
+                    &vips_image,
+                    "srgb",
+                    &ops::IccTransformOptions {
+                        embedded: true,
+                        ..ops::IccTransformOptions::default()
+                    },
+                ) {
+                    Ok(srgb_image) => srgb_image,
+                    Err(_) => vips_image,
+                }
+            }
+            _ => vips_image,
+        })
+    }
+
+    // first frame squashed to exactly width x height
+    fn thumbnail(&self, width: u32, height: u32) -> Result<VipsImage> {
+        Ok(ops::thumbnail_buffer_with_opts(
+            &self.in_buffer,
+            width as i32,
+            &ops::ThumbnailBufferOptions {
+                height: height as i32,
+                size: ops::Size::Force,
+                ..ops::ThumbnailBufferOptions::default()
+            },
+        )?)
     }
+
+    // Skips gifsave, so the palette is left to the optimizer. Keeps every `frame_step`th frame, each one lasting as
+    // long as the frames it replaces so the speed doesn't change.
+    fn resize_frames(
+        &self,
+        vips_image: &VipsImage,
+        out_width: u32,
+        options: &ResizeOptions,
+    ) -> Result<(u32, u32, Vec<u8>, ImageType)> {
+        let (width, page_height) = (vips_image.get_width(), vips_image.get_page_height());
+        let frame_info = FrameInfo::probe(&self.in_buffer).unwrap_or_else(FrameInfo::still);
+
+        let (mut out_width, mut out_height) = (out_width, 0);
+        let mut frames = vec![];
+        let (n_pages, frame_step) = (vips_image.get_n_pages(), options.encode.frame_step.max(1));
+        for page in (0..n_pages).step_by(frame_step as usize) {
+            let delay_ms: u32 = (page..(page + frame_step as i32).min(n_pages))
+                .map(|page| frame_info.delays.get(page as usize).copied().unwrap_or(0))
+                .sum();
+            let frame = ops::extract_area(vips_image, 0, page * page_height, width, page_height)?;
+            let frame = to_rgba(&scale(&frame, out_width, options.kernel)?)?;
+            out_width = frame.get_width() as u32;
+            out_height = frame.get_height() as u32;
+            frames.push(CanvasFrame {
+                pixels: frame
+                    .image_write_to_memory()
+                    .chunks_exact(4)
+                    .map(|p| [p[0], p[1], p[2], p[3]])
+                    .collect(),
+                delay: ((delay_ms + 5) / 10) as u16,
+            });
+        }
+
+        let out_buffer = encode_gif(out_width as u16, out_height as u16, &frames)?;
+        let out_buffer = if options.animation.is_noop() {
+            // encode_gif always loops forever, the source might not
+            set_gif_repeat(out_buffer, frame_info.gif_repeat())
+        } else {
+            options.animation.apply_to_gif(out_buffer)?
+        };
+        Ok((out_width, out_height, out_buffer, ImageType::GIF))
+    }
+}
+
+// Plain resize with the kernel asked for, only for a single frame
+fn scale(vips_image: &VipsImage, out_width: u32, kernel: ScaleKernel) -> Result<VipsImage> {
+    let scale = out_width as f64 / vips_image.get_width() as f64;
+    Ok(ops::resize_with_opts(
+        vips_image,
+        scale,
+        &ops::ResizeOptions {
+            kernel: match kernel {
+                ScaleKernel::Nearest => ops::Kernel::Nearest,
+                ScaleKernel::Linear => ops::Kernel::Linear,
+                ScaleKernel::Cubic => ops::Kernel::Cubic,
+                ScaleKernel::Lanczos => ops::Kernel::Lanczos3,
+            },
+            vscale: scale,
+            ..ops::ResizeOptions::default()
+        },
+    )?)
+}
+
+// 8 bit sRGB with an alpha channel, whatever it was before
+fn to_rgba(vips_image: &VipsImage) -> Result<VipsImage> {
+    let vips_image = ops::colourspace(vips_image, ops::Interpretation::Srgb)?;
+    let vips_image = if vips_image.image_hasalpha() {
+        vips_image
+    } else {
+        ops::bandjoin_const(&vips_image, &mut [255.0])?
+    };
+    Ok(ops::cast(&vips_image, ops::BandFormat::Uchar)?)
 }
 
 impl ResizerBackend for VipsResizerBackend {
     fn new(in_buffer: Arc<Vec<u8>>, in_type: ImageType) -> Self {
+        lazy_static::initialize(&VIPS);
         Self { in_buffer, in_type }
     }
-    fn resize(
-        &self,
-        out_width: u32,
-        out_height: Option<u32>,
-        out_multiplier: Option<u32>,
-    ) -> Result<(u32, u32, Vec<u8>)> {
+    fn resize(&self, options: &ResizeOptions) -> Result<(u32, u32, Vec<u8>, ImageType)> {
         let vips_image = self.vips_image()?;
-        // TODO use the height
-        if let Some(_) = out_height {
-            unimplemented!()
+        // TODO use the height, DisplayOptions::validate keeps it from getting here for now
+        if options.height.is_some() {
+            return Err(anyhow!("resizing by height isn't supported yet"));
+        }
+        let out_width = options.out_width(vips_image.get_width() as u32);
+        let animated = match self.in_type {
+            ImageType::WEBPAnimated | ImageType::GIF => true,
+            _ => false,
+        };
+
+        // resize treats an animation as one tall image, so the frames are scaled one by one and put back together.
+        // Same when frames are dropped, that's not something thumbnail does.
+        if animated && (options.kernel != ScaleKernel::Lanczos || options.encode.frame_step > 1) {
+            return self.resize_frames(&vips_image, out_width, options);
         }
-        let resized_vips_image = ops::thumbnail_image(&vips_image, out_width as i32)?;
+        let resized_vips_image = match options.kernel {
+            // what thumbnail uses anyway, and it knows about animations
+            ScaleKernel::Lanczos => ops::thumbnail_image(&vips_image, out_width as i32)?,
+            kernel => scale(&vips_image, out_width, kernel)?,
+        };
+
+        let (out_buffer, out_type) = match self.in_type {
+            ImageType::WEBPAnimated | ImageType::GIF => {
+                let out_buffer = ops::gifsave_buffer_with_opts(
+                    &resized_vips_image,
+                    &ops::GifsaveBufferOptions {
+                        bitdepth: options.encode.bitdepth() as i32,
+                        strip: true,
+                        ..ops::GifsaveBufferOptions::default()
+                    },
+                )?;
+                // same goes for playback, it's done on the GIF vips made
+                let out_buffer = if options.animation.is_noop() {
+                    // and the loop count is the source's, whatever gifsave thought it was
+                    let frame_info =
+                        FrameInfo::probe(&self.in_buffer).unwrap_or_else(FrameInfo::still);
+                    set_gif_repeat(out_buffer, frame_info.gif_repeat())
+                } else {
+                    options.animation.apply_to_gif(out_buffer)?
+                };
+                (out_buffer, ImageType::GIF)
+            }
+            ImageType::PNG | ImageType::JPEG | ImageType::WEBPStill | ImageType::SVG => (
+                ops::pngsave_buffer_with_opts(
+                    &resized_vips_image,
+                    &ops::PngsaveBufferOptions {
+                        // only go to a palette when we have to, it's lossy
+                        palette: options.encode.colours < 256,
+                        q: options.encode.quality as i32,
+                        bitdepth: options.encode.bitdepth() as i32,
+                        strip: true,
+                        ..ops::PngsaveBufferOptions::default()
+                    },
+                )?,
+                ImageType::PNG,
+            ),
+            _ => unimplemented!(),
+        };
 
         Ok((
             resized_vips_image.get_width() as u32,
             resized_vips_image.get_page_height() as u32,
-            match self.in_type {
-                ImageType::WEBPAnimated | ImageType::GIF => {
-                    ops::gifsave_buffer(&resized_vips_image)?
-                }
-                ImageType::PNG | ImageType::JPEG | ImageType::SVG => {
-                    ops::pngsave_buffer(&resized_vips_image)?
-                }
-                _ => unimplemented!(),
-            },
+            out_buffer,
+            out_type,
         ))
     }
 
@@ -62,13 +228,60 @@ impl ResizerBackend for VipsResizerBackend {
         ))
     }
 
-    fn no_frames(in_buffer: Arc<Vec<u8>>) -> Result<u32> {
-        // the more I think about this, the more this is a hack
-        let frame_counter = VipsImage::new_from_buffer(&in_buffer, "[n=-1]"); // load all frames
+    fn rgba_frames(&self) -> Result<Vec<Vec<u8>>> {
+        let vips_image = to_rgba(&self.vips_image()?)?;
 
-        if let Err(_) = frame_counter {
-            return Ok(1);
-        }
-        Ok(frame_counter.unwrap().get_n_pages() as u32)
+        // the frames are stacked on top of each other in one tall image
+        let frame_size = (vips_image.get_width() * vips_image.get_page_height() * 4) as usize;
+        Ok(vips_image
+            .image_write_to_memory()
+            .chunks_exact(frame_size)
+            .map(|frame| frame.to_vec())
+            .collect())
+    }
+
+    fn luma_thumbnail(&self, width: u32, height: u32) -> Result<Vec<u8>> {
+        let thumbnail = self.thumbnail(width, height)?;
+        let flattened = if thumbnail.image_hasalpha() {
+            ops::flatten_with_opts(
+                &thumbnail,
+                &ops::FlattenOptions {
+                    background: vec![255.0],
+                    ..ops::FlattenOptions::default()
+                },
+            )?
+        } else {
+            thumbnail
+        };
+        let grayscale = ops::colourspace(&flattened, ops::Interpretation::BW)?;
+        let grayscale = ops::cast(&ops::extract_band(&grayscale, 0)?, ops::BandFormat::Uchar)?;
+
+        Ok(grayscale.image_write_to_memory())
+    }
+
+    fn rgba_thumbnail(&self, width: u32, height: u32) -> Result<Vec<u8>> {
+        Ok(to_rgba(&self.thumbnail(width, height)?)?.image_write_to_memory())
+    }
+
+    fn can_drop_frames(&self) -> bool {
+        matches!(self.in_type, ImageType::WEBPAnimated | ImageType::GIF)
+    }
+
+    fn encode_jpeg(&self, width: u32, height: u32, rgb: &[u8], quality: u8) -> Result<Vec<u8>> {
+        let vips_image = VipsImage::new_from_memory(
+            rgb,
+            width as i32,
+            height as i32,
+            3,
+            ops::BandFormat::Uchar,
+        )?;
+        Ok(ops::jpegsave_buffer_with_opts(
+            &vips_image,
+            &ops::JpegsaveBufferOptions {
+                q: quality as i32,
+                strip: true,
+                ..ops::JpegsaveBufferOptions::default()
+            },
+        )?)
     }
 }
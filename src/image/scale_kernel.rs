@@ -0,0 +1,61 @@
+use anyhow::{anyhow, Result};
+use async_graphql::Enum;
+use serde::{Deserialize, Serialize};
+
+// How the new pixels are worked out when an emote is scaled. Nearest is for pixel art: it keeps the edges hard,
+// and the size is snapped to a whole multiple (or fraction) of the source so every pixel stays the same size.
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Enum)]
+#[sqlx(type_name = "scale_kernel", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ScaleKernel {
+    Nearest,
+    Linear,
+    Cubic,
+    Lanczos,
+}
+
+// what both resizers always used
+impl Default for ScaleKernel {
+    fn default() -> Self {
+        ScaleKernel::Lanczos
+    }
+}
+
+impl ScaleKernel {
+    pub fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "nearest" => ScaleKernel::Nearest,
+            "linear" => ScaleKernel::Linear,
+            "cubic" => ScaleKernel::Cubic,
+            "lanczos" => ScaleKernel::Lanczos,
+            _ => {
+                return Err(anyhow!(
+                    "\"kernel\" should be nearest, linear, cubic or lanczos"
+                ))
+            }
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScaleKernel::Nearest => "nearest",
+            ScaleKernel::Linear => "linear",
+            ScaleKernel::Cubic => "cubic",
+            ScaleKernel::Lanczos => "lanczos",
+        }
+    }
+
+    // The width to really scale `in_width` to, eg. 16 -> 48 instead of 16 -> 50 for pixel art
+    pub fn snap_width(&self, in_width: u32, out_width: u32) -> u32 {
+        if *self != ScaleKernel::Nearest || in_width == 0 {
+            return out_width;
+        }
+        if out_width >= in_width {
+            let factor = (out_width as f64 / in_width as f64).round() as u32;
+            in_width * factor.max(1)
+        } else {
+            let divisor = (in_width as f64 / out_width.max(1) as f64).round() as u32;
+            (in_width / divisor.max(1)).max(1)
+        }
+    }
+}
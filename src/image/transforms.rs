@@ -0,0 +1,357 @@
+use anyhow::{anyhow, Result};
+
+use crate::image::{
+    frames::{decode_gif, decode_png, encode_gif, encode_png, CanvasFrame},
+    ImageType,
+};
+
+// Modifiers chat people put on emotes, eg. "flip=h", "rotate=90", "grayscale" or "hue=120".
+// However the chain was written, it's kept as the single flip + rotation and colour change it adds up to,
+// so "flip=h,flip=h" is the same image as no transforms at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transforms {
+    // mirrored left to right, done before the rotation
+    pub flip: bool,
+    // clockwise, 0, 90, 180 or 270
+    pub rotate: u16,
+    pub grayscale: bool,
+    // degrees around the colour wheel, 0-359
+    pub hue: u16,
+}
+
+impl Transforms {
+    pub fn is_noop(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // "flip=h,rotate=90,hue=120", the same grammar as the modifiers in an emote URL
+    pub fn parse(chain: &str) -> Result<Self> {
+        let mut transforms = Self::default();
+        for modifier in chain.split(',').filter(|modifier| !modifier.is_empty()) {
+            let (key, value) = match modifier.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (modifier, None),
+            };
+            if !transforms.push(key, value)? {
+                return Err(anyhow!("Unknown transform \"{}\"", key));
+            }
+        }
+        Ok(transforms)
+    }
+
+    // Adds one modifier to the end of the chain. false if `key` isn't a transform.
+    pub fn push(&mut self, key: &str, value: Option<&str>) -> Result<bool> {
+        let degrees = |value: Option<&str>| {
+            value
+                .and_then(|value| value.parse::<i32>().ok())
+                .ok_or_else(|| anyhow!("\"{}\" needs a number of degrees", key))
+        };
+
+        match (key, value) {
+            ("flip", Some("h")) | ("mirror", None) => self.flip_horizontal(),
+            ("flip", Some("v")) => {
+                self.flip_horizontal();
+                self.rotate_by(180);
+            }
+            ("flip", _) => return Err(anyhow!("\"flip\" should be flip=h or flip=v")),
+            ("rotate", value) => {
+                let degrees = degrees(value)?;
+                if degrees % 90 != 0 {
+                    return Err(anyhow!(
+                        "Emotes can only be rotated by multiples of 90 degrees"
+                    ));
+                }
+                self.rotate_by(degrees);
+            }
+            ("grayscale", None) | ("greyscale", None) => self.grayscale = true,
+            ("hue", value) => self.hue = (self.hue as i32 + degrees(value)?).rem_euclid(360) as u16,
+            _ => return Ok(false),
+        }
+
+        // there's no hue left to shift once it's gray
+        if self.grayscale {
+            self.hue = 0;
+        }
+        Ok(true)
+    }
+
+    // a flip after a rotation is the same as the flip first and the rotation the other way
+    fn flip_horizontal(&mut self) {
+        self.flip = !self.flip;
+        self.rotate = (360 - self.rotate) % 360;
+    }
+
+    fn rotate_by(&mut self, degrees: i32) {
+        self.rotate = (self.rotate as i32 + degrees).rem_euclid(360) as u16;
+    }
+
+    // the width and height trade places
+    pub fn swaps_axes(&self) -> bool {
+        self.rotate % 180 == 90
+    }
+
+    // The normalized chain, for telling derivatives apart. Empty when there's nothing to do.
+    pub fn key(&self) -> String {
+        let mut modifiers = vec![];
+        if self.flip {
+            modifiers.push("flip=h".to_owned());
+        }
+        if self.rotate != 0 {
+            modifiers.push(format!("rotate={}", self.rotate));
+        }
+        if self.grayscale {
+            modifiers.push("grayscale".to_owned());
+        }
+        if self.hue != 0 {
+            modifiers.push(format!("hue={}", self.hue));
+        }
+        modifiers.join(",")
+    }
+
+    // Runs the chain on what the resizer made. Returns the new width, height and buffer.
+    pub fn apply(&self, buffer: Vec<u8>, image_type: ImageType) -> Result<(u32, u32, Vec<u8>)> {
+        match image_type {
+            ImageType::GIF => {
+                let (width, height, frames) = decode_gif(&buffer)?;
+                let (mut out_width, mut out_height) = (width as u32, height as u32);
+                let frames: Vec<CanvasFrame> = frames
+                    .into_iter()
+                    .map(|frame| {
+                        let (transformed_width, transformed_height, pixels) =
+                            self.transform(width as u32, height as u32, frame.pixels);
+                        out_width = transformed_width;
+                        out_height = transformed_height;
+                        CanvasFrame {
+                            pixels,
+                            delay: frame.delay,
+                        }
+                    })
+                    .collect();
+                Ok((
+                    out_width,
+                    out_height,
+                    encode_gif(out_width as u16, out_height as u16, &frames)?,
+                ))
+            }
+            ImageType::PNG => {
+                let (width, height, pixels) = match decode_png(&buffer)? {
+                    Some(decoded) => decoded,
+                    None => return Err(anyhow!("can't transform an animated PNG")),
+                };
+                let (out_width, out_height, pixels) = self.transform(width, height, pixels);
+                Ok((
+                    out_width,
+                    out_height,
+                    encode_png(out_width, out_height, &pixels)?,
+                ))
+            }
+            _ => Err(anyhow!("can't transform {}", image_type.content_type())),
+        }
+    }
+
+    fn transform(
+        &self,
+        width: u32,
+        height: u32,
+        mut pixels: Vec<[u8; 4]>,
+    ) -> (u32, u32, Vec<[u8; 4]>) {
+        if self.grayscale || self.hue != 0 {
+            let matrix = self.colour_matrix();
+            for pixel in pixels.iter_mut() {
+                let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+                for (c, row) in matrix.iter().enumerate() {
+                    pixel[c] = (row[0] * r + row[1] * g + row[2] * b)
+                        .round()
+                        .clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+
+        if !self.flip && self.rotate == 0 {
+            return (width, height, pixels);
+        }
+
+        let (out_width, out_height) = if self.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        };
+        let mut out = vec![[0u8; 4]; pixels.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let x_flipped = if self.flip { width - 1 - x } else { x };
+                let (out_x, out_y) = match self.rotate {
+                    90 => (height - 1 - y, x_flipped),
+                    180 => (width - 1 - x_flipped, height - 1 - y),
+                    270 => (y, width - 1 - x_flipped),
+                    _ => (x_flipped, y),
+                };
+                out[(out_y * out_width + out_x) as usize] = pixels[(y * width + x) as usize];
+            }
+        }
+        (out_width, out_height, out)
+    }
+
+    // The same matrices as CSS' grayscale() and hue-rotate(), they keep the brightness the same
+    fn colour_matrix(&self) -> [[f32; 3]; 3] {
+        if self.grayscale {
+            let row = [0.2126, 0.7152, 0.0722];
+            return [row, row, row];
+        }
+
+        let (sin, cos) = (self.hue as f32).to_radians().sin_cos();
+        [
+            [
+                0.213 + cos * 0.787 - sin * 0.213,
+                0.715 - cos * 0.715 - sin * 0.715,
+                0.072 - cos * 0.072 + sin * 0.928,
+            ],
+            [
+                0.213 - cos * 0.213 + sin * 0.143,
+                0.715 + cos * 0.285 + sin * 0.140,
+                0.072 - cos * 0.072 - sin * 0.283,
+            ],
+            [
+                0.213 - cos * 0.213 - sin * 0.787,
+                0.715 - cos * 0.715 + sin * 0.715,
+                0.072 + cos * 0.928 + sin * 0.072,
+            ],
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 3x2, each pixel's red is its index:
+    // 0 1 2
+    // 3 4 5
+    fn pixels() -> Vec<[u8; 4]> {
+        (0..6).map(|i| [i, 0, 0, 255]).collect()
+    }
+
+    fn transformed(chain: &str) -> (u32, u32, Vec<u8>) {
+        let (width, height, pixels) = Transforms::parse(chain).unwrap().transform(3, 2, pixels());
+        (width, height, pixels.iter().map(|pixel| pixel[0]).collect())
+    }
+
+    #[test]
+    fn chains_add_up() {
+        assert!(Transforms::parse("flip=h,flip=h").unwrap().is_noop());
+        assert!(Transforms::parse("rotate=90,rotate=-90").unwrap().is_noop());
+        assert!(Transforms::parse("rotate=90,rotate=90,rotate=180")
+            .unwrap()
+            .is_noop());
+        assert!(Transforms::parse("hue=200,hue=160").unwrap().is_noop());
+        assert!(Transforms::parse("").unwrap().is_noop());
+        assert_eq!(
+            Transforms::parse("flip=v").unwrap(),
+            Transforms::parse("flip=h,rotate=180").unwrap()
+        );
+        assert_eq!(
+            Transforms::parse("mirror").unwrap(),
+            Transforms::parse("flip=h").unwrap()
+        );
+    }
+
+    #[test]
+    fn keys_are_canonical() {
+        let key = |chain| Transforms::parse(chain).unwrap().key();
+        assert_eq!(key("flip=h,flip=h"), "");
+        assert_eq!(key("rotate=-90"), "rotate=270");
+        assert_eq!(key("rotate=90,flip=h"), "flip=h,rotate=270");
+        assert_eq!(key("hue=-30"), "hue=330");
+        assert_eq!(key("greyscale,hue=90"), "grayscale");
+        assert_eq!(key("hue=90,grayscale"), "grayscale");
+        assert_eq!(key("hue=10,rotate=450,flip=v"), "flip=h,rotate=90,hue=10");
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        for chain in [
+            "flip=h",
+            "flip=v",
+            "rotate=90,flip=h,rotate=90",
+            "mirror,hue=45,rotate=180",
+            "grayscale,rotate=270",
+        ]
+        .iter()
+        {
+            let transforms = Transforms::parse(chain).unwrap();
+            assert_eq!(
+                Transforms::parse(&transforms.key()).unwrap(),
+                transforms,
+                "{}",
+                chain
+            );
+        }
+    }
+
+    #[test]
+    fn bad_modifiers_are_errors() {
+        for chain in [
+            "rotate=45",
+            "rotate",
+            "rotate=left",
+            "flip=x",
+            "flip",
+            "hue",
+        ]
+        .iter()
+        {
+            assert!(Transforms::parse(chain).is_err(), "{}", chain);
+        }
+        assert!(Transforms::parse("spin").is_err());
+        // the caller decides what to do with keys that aren't transforms
+        assert!(!Transforms::default().push("speed", Some("2")).unwrap());
+    }
+
+    #[test]
+    fn rotates_and_flips_pixels() {
+        assert_eq!(transformed(""), (3, 2, vec![0, 1, 2, 3, 4, 5]));
+        assert_eq!(transformed("rotate=90"), (2, 3, vec![3, 0, 4, 1, 5, 2]));
+        assert_eq!(transformed("rotate=180"), (3, 2, vec![5, 4, 3, 2, 1, 0]));
+        assert_eq!(transformed("rotate=270"), (2, 3, vec![2, 5, 1, 4, 0, 3]));
+        assert_eq!(transformed("flip=h"), (3, 2, vec![2, 1, 0, 5, 4, 3]));
+        assert_eq!(transformed("flip=v"), (3, 2, vec![3, 4, 5, 0, 1, 2]));
+        // the flip comes first
+        assert_eq!(
+            transformed("flip=h,rotate=90"),
+            (2, 3, vec![5, 2, 4, 1, 3, 0])
+        );
+    }
+
+    #[test]
+    fn shifts_colours() {
+        let colour = |chain: &str, pixel: [u8; 4]| {
+            Transforms::parse(chain)
+                .unwrap()
+                .transform(1, 1, vec![pixel])
+                .2[0]
+        };
+        assert_eq!(colour("grayscale", [255, 0, 0, 128]), [54, 54, 54, 128]);
+        assert_eq!(
+            colour("grayscale", [255, 255, 255, 255]),
+            [255, 255, 255, 255]
+        );
+        // a third of the way round takes red most of the way to green, and leaves grays alone
+        let shifted = colour("hue=120", [255, 0, 0, 255]);
+        assert!(
+            shifted[1] > shifted[0] && shifted[1] > shifted[2],
+            "{:?}",
+            shifted
+        );
+        assert_eq!(colour("hue=120", [90, 90, 90, 255]), [90, 90, 90, 255]);
+    }
+
+    #[test]
+    fn applies_to_pngs() {
+        let (width, height, buffer) = Transforms::parse("rotate=90")
+            .unwrap()
+            .apply(crate::test_support::png(30, 10), ImageType::PNG)
+            .unwrap();
+        assert_eq!((width, height), (10, 30));
+        assert_eq!(decode_png(&buffer).unwrap().unwrap().0, 10);
+    }
+}
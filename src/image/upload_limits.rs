@@ -0,0 +1,279 @@
+use async_graphql::ErrorExtensions;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+
+use crate::image::{ImageHeader, ImageType};
+use crate::types::EmoteDir;
+
+// Caps on what can be uploaded, so a tiny file can't make us decode gigabytes of pixels.
+// These are set globally in the config, and a dir can tighten (but not loosen) them.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct UploadLimits {
+    pub max_bytes: u64,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_frames: u32,
+    // width * height * frames, which is roughly how much memory decoding will take
+    pub max_total_pixels: u64,
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 16 * 1024 * 1024,
+            max_width: 4096,
+            max_height: 4096,
+            max_frames: 1000,
+            max_total_pixels: 256 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum UploadLimit {
+    Bytes,
+    Width,
+    Height,
+    Frames,
+    TotalPixels,
+}
+
+impl UploadLimit {
+    fn name(&self) -> &'static str {
+        match self {
+            UploadLimit::Bytes => "max_bytes",
+            UploadLimit::Width => "max_width",
+            UploadLimit::Height => "max_height",
+            UploadLimit::Frames => "max_frames",
+            UploadLimit::TotalPixels => "max_total_pixels",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            UploadLimit::Bytes => "file size in bytes",
+            UploadLimit::Width => "width in pixels",
+            UploadLimit::Height => "height in pixels",
+            UploadLimit::Frames => "number of frames",
+            UploadLimit::TotalPixels => "total decoded pixels (width * height * frames)",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UploadLimitError {
+    pub limit: UploadLimit,
+    pub allowed: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for UploadLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Upload rejected: its {} is {}, but at most {} is allowed ({})",
+            self.limit.description(),
+            self.actual,
+            self.allowed,
+            self.limit.name()
+        )
+    }
+}
+
+impl std::error::Error for UploadLimitError {}
+
+impl ErrorExtensions for UploadLimitError {
+    fn extend(&self) -> async_graphql::Error {
+        async_graphql::Error::new(self.to_string()).extend_with(|_, e| {
+            e.set("code", "UPLOAD_LIMIT_EXCEEDED");
+            e.set("limit", self.limit.name());
+            e.set("allowed", self.allowed);
+            e.set("actual", self.actual);
+        })
+    }
+}
+
+// Keeps the limit information when an error went through anyhow on its way up
+pub fn upload_error(e: anyhow::Error) -> async_graphql::Error {
+    match e.downcast::<UploadLimitError>() {
+        Ok(limit_error) => limit_error.extend(),
+        Err(e) => e.into(),
+    }
+}
+
+impl UploadLimits {
+    pub fn tightened_by(&self, dir: &EmoteDir) -> Self {
+        fn tighten<T: Ord + Copy + TryFrom<i64>>(global: T, dir: Option<i64>) -> T {
+            match dir.and_then(|dir| T::try_from(dir.max(0)).ok()) {
+                Some(dir) => global.min(dir),
+                None => global,
+            }
+        }
+
+        Self {
+            max_bytes: tighten(self.max_bytes, dir.max_upload_bytes),
+            max_width: tighten(self.max_width, dir.max_upload_width.map(i64::from)),
+            max_height: tighten(self.max_height, dir.max_upload_height.map(i64::from)),
+            max_frames: tighten(self.max_frames, dir.max_upload_frames.map(i64::from)),
+            max_total_pixels: tighten(self.max_total_pixels, dir.max_upload_total_pixels),
+        }
+    }
+
+    fn check(&self, limit: UploadLimit, actual: u64) -> Result<(), UploadLimitError> {
+        let allowed = match limit {
+            UploadLimit::Bytes => self.max_bytes,
+            UploadLimit::Width => self.max_width as u64,
+            UploadLimit::Height => self.max_height as u64,
+            UploadLimit::Frames => self.max_frames as u64,
+            UploadLimit::TotalPixels => self.max_total_pixels,
+        };
+        if actual > allowed {
+            Err(UploadLimitError {
+                limit,
+                allowed,
+                actual,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    // Checks the size before reading anything, and never reads more than the limit in case the size lied
+    pub fn read_upload(&self, file: File) -> anyhow::Result<Vec<u8>> {
+        self.check(UploadLimit::Bytes, file.metadata()?.len())?;
+
+        let mut buffer = vec![];
+        file.take(self.max_bytes + 1).read_to_end(&mut buffer)?;
+        self.check(UploadLimit::Bytes, buffer.len() as u64)?;
+
+        Ok(buffer)
+    }
+
+    pub fn check_dimensions(
+        &self,
+        width: u32,
+        height: u32,
+        frames: u32,
+    ) -> Result<(), UploadLimitError> {
+        self.check(UploadLimit::Width, width as u64)?;
+        self.check(UploadLimit::Height, height as u64)?;
+        self.check(UploadLimit::Frames, frames as u64)?;
+        self.check(
+            UploadLimit::TotalPixels,
+            width as u64 * height as u64 * frames as u64,
+        )
+    }
+
+    // Runs before the image is handed to a decoder. Formats we can't read the headers of are checked after decoding
+    // instead, except SVGs, since the size one renders at is only known once it's been rendered.
+    pub fn check_header(&self, buffer: &[u8]) -> anyhow::Result<()> {
+        match ImageHeader::probe(buffer) {
+            Some(header) => self.check_dimensions(header.width, header.height, header.frames)?,
+            None if ImageType::sniff(buffer) == Some(ImageType::SVG) => {
+                return Err(anyhow::anyhow!(
+                    "Upload rejected: SVGs need a width and height or a viewBox on the <svg> element"
+                ))
+            }
+            None => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::png;
+    use uuid::Uuid;
+
+    fn limits() -> UploadLimits {
+        UploadLimits {
+            max_bytes: 1000,
+            max_width: 64,
+            max_height: 32,
+            max_frames: 10,
+            max_total_pixels: 64 * 32 * 4,
+        }
+    }
+
+    fn exceeded(result: anyhow::Result<()>) -> Option<(&'static str, u64, u64)> {
+        let e = result.err()?.downcast::<UploadLimitError>().ok()?;
+        Some((e.limit.name(), e.allowed, e.actual))
+    }
+
+    fn read(limits: &UploadLimits, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let path = std::env::temp_dir().join(format!("emotes-rs-upload-{}", Uuid::new_v4()));
+        std::fs::write(&path, bytes).unwrap();
+        let result = limits.read_upload(File::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn reads_uploads_up_to_the_limit() {
+        let bytes = vec![7; 1000];
+        assert_eq!(read(&limits(), &bytes).unwrap(), bytes);
+        assert_eq!(
+            exceeded(read(&limits(), &[7; 1001]).map(|_| ())),
+            Some(("max_bytes", 1000, 1001))
+        );
+    }
+
+    #[test]
+    fn checks_dimensions() {
+        let limits = limits();
+        assert!(limits.check_dimensions(64, 32, 4).is_ok());
+        let error = |result: Result<(), UploadLimitError>| exceeded(result.map_err(Into::into));
+        assert_eq!(
+            error(limits.check_dimensions(65, 32, 1)),
+            Some(("max_width", 64, 65))
+        );
+        assert_eq!(
+            error(limits.check_dimensions(64, 33, 1)),
+            Some(("max_height", 32, 33))
+        );
+        assert_eq!(
+            error(limits.check_dimensions(1, 1, 11)),
+            Some(("max_frames", 10, 11))
+        );
+        // each is within its own limit, but not all together
+        assert_eq!(
+            error(limits.check_dimensions(64, 32, 5)),
+            Some(("max_total_pixels", 64 * 32 * 4, 64 * 32 * 5))
+        );
+    }
+
+    #[test]
+    fn checks_headers() {
+        let limits = limits();
+        assert!(limits.check_header(&png(64, 32)).is_ok());
+        assert_eq!(
+            exceeded(limits.check_header(&png(100, 10))),
+            Some(("max_width", 64, 100))
+        );
+        // nothing we can read the headers of, so that's up to the decoder
+        assert!(limits.check_header(b"not an image").is_ok());
+    }
+
+    #[test]
+    fn checks_svg_headers() {
+        let limits = limits();
+        assert!(limits
+            .check_header(br#"<svg width="64" height="32"></svg>"#)
+            .is_ok());
+        assert_eq!(
+            exceeded(limits.check_header(br#"<svg viewBox="0 0 64 4000"></svg>"#)),
+            Some(("max_height", 32, 4000))
+        );
+
+        // the size is only known once it's been rendered
+        let no_size =
+            limits.check_header(br#"<!-- --><svg><rect width="9999" height="9999"/></svg>"#);
+        assert!(no_size.is_err());
+        assert_eq!(exceeded(no_size), None);
+    }
+}
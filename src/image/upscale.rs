@@ -0,0 +1,61 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::image::{DisplayOptions, ScaleKernel};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpscaleAction {
+    // make it bigger like any other size, with the policy's kernel if it has one
+    Allow,
+    // cap it at the emote's own size, which usually means the original is served
+    Never,
+    // refuse the request
+    Reject,
+}
+
+// What to do when a request is wider than the emote itself
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct UpscalePolicy {
+    pub action: UpscaleAction,
+    // only for "allow", eg. nearest so upscales stay sharp instead of blurry. None keeps the emote's kernel.
+    pub kernel: Option<ScaleKernel>,
+}
+
+impl Default for UpscalePolicy {
+    fn default() -> Self {
+        Self {
+            action: UpscaleAction::Allow,
+            kernel: None,
+        }
+    }
+}
+
+impl UpscalePolicy {
+    // `natural_width` is how wide the emote comes out without any scaling
+    pub fn apply(&self, options: &mut DisplayOptions, natural_width: u32) -> Result<()> {
+        if natural_width == 0 || options.width <= natural_width {
+            return Ok(());
+        }
+
+        match self.action {
+            // a kernel picked in the request still wins
+            UpscaleAction::Allow => options.kernel = options.kernel.or(self.kernel),
+            UpscaleAction::Never => {
+                options.height = options.height.map(|height| {
+                    ((height as u64 * natural_width as u64 / options.width as u64) as u32).max(1)
+                });
+                options.width = natural_width;
+            }
+            UpscaleAction::Reject => {
+                return Err(anyhow!(
+                    "This emote is only {} pixels wide, it can't be made {} wide",
+                    natural_width,
+                    options.width
+                ))
+            }
+        }
+        Ok(())
+    }
+}
@@ -4,8 +4,7 @@ use actix_web::{
     middleware::{Logger, NormalizePath},
     web, App, HttpServer,
 };
-use anyhow::{Context, Result};
-use async_graphql::EmptySubscription;
+use anyhow::{anyhow, bail, Context, Result};
 use async_graphql::Schema;
 use dotenv::dotenv;
 use log::*;
@@ -17,7 +16,10 @@ mod graphql_schema;
 mod handler;
 mod image;
 mod storage;
+#[cfg(test)]
+mod test_support;
 mod types;
+mod worker_pool;
 
 use config::EMOTES_CONFIG;
 
@@ -27,6 +29,16 @@ async fn main() -> Result<()> {
     dotenv().ok();
     env_logger::init();
 
+    if !EMOTES_CONFIG.resizer_backend.compiled_in() {
+        bail!(
+            "The {:?} resizer backend was selected in the config, but emotes-rs was built without it.",
+            EMOTES_CONFIG.resizer_backend
+        );
+    }
+    // the same number of threads as the worker pool
+    #[cfg(feature = "vips")]
+    image::VipsResizerBackend::set_threads(EMOTES_CONFIG.workers.threads);
+
     let db_pool = Arc::new(
         PgPoolOptions::new()
             // TODO make this variable configurable in the JSON file
@@ -42,6 +54,20 @@ async fn main() -> Result<()> {
         .await
         .with_context(|| "Failed to migrate the database!")?;
 
+    // `emotes-rs reprocess [--dir UUID] [--type standard|sticker] [--content-type TYPE] [--outdated]` queues a
+    // reprocess run and exits, the resize workers of the running instances make the images
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("reprocess") {
+        let run = types::ReprocessRun::start(Arc::clone(&db_pool), &reprocess_filter(&args[1..])?)
+            .await
+            .map_err(|e| anyhow!(e.message))?;
+        println!(
+            "Queued {} images to be made again, follow it with reprocessRun(uuid: \"{}\")",
+            run.total, run.uuid
+        );
+        return Ok(());
+    }
+
     // Configure first-run mode by checking how many administrator users **with tokens** are in the database
     if let Some(count) = sqlx::query!(
         "SELECT COUNT(emote_token.uuid) as count FROM emote_token INNER JOIN emote_user ON emote_user.uuid = emote_token.emote_user_uuid WHERE emote_user.administrator = ($1)",
@@ -61,10 +87,17 @@ async fn main() -> Result<()> {
 
     info!("Database connection success, starting up actix");
 
+    actix_web::rt::spawn(types::listen_for_events(Arc::clone(&db_pool)));
+    actix_web::rt::spawn(types::ResizeJob::work(Arc::clone(&db_pool)));
+    actix_web::rt::spawn(types::ReconcileReport::work(
+        Arc::clone(&db_pool),
+        EMOTES_CONFIG.reconciler,
+    ));
+
     let schema = Schema::build(
         graphql_schema::query::Query,
         graphql_schema::mutation::Mutation,
-        EmptySubscription,
+        graphql_schema::subscription::Subscription,
     )
     .data(Arc::clone(&db_pool))
     .finish();
@@ -91,6 +124,13 @@ async fn main() -> Result<()> {
                     .guard(guard::Post())
                     .to(handler::api_graphql_handler),
             )
+            // subscriptions, the token goes in the connection_init payload
+            .service(
+                web::resource("/api")
+                    .guard(guard::Get())
+                    .guard(guard::Header("upgrade", "websocket"))
+                    .to(handler::api_graphql_ws_handler),
+            )
             .service(
                 web::resource("/playground")
                     .guard(guard::Get())
@@ -113,3 +153,32 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+// The same filter the reprocessDerivatives mutation takes
+fn reprocess_filter(args: &[String]) -> Result<types::ReprocessFilter> {
+    let mut filter = types::ReprocessFilter::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "--dir" => filter.dir_uuid = Some(value()?.parse()?),
+            "--type" => {
+                filter.emote_type = Some(match value()?.as_str() {
+                    "standard" => types::EmoteType::Standard,
+                    "sticker" => types::EmoteType::Sticker,
+                    other => bail!("Unknown emote type {}, expected standard or sticker", other),
+                })
+            }
+            "--content-type" => filter.content_type = Some(value()?.clone()),
+            "--outdated" => filter.outdated_only = true,
+            _ => bail!(
+                "Unknown argument {}, expected --dir, --type, --content-type or --outdated",
+                arg
+            ),
+        }
+    }
+    Ok(filter)
+}
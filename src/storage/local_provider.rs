@@ -1,9 +1,14 @@
-use crate::storage::StorageProvider;
+use crate::storage::{ByteRange, ByteStream, ObjectHead, StorageProvider};
 use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::{StreamExt, TryStreamExt};
 use log::info;
 use serde::Deserialize;
 use std::fs;
+use std::io::{ErrorKind, SeekFrom};
 use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 
 pub struct LocalStorageProvider {
@@ -20,20 +25,88 @@ impl LocalStorageProvider {
             base_path: config.data_dir.clone(),
         })
     }
+
+    fn path(&self, uuid: Uuid) -> PathBuf {
+        self.base_path.join(format!("{}", uuid))
+    }
 }
 
+#[async_trait]
 impl StorageProvider for LocalStorageProvider {
-    fn save(&self, uuid: Uuid, data: &[u8]) -> Result<()> {
-        fs::write(self.base_path.join(format!("{}", uuid)), data)?;
+    async fn save(&self, uuid: Uuid, data: &[u8]) -> Result<()> {
+        tokio::fs::write(self.path(uuid), data).await?;
         Ok(())
     }
-    fn load(&self, uuid: Uuid) -> Result<Vec<u8>> {
-        Ok(fs::read(self.base_path.join(format!("{}", uuid)))?)
-    }
-    fn delete(&self, uuid: Uuid) -> Result<()> {
-        fs::remove_file(self.base_path.join(format!("{}", uuid)))?;
+    async fn save_stream(&self, uuid: Uuid, mut stream: ByteStream) -> Result<()> {
+        // written next to it and moved into place, list() skips it since it isn't named by a UUID
+        let part_path = self.base_path.join(format!("{}.part", uuid));
+        let mut file = tokio::fs::File::create(&part_path).await?;
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    drop(file);
+                    tokio::fs::remove_file(&part_path).await?;
+                    return Err(e);
+                }
+            };
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        tokio::fs::rename(&part_path, self.path(uuid)).await?;
         Ok(())
     }
+    async fn load(&self, uuid: Uuid) -> Result<Vec<u8>> {
+        Ok(tokio::fs::read(self.path(uuid)).await?)
+    }
+    async fn load_stream(&self, uuid: Uuid, range: Option<ByteRange>) -> Result<ByteStream> {
+        let mut file = tokio::fs::File::open(self.path(uuid)).await?;
+        let range = match range {
+            Some(range) => range,
+            None => return Ok(Box::pin(ReaderStream::new(file).map_err(Into::into))),
+        };
+        file.seek(SeekFrom::Start(range.start)).await?;
+        let length = match range.end {
+            Some(end) => (end + 1).saturating_sub(range.start),
+            None => u64::MAX,
+        };
+        Ok(Box::pin(
+            ReaderStream::new(file.take(length)).map_err(Into::into),
+        ))
+    }
+    async fn head(&self, uuid: Uuid) -> Result<Option<ObjectHead>> {
+        let metadata = match tokio::fs::metadata(self.path(uuid)).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(ObjectHead {
+            size: metadata.len(),
+            content_type: None,
+            last_modified: metadata.modified().ok().map(Into::into),
+        }))
+    }
+    async fn delete(&self, uuid: Uuid) -> Result<()> {
+        // already gone is fine, same as S3
+        match tokio::fs::remove_file(self.path(uuid)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        let mut uuids = vec![];
+        let mut entries = tokio::fs::read_dir(&self.base_path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(uuid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Uuid::parse_str(name).ok())
+            {
+                uuids.push(uuid);
+            }
+        }
+        Ok(uuids)
+    }
 }
 
 #[derive(Deserialize)]
@@ -1,12 +1,45 @@
 use crate::config::{EmotesConfigStorageProvider, EMOTES_CONFIG};
 use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::Stream;
 use lazy_static::lazy_static;
+use std::pin::Pin;
 use uuid::Uuid;
 
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+// Bytes `start` to `end` of an object, both inclusive like an HTTP Range. No `end` reads to the end of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectHead {
+    pub size: u64,
+    // only if the backend keeps track of it
+    pub content_type: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+#[async_trait]
 pub trait StorageProvider {
-    fn save(&self, uuid: Uuid, data: &[u8]) -> Result<()>;
-    fn load(&self, uuid: Uuid) -> Result<Vec<u8>>;
-    fn delete(&self, uuid: Uuid) -> Result<()>;
+    async fn save(&self, uuid: Uuid, data: &[u8]) -> Result<()>;
+    // the object only shows up once the stream is done
+    async fn save_stream(&self, uuid: Uuid, stream: ByteStream) -> Result<()>;
+    async fn load(&self, uuid: Uuid) -> Result<Vec<u8>>;
+    async fn load_stream(&self, uuid: Uuid, range: Option<ByteRange>) -> Result<ByteStream>;
+    // None if nothing's stored under `uuid`
+    async fn head(&self, uuid: Uuid) -> Result<Option<ObjectHead>>;
+    async fn exists(&self, uuid: Uuid) -> Result<bool> {
+        Ok(self.head(uuid).await?.is_some())
+    }
+    async fn delete(&self, uuid: Uuid) -> Result<()>;
+    // everything stored, anything that isn't named by a UUID is left out
+    async fn list(&self) -> Result<Vec<Uuid>>;
 }
 
 mod local_provider;
@@ -17,7 +50,7 @@ pub use s3_provider::{S3StorageProvider, S3StorageProviderConfig};
 
 // TODO make STORAGE_PROVIDER dynamically configurable from EMOTES_CONFIG
 lazy_static! {
-    pub static ref STORAGE_PROVIDER: Box<dyn StorageProvider + Sync> =
+    pub static ref STORAGE_PROVIDER: Box<dyn StorageProvider + Send + Sync> =
         match &EMOTES_CONFIG.storage_provider {
             EmotesConfigStorageProvider::Local(config) =>
                 Box::new(LocalStorageProvider::new(config).unwrap()),
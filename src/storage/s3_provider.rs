@@ -1,9 +1,18 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::{stream, TryStreamExt};
 use s3::{bucket::Bucket, creds::Credentials};
 use serde::Deserialize;
+use std::io;
+use tokio_util::io::StreamReader;
 use uuid::Uuid;
 
-use crate::storage::StorageProvider;
+use crate::storage::{ByteRange, ByteStream, ObjectHead, StorageProvider};
+
+// how much of an object each ranged GET fetches when streaming it
+const CHUNK_SIZE: u64 = 1024 * 1024;
 
 pub struct S3StorageProvider {
     bucket: Bucket,
@@ -28,18 +37,97 @@ impl S3StorageProvider {
     }
 }
 
-impl StorageProvider for S3StorageProvider {
-    fn save(&self, uuid: Uuid, data: &[u8]) -> Result<()> {
-        self.bucket.put_object_blocking(format!("{}", uuid), data)?;
+// anything outside 2xx is an error, except a 404 where the caller asks for it
+fn check_code(code: u16, uuid: Uuid) -> Result<()> {
+    if (200..300).contains(&code) {
         Ok(())
+    } else {
+        Err(anyhow!("S3 returned {} for {}", code, uuid))
     }
-    fn load(&self, uuid: Uuid) -> Result<Vec<u8>> {
-        // We're ignoring the "code" value. TODO don't ignore the code value.
-        Ok(self.bucket.get_object_blocking(format!("{}", uuid))?.0)
+}
+
+#[async_trait]
+impl StorageProvider for S3StorageProvider {
+    async fn save(&self, uuid: Uuid, data: &[u8]) -> Result<()> {
+        let (_, code) = self.bucket.put_object(format!("{}", uuid), data).await?;
+        check_code(code, uuid)
     }
-    fn delete(&self, uuid: uuid::Uuid) -> Result<()> {
-        self.bucket.delete_object_blocking(format!("{}", uuid))?;
-        Ok(())
+    async fn save_stream(&self, uuid: Uuid, stream: ByteStream) -> Result<()> {
+        // uploaded in parts as the stream comes in
+        let mut reader =
+            StreamReader::new(stream.map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+        let code = self
+            .bucket
+            .put_object_stream(&mut reader, format!("{}", uuid))
+            .await?;
+        check_code(code, uuid)
+    }
+    async fn load(&self, uuid: Uuid) -> Result<Vec<u8>> {
+        let (data, code) = self.bucket.get_object(format!("{}", uuid)).await?;
+        check_code(code, uuid)?;
+        Ok(data)
+    }
+    async fn load_stream(&self, uuid: Uuid, range: Option<ByteRange>) -> Result<ByteStream> {
+        let size = self
+            .head(uuid)
+            .await?
+            .ok_or_else(|| anyhow!("{} isn't stored", uuid))?
+            .size;
+        let range = range.unwrap_or(ByteRange {
+            start: 0,
+            end: None,
+        });
+        if size == 0 || range.start >= size {
+            return Ok(Box::pin(stream::empty()));
+        }
+        let end = range.end.map_or(size - 1, |end| end.min(size - 1));
+
+        // one ranged GET per chunk, so only a chunk is ever held in memory
+        let bucket = self.bucket.clone();
+        Ok(Box::pin(stream::try_unfold(range.start, move |offset| {
+            let bucket = bucket.clone();
+            async move {
+                if offset > end {
+                    return Ok(None);
+                }
+                let chunk_end = end.min(offset + CHUNK_SIZE - 1);
+                let (data, code) = bucket
+                    .get_object_range(format!("{}", uuid), offset, Some(chunk_end))
+                    .await?;
+                check_code(code, uuid)?;
+                Ok(Some((Bytes::from(data), chunk_end + 1)))
+            }
+        })))
+    }
+    async fn head(&self, uuid: Uuid) -> Result<Option<ObjectHead>> {
+        let (head, code) = self.bucket.head_object(format!("{}", uuid)).await?;
+        if code == 404 {
+            return Ok(None);
+        }
+        check_code(code, uuid)?;
+        Ok(Some(ObjectHead {
+            size: head.content_length.unwrap_or(0) as u64,
+            content_type: head.content_type,
+            last_modified: head
+                .last_modified
+                .and_then(|time| DateTime::parse_from_rfc2822(&time).ok())
+                .map(|time| time.with_timezone(&Utc)),
+        }))
+    }
+    async fn delete(&self, uuid: uuid::Uuid) -> Result<()> {
+        let (_, code) = self.bucket.delete_object(format!("{}", uuid)).await?;
+        check_code(code, uuid)
+    }
+    async fn list(&self) -> Result<Vec<Uuid>> {
+        // one result per page of keys
+        Ok(self
+            .bucket
+            .list(String::new(), None)
+            .await?
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .filter_map(|object| Uuid::parse_str(&object.key).ok())
+            .collect())
     }
 }
 
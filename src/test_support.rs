@@ -0,0 +1,71 @@
+// Helpers shared by the tests. The ones that need Postgres and a storage backend use the ones in the config file
+// EMOTES_CONFIG_FILE points at, and are skipped unless EMOTES_DB_TESTS is set as well, since they write to both.
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::config::EMOTES_CONFIG;
+use crate::types::{Emote, EmoteImage, EmoteType};
+
+// a gradient, so resizing it can't come out the same as some other picture
+pub fn png(width: u32, height: u32) -> Vec<u8> {
+    let pixels: Vec<u8> = (0..width * height)
+        .flat_map(|i| vec![(i % 256) as u8, (i / 256 % 256) as u8, 128, 255])
+        .collect();
+    let mut buffer = vec![];
+    {
+        let mut encoder = png::Encoder::new(&mut buffer, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&pixels).unwrap();
+    }
+    buffer
+}
+
+// None when the database tests are turned off
+pub async fn pool() -> Option<Arc<PgPool>> {
+    std::env::var_os("EMOTES_DB_TESTS")?;
+    let pool = PgPoolOptions::new()
+        .max_connections(8)
+        .connect(&EMOTES_CONFIG.db_url)
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    Some(Arc::new(pool))
+}
+
+// An emote in a dir of its own with the same slug, with `original` (a PNG) uploaded as its original
+pub async fn emote(pool: Arc<PgPool>, original: Vec<u8>) -> Emote {
+    let slug = Uuid::new_v4().to_string();
+    let dir_uuid = sqlx::query!(
+        "INSERT INTO emote_dir (slug) VALUES ($1) RETURNING uuid",
+        slug
+    )
+    .fetch_one(&*pool)
+    .await
+    .unwrap()
+    .uuid;
+    let emote_uuid = sqlx::query!(
+        "INSERT INTO emote (slug, emote_dir_uuid, emote_type) VALUES ($1, $2, $3) RETURNING uuid",
+        slug,
+        dir_uuid,
+        EmoteType::Standard as EmoteType
+    )
+    .fetch_one(&*pool)
+    .await
+    .unwrap()
+    .uuid;
+
+    EmoteImage::create_from_original(
+        Arc::clone(&pool),
+        emote_uuid,
+        "image/png".to_owned(),
+        original,
+        &EMOTES_CONFIG.upload_limits,
+    )
+    .await
+    .unwrap();
+    Emote::by_uuid(pool, emote_uuid).await.unwrap().unwrap()
+}
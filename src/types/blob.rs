@@ -0,0 +1,135 @@
+use async_graphql::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::storage::STORAGE_PROVIDER;
+
+// Bytes in the storage backend that any number of emote images can share. Everything here takes a connection
+// so it can be part of the transaction that points the images at the blob, that way the reference count always
+// moves together with the references.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Blob {
+    // sha256 of the stored bytes
+    pub hash: String,
+    pub size: i64,
+    pub ref_count: i32,
+    pub create_time: DateTime<Utc>,
+}
+
+impl Blob {
+    // The storage backends name everything by UUID, so a blob is stored under the first half of its hash
+    pub fn storage_key(hash: &str) -> Uuid {
+        let mut bytes = [0; 16];
+        // only ever made by put(), so it's always hex
+        hex::decode_to_slice(&hash[..32], &mut bytes).expect("blob hash isn't hex");
+        Uuid::from_bytes(bytes)
+    }
+
+    pub fn hash(bytes: &[u8]) -> String {
+        hex::encode(Sha256::digest(bytes))
+    }
+
+    // Adds a reference to the blob with these bytes, storing them first if there isn't one yet. Returns its hash.
+    pub async fn put(conn: &mut PgConnection, bytes: &[u8]) -> Result<String> {
+        let hash = Self::hash(bytes);
+        loop {
+            // already stored
+            if sqlx::query!(
+                "UPDATE blob SET ref_count = ref_count + 1 WHERE hash = ($1) RETURNING hash",
+                hash
+            )
+            .fetch_optional(&mut *conn)
+            .await?
+            .is_some()
+            {
+                return Ok(hash);
+            }
+
+            // this waits on anyone else storing the same bytes right now, if they get there first it's back to the update
+            if sqlx::query!(
+                "INSERT INTO blob (hash, size, ref_count) VALUES ($1, $2, $3) ON CONFLICT (hash) DO NOTHING RETURNING hash",
+                hash,
+                bytes.len() as i64,
+                1
+            )
+            .fetch_optional(&mut *conn)
+            .await?
+            .is_some()
+            {
+                STORAGE_PROVIDER
+                    .save(Self::storage_key(&hash), bytes)
+                    .await?;
+                return Ok(hash);
+            }
+        }
+    }
+
+    // Takes a reference away, deleting the blob when it was the last one. Returns whether it did, in which case
+    // the bytes should go with delete_bytes() once the transaction has committed.
+    pub async fn release(conn: &mut PgConnection, hash: &str) -> Result<bool> {
+        let released = sqlx::query!(
+            "UPDATE blob SET ref_count = ref_count - 1 WHERE hash = ($1) RETURNING ref_count",
+            hash
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        if released.map_or(false, |x| x.ref_count <= 0) {
+            return Self::delete_unreferenced(conn, hash).await;
+        }
+        Ok(false)
+    }
+
+    // Deletes the blob if nothing references it, returns whether it did. Only the row, the bytes are left for
+    // delete_bytes() after the commit so a rollback never leaves a row without its file.
+    pub async fn delete_unreferenced(conn: &mut PgConnection, hash: &str) -> Result<bool> {
+        Ok(sqlx::query!(
+            "DELETE FROM blob WHERE hash = ($1) AND ref_count <= ($2) RETURNING hash",
+            hash,
+            0
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some())
+    }
+
+    // Deletes the bytes of a blob whose row is gone. A placeholder row is held while they're deleted so a put() of
+    // the same bytes waits for it, and if there's a row already the bytes were stored again and stay. Anything
+    // that doesn't get deleted here is an orphan for the reconciler.
+    pub async fn delete_bytes(pool: &PgPool, hash: &str) -> Result<()> {
+        let mut tx = pool.begin().await?;
+        if sqlx::query!(
+            "INSERT INTO blob (hash, size, ref_count) VALUES ($1, $2, $3) ON CONFLICT (hash) DO NOTHING RETURNING hash",
+            hash,
+            0,
+            0
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .is_none()
+        {
+            return Ok(());
+        }
+
+        STORAGE_PROVIDER.delete(Self::storage_key(hash)).await?;
+        sqlx::query!("DELETE FROM blob WHERE hash = ($1)", hash)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Moves the reference count by `by`, for the reconciler
+    pub async fn adjust_ref_count(conn: &mut PgConnection, hash: &str, by: i32) -> Result<()> {
+        sqlx::query!(
+            "UPDATE blob SET ref_count = ref_count + $1::INTEGER WHERE hash = ($2)",
+            by,
+            hash
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+}
@@ -6,6 +6,11 @@ use sqlx::PgPool;
 use std::sync::Arc;
 use uuid::Uuid;
 
+use crate::config::EMOTES_CONFIG;
+use crate::image::{
+    upload_error, Animation, ContentBox, DisplayOptions, Normalize, OutputOptions, ScaleKernel,
+    Transforms,
+};
 use crate::types::*;
 
 use crate::graphql_schema::guards::{Column, UserOwnership};
@@ -16,7 +21,7 @@ pub enum EmoteType {
     Sticker,
 }
 
-#[derive(Serialize, Deserialize, Debug, SimpleObject)]
+#[derive(Serialize, Deserialize, Debug, Clone, SimpleObject)]
 #[graphql(complex)]
 pub struct Emote {
     pub uuid: Uuid,
@@ -25,6 +30,58 @@ pub struct Emote {
     pub emote_type: EmoteType,
     pub create_time: DateTime<Utc>,
     pub modify_time: Option<DateTime<Utc>>,
+    // Overrides for the optimize settings in the config file, NULL means use the config's
+    pub optimize: Option<bool>,
+    pub optimize_colours: Option<i32>,
+    pub optimize_dither: Option<bool>,
+    pub optimize_dedup_frames: Option<bool>,
+    pub optimize_transparent_diff: Option<bool>,
+    pub optimize_palette_png: Option<bool>,
+    // Applied whenever the emote is resized, the original stays as it was uploaded
+    pub trim: bool,
+    pub pad_square: bool,
+    // Part of the original the resized images are made from, all four are set or none are
+    pub crop_left: Option<i32>,
+    pub crop_top: Option<i32>,
+    pub crop_width: Option<i32>,
+    pub crop_height: Option<i32>,
+    // What to keep in view when a resize has to cut the emote down to another shape, in pixels of the original
+    pub focal_x: Option<i32>,
+    pub focal_y: Option<i32>,
+    // used for every resize of this emote, unless the request picks another one
+    pub scale_kernel: ScaleKernel,
+}
+
+#[derive(Debug, Clone, Copy, InputObject)]
+pub struct CropInput {
+    pub left: i32,
+    pub top: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Debug, Clone, Copy, InputObject)]
+pub struct FocalPointInput {
+    pub x: i32,
+    pub y: i32,
+}
+
+// Two emotes that look like the same picture
+#[derive(Debug, SimpleObject)]
+pub struct EmoteDuplicate {
+    pub emote: Emote,
+    pub duplicate_of: Emote,
+    // how many bits of the perceptual hashes differ, 0 for identical pictures
+    pub distance: u32,
+    // the uploaded files are byte-for-byte the same
+    pub exact: bool,
+}
+
+// An emote that looks like what was searched for
+#[derive(Debug, SimpleObject)]
+pub struct SimilarEmote {
+    pub emote: Emote,
+    pub distance: u32,
 }
 
 #[ComplexObject]
@@ -40,22 +97,157 @@ impl Emote {
         .fetch_all(&**pool)
         .await?)
     }
-    // get an emote image by size
+    // get an emote image by size, fit_bytes picks the one that was squeezed under that many bytes
+    // cover the one that fills width x height,
+    // transforms the one with those modifiers, eg. "flip=h,grayscale", and animation the one played back differently, eg. "speed=2,reverse"
+    // kernel the one scaled with a kernel other than the emote's, and output the one written out differently, eg. "format=jpeg,q=80"
     async fn image(
         &self,
         ctx: &Context<'_>,
-        width: i32,
-        height: Option<i32>,
+        width: u32,
+        height: Option<u32>,
+        fit_bytes: Option<u32>,
+        transforms: Option<String>,
+        animation: Option<String>,
+        cover: Option<bool>,
+        kernel: Option<ScaleKernel>,
+        output: Option<String>,
     ) -> Result<Option<EmoteImage>> {
         let pool = ctx.data::<Arc<PgPool>>()?;
-        EmoteImage::by_emote_and_size(Arc::clone(&pool), self.uuid, width, height).await
+        let options = DisplayOptions {
+            byte_budget: fit_bytes,
+            transforms: Transforms::parse(&transforms.unwrap_or_default())?,
+            animation: Animation::parse(&animation.unwrap_or_default())?,
+            cover: cover.unwrap_or(false),
+            kernel,
+            output: OutputOptions::parse(&output.unwrap_or_default())?,
+            ..DisplayOptions::sized(width, height)
+        };
+        match EmoteImage::serve(Arc::clone(&pool), self, options).await? {
+            Serve::Original(original) => Ok(Some(original)),
+            Serve::Resized(options) => {
+                EmoteImage::by_emote_and_options(Arc::clone(&pool), self.uuid, &options).await
+            }
+        }
+    }
+    // the placeholder fields are copied from the original image, so a listing doesn't have to ask for it
+    async fn blurhash(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        Ok(self.original(ctx).await?.and_then(|x| x.blurhash))
+    }
+    async fn average_colour(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        Ok(self.original(ctx).await?.and_then(|x| x.average_colour))
+    }
+    async fn dominant_colour(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        Ok(self.original(ctx).await?.and_then(|x| x.dominant_colour))
+    }
+    async fn animated(&self, ctx: &Context<'_>) -> Result<Option<bool>> {
+        Ok(self.original(ctx).await?.and_then(|x| x.animated))
     }
+    // other emotes in the same dir that look like this one, closest first
+    async fn duplicates(&self, ctx: &Context<'_>) -> Result<Vec<Emote>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        let original = if let Some(original) = self.original(ctx).await? {
+            original
+        } else {
+            return Ok(vec![]);
+        };
+
+        let mut duplicates = vec![];
+        for (duplicate, _) in original.duplicates_in_dir(Arc::clone(&pool)).await? {
+            if let Some(emote) = Emote::by_uuid(Arc::clone(&pool), duplicate.emote_uuid).await? {
+                duplicates.push(emote);
+            }
+        }
+        Ok(duplicates)
+    }
+}
+
+// Slugs end up in the emote's URL as /dir_slug/emote_slug, so they can't be empty or have a '/' of their own
+pub fn check_slug(slug: &str) -> Result<()> {
+    if slug.is_empty() {
+        return Err("slug can't be empty".into());
+    }
+    if slug.contains('/') {
+        return Err("slug can't contain '/'".into());
+    }
+    if slug.chars().count() > 200 {
+        return Err("slug can't be longer than 200 characters".into());
+    }
+    Ok(())
+}
+
+// Both are checked against the image's size when it's resized, this only catches what can't be right for any image
+pub fn check_crop(crop: Option<CropInput>, focal_point: Option<FocalPointInput>) -> Result<()> {
+    if let Some(crop) = crop {
+        if crop.left < 0 || crop.top < 0 || crop.width <= 0 || crop.height <= 0 {
+            return Err("crop can't be empty or start outside the image".into());
+        }
+    }
+    if let Some(focal_point) = focal_point {
+        if focal_point.x < 0 || focal_point.y < 0 {
+            return Err("focal point can't be outside the image".into());
+        }
+    }
+    Ok(())
 }
 
 impl Emote {
-    // TODO make this impl Deletable or something??
-    pub async fn delete(pool: Arc<PgPool>, uuid: Uuid) -> Result<PgQueryResult> {
-        // cascade was pointless
+    async fn original(&self, ctx: &Context<'_>) -> Result<Option<EmoteImage>> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+        EmoteImage::original(Arc::clone(&pool), self.uuid).await
+    }
+
+    // How big the resized images are at 1:1, before any trimming
+    pub fn natural_size(&self, original: &EmoteImage, transforms: &Transforms) -> (u32, u32) {
+        let (width, height) = match self.normalize().crop {
+            Some(crop) => (
+                crop.width.min(original.width.max(0) as u32),
+                crop.height.min(original.height.max(0) as u32),
+            ),
+            None => (original.width.max(0) as u32, original.height.max(0) as u32),
+        };
+        let (width, height) = if self.pad_square {
+            (width.max(height), width.max(height))
+        } else {
+            (width, height)
+        };
+        if transforms.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    pub fn normalize(&self) -> Normalize {
+        let crop = match (
+            self.crop_left,
+            self.crop_top,
+            self.crop_width,
+            self.crop_height,
+        ) {
+            (Some(left), Some(top), Some(width), Some(height)) => Some(ContentBox {
+                left: left as u32,
+                top: top as u32,
+                width: width as u32,
+                height: height as u32,
+            }),
+            _ => None,
+        };
+        let focal_point = match (self.focal_x, self.focal_y) {
+            (Some(x), Some(y)) => Some((x as u32, y as u32)),
+            _ => None,
+        };
+
+        Normalize {
+            trim: self.trim,
+            pad_square: self.pad_square,
+            crop,
+            focal_point,
+        }
+    }
+
+    // cascade was pointless, the images have to let go of their blobs
+    async fn delete_images(pool: Arc<PgPool>, uuid: Uuid) -> Result<()> {
         for emote_image_uuid in
             sqlx::query!("SELECT uuid FROM emote_image WHERE emote_uuid = ($1)", uuid)
                 .fetch_all(&*pool)
@@ -63,25 +255,59 @@ impl Emote {
         {
             EmoteImage::delete(Arc::clone(&pool), emote_image_uuid.uuid).await?;
         }
+        Ok(())
+    }
 
-        Ok(sqlx::query!("DELETE FROM emote WHERE uuid = ($1)", uuid)
+    // Deletes an emote whose upload never finished, it was never added so there's nothing to publish
+    pub async fn discard(pool: Arc<PgPool>, uuid: Uuid) -> Result<()> {
+        Self::delete_images(Arc::clone(&pool), uuid).await?;
+        sqlx::query!("DELETE FROM emote WHERE uuid = ($1)", uuid)
             .execute(&*pool)
-            .await?)
+            .await?;
+        Ok(())
+    }
+
+    // TODO make this impl Deletable or something??
+    pub async fn delete(pool: Arc<PgPool>, uuid: Uuid) -> Result<PgQueryResult> {
+        let emote = Self::by_uuid(Arc::clone(&pool), uuid).await?;
+        Self::delete_images(Arc::clone(&pool), uuid).await?;
+
+        let result = sqlx::query!("DELETE FROM emote WHERE uuid = ($1)", uuid)
+            .execute(&*pool)
+            .await?;
+
+        if let Some(emote) = emote {
+            EmoteDirChange::publish(&pool, &emote, EmoteDirChangeKind::Removed, None).await;
+        }
+        Ok(result)
     }
 
     // Don't really see a way to keep this DRY
     pub async fn all(pool: Arc<PgPool>) -> Result<Vec<Self>> {
         Ok(sqlx::query_as!(
             Emote,
-            "SELECT emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time FROM emote")
+            "SELECT emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time, emote.optimize, emote.optimize_colours, emote.optimize_dither, emote.optimize_dedup_frames, emote.optimize_transparent_diff, emote.optimize_palette_png, emote.trim, emote.pad_square, emote.crop_left, emote.crop_top, emote.crop_width, emote.crop_height, emote.focal_x, emote.focal_y, emote.scale_kernel as \"scale_kernel!: ScaleKernel\" FROM emote")
             .fetch_all(&*pool).await?)
     }
     pub async fn by_uuid(pool: Arc<PgPool>, uuid: Uuid) -> Result<Option<Self>> {
         Ok(sqlx::query_as!(
             Emote,
-            "SELECT emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time FROM emote WHERE emote.uuid = ($1)",
+            "SELECT emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time, emote.optimize, emote.optimize_colours, emote.optimize_dither, emote.optimize_dedup_frames, emote.optimize_transparent_diff, emote.optimize_palette_png, emote.trim, emote.pad_square, emote.crop_left, emote.crop_top, emote.crop_width, emote.crop_height, emote.focal_x, emote.focal_y, emote.scale_kernel as \"scale_kernel!: ScaleKernel\" FROM emote WHERE emote.uuid = ($1)",
             uuid).fetch_optional(&*pool).await?)
     }
+    // in one query instead of one each, the ones that don't exist are left out
+    pub async fn by_uuids(pool: Arc<PgPool>, uuids: &[Uuid]) -> Result<Vec<Self>> {
+        Ok(sqlx::query_as!(
+            Emote,
+            "SELECT emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time, emote.optimize, emote.optimize_colours, emote.optimize_dither, emote.optimize_dedup_frames, emote.optimize_transparent_diff, emote.optimize_palette_png, emote.trim, emote.pad_square, emote.crop_left, emote.crop_top, emote.crop_width, emote.crop_height, emote.focal_x, emote.focal_y, emote.scale_kernel as \"scale_kernel!: ScaleKernel\" FROM emote WHERE emote.uuid = ANY($1)",
+            uuids).fetch_all(&*pool).await?)
+    }
+    pub async fn by_dir(pool: Arc<PgPool>, dir_uuid: Uuid) -> Result<Vec<Self>> {
+        Ok(sqlx::query_as!(
+            Emote,
+            "SELECT emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time, emote.optimize, emote.optimize_colours, emote.optimize_dither, emote.optimize_dedup_frames, emote.optimize_transparent_diff, emote.optimize_palette_png, emote.trim, emote.pad_square, emote.crop_left, emote.crop_top, emote.crop_width, emote.crop_height, emote.focal_x, emote.focal_y, emote.scale_kernel as \"scale_kernel!: ScaleKernel\" FROM emote WHERE emote_dir_uuid = ($1)",
+            dir_uuid).fetch_all(&*pool).await?)
+    }
     pub async fn by_slug(pool: Arc<PgPool>, slug: String) -> Result<Option<Self>> {
         // 100% of the time, you can split the slug with '/'
         let emote_parts: Vec<&str> = slug.split("/").collect();
@@ -90,7 +316,7 @@ impl Emote {
         // might not be possible, though
         Ok(sqlx::query_as!(
             Emote,
-            "SELECT emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time FROM emote INNER JOIN emote_dir ON emote.emote_dir_uuid = emote_dir.uuid WHERE emote_dir.slug= ($1) AND emote.slug = ($2)",
+            "SELECT emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time, emote.optimize, emote.optimize_colours, emote.optimize_dither, emote.optimize_dedup_frames, emote.optimize_transparent_diff, emote.optimize_palette_png, emote.trim, emote.pad_square, emote.crop_left, emote.crop_top, emote.crop_width, emote.crop_height, emote.focal_x, emote.focal_y, emote.scale_kernel as \"scale_kernel!: ScaleKernel\" FROM emote INNER JOIN emote_dir ON emote.emote_dir_uuid = emote_dir.uuid WHERE emote_dir.slug= ($1) AND emote.slug = ($2)",
             emote_parts[0], emote_parts[1]).fetch_optional(&*pool).await?)
     }
 
@@ -100,24 +326,62 @@ impl Emote {
         slug: String,
         upload_value: UploadValue,
         emote_type: EmoteType,
+        trim: Option<bool>,       // falls back to the dir's default
+        pad_square: Option<bool>, // same here
+        crop: Option<CropInput>,
+        focal_point: Option<FocalPointInput>,
+        scale_kernel: Option<ScaleKernel>,
     ) -> Result<Emote> {
         if let None = upload_value.content_type {
             return Err("invalid content type".into());
         }
-        let emote = sqlx::query_as!(Emote, "INSERT INTO emote (slug, emote_dir_uuid, emote_type) VALUES ($1, $2, $3) RETURNING emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time",
+
+        check_slug(&slug)?;
+        check_crop(crop, focal_point)?;
+
+        let dir = if let Some(dir) = EmoteDir::by_uuid(Arc::clone(&pool), dir_uuid).await? {
+            dir
+        } else {
+            return Err("dir does not exist".into());
+        };
+
+        // Check as much as possible before anything gets decoded
+        let limits = EMOTES_CONFIG.upload_limits.tightened_by(&dir);
+        let image_buffer = limits
+            .read_upload(upload_value.content)
+            .map_err(upload_error)?;
+        limits.check_header(&image_buffer).map_err(upload_error)?;
+
+        let emote = sqlx::query_as!(Emote, "INSERT INTO emote (slug, emote_dir_uuid, emote_type, trim, pad_square, crop_left, crop_top, crop_width, crop_height, focal_x, focal_y, scale_kernel) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) RETURNING emote.uuid, emote.slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", emote.create_time, emote.modify_time, emote.optimize, emote.optimize_colours, emote.optimize_dither, emote.optimize_dedup_frames, emote.optimize_transparent_diff, emote.optimize_palette_png, emote.trim, emote.pad_square, emote.crop_left, emote.crop_top, emote.crop_width, emote.crop_height, emote.focal_x, emote.focal_y, emote.scale_kernel as \"scale_kernel!: ScaleKernel\"",
                                         slug,
                                         dir_uuid,
-                                        emote_type as EmoteType).fetch_one(&*pool).await?;
+                                        emote_type as EmoteType,
+                                        trim.unwrap_or(dir.default_trim),
+                                        pad_square.unwrap_or(dir.default_pad_square),
+                                        crop.map(|x| x.left),
+                                        crop.map(|x| x.top),
+                                        crop.map(|x| x.width),
+                                        crop.map(|x| x.height),
+                                        focal_point.map(|x| x.x),
+                                        focal_point.map(|x| x.y),
+                                        scale_kernel.unwrap_or_default() as ScaleKernel).fetch_one(&*pool).await?;
 
         // TODO create emote images here, the magic fun code
-        EmoteImage::create_from_original(
-            pool,
+        if let Err(e) = EmoteImage::create_from_original(
+            Arc::clone(&pool),
             emote.uuid,
             upload_value.content_type.unwrap(), // this is safe since we already checked this
-            upload_value.content,
+            image_buffer,
+            &limits,
         )
-        .await?;
+        .await
+        {
+            // don't leave an emote with no image behind
+            Self::discard(Arc::clone(&pool), emote.uuid).await?;
+            return Err(e);
+        }
 
+        EmoteDirChange::publish(&pool, &emote, EmoteDirChangeKind::Added, None).await;
         Ok(emote)
     }
 }
@@ -18,9 +18,26 @@ pub struct EmoteDir {
     pub slug: String,
     pub create_time: DateTime<Utc>,
     pub modify_time: Option<DateTime<Utc>>,
+    // Per-dir upload limits. These can only tighten the limits in the config file, NULL means use the config's.
+    pub max_upload_bytes: Option<i64>,
+    pub max_upload_width: Option<i32>,
+    pub max_upload_height: Option<i32>,
+    pub max_upload_frames: Option<i32>,
+    pub max_upload_total_pixels: Option<i64>,
+    // What new emotes get for Emote.trim and Emote.pad_square when the upload doesn't say
+    pub default_trim: bool,
+    pub default_pad_square: bool,
 }
 
 impl EmoteDir {
+    pub async fn by_uuid(pool: Arc<PgPool>, uuid: Uuid) -> Result<Option<Self>> {
+        Ok(
+            sqlx::query_as!(EmoteDir, "SELECT * FROM emote_dir WHERE uuid = ($1)", uuid)
+                .fetch_optional(&*pool)
+                .await?,
+        )
+    }
+
     pub async fn delete(pool: Arc<PgPool>, uuid: Uuid) -> Result<PgQueryResult> {
         // cascade was pointless
         for emote_uuid in
@@ -28,7 +45,7 @@ impl EmoteDir {
                 .fetch_all(&*pool)
                 .await?
         {
-            EmoteImage::delete(Arc::clone(&pool), emote_uuid.uuid).await?;
+            Emote::delete(Arc::clone(&pool), emote_uuid.uuid).await?;
         }
 
         Ok(sqlx::query!("DELETE FROM emote_dir WHERE uuid = ($1)", uuid)
@@ -66,15 +83,7 @@ impl EmoteDir {
     }
     async fn emotes(&self, ctx: &Context<'_>) -> Result<Vec<Emote>> {
         let pool = ctx.data::<Arc<PgPool>>()?;
-
-        // You have to do this when querying an enum
-        Ok(sqlx::query_as!(
-            Emote,
-            "SELECT uuid, slug, emote_dir_uuid, emote_type as \"emote_type!: EmoteType\", create_time, modify_time FROM emote WHERE emote_dir_uuid = ($1)",
-            self.uuid
-        )
-        .fetch_all(&**pool)
-        .await?)
+        Emote::by_dir(Arc::clone(&pool), self.uuid).await
     }
 }
 
@@ -0,0 +1,149 @@
+use async_graphql::*;
+use futures_channel::mpsc::Receiver;
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::graphql_schema::broker::Broker;
+use crate::types::{Emote, EmoteImage, ResizeJobState};
+
+lazy_static! {
+    static ref EMOTE_IMAGE_PROGRESS: Broker<EmoteImageProgress> =
+        Broker::new("emote_image_progress");
+    static ref EMOTE_DIR_CHANGES: Broker<EmoteDirChange> = Broker::new("emote_dir_changes");
+}
+
+// Hands what any instance publishes to this one's subscribers, for as long as the server runs
+pub async fn listen_for_events(pool: Arc<PgPool>) {
+    loop {
+        let mut listener = match PgListener::connect_with(&pool).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Couldn't connect to listen for events: {}", e);
+                retry_wait().await;
+                continue;
+            }
+        };
+        if let Err(e) = listener
+            .listen_all(vec![
+                EMOTE_IMAGE_PROGRESS.channel,
+                EMOTE_DIR_CHANGES.channel,
+            ])
+            .await
+        {
+            error!("Couldn't listen for events: {}", e);
+            retry_wait().await;
+            continue;
+        }
+        info!("Listening for events");
+
+        // recv() reconnects by itself, an error means it couldn't
+        loop {
+            match listener.recv().await {
+                Ok(notification) if notification.channel() == EMOTE_IMAGE_PROGRESS.channel => {
+                    EMOTE_IMAGE_PROGRESS.deliver(notification.payload())
+                }
+                Ok(notification) if notification.channel() == EMOTE_DIR_CHANGES.channel => {
+                    EMOTE_DIR_CHANGES.deliver(notification.payload())
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    error!("Stopped listening for events: {}", e);
+                    break;
+                }
+            }
+        }
+        retry_wait().await;
+    }
+}
+
+async fn retry_wait() {
+    actix_web::rt::time::sleep(std::time::Duration::from_secs(5)).await;
+}
+
+// A resized image of an emote moving along the resize queue
+#[derive(Serialize, Deserialize, Debug, Clone, SimpleObject)]
+pub struct EmoteImageProgress {
+    pub emote_uuid: Uuid,
+    pub emote_image_uuid: Uuid,
+    pub derivative_key: Option<String>,
+    pub state: ResizeJobState,
+    // why the last attempt failed, it's queued again unless the state is FAILED
+    pub error: Option<String>,
+}
+
+impl EmoteImageProgress {
+    // a NOTIFY payload has to fit in 8000 bytes, the rest of the error is in the job's last_error
+    const MAX_ERROR_CHARS: usize = 1000;
+
+    pub async fn publish(
+        pool: &PgPool,
+        emote_image: &EmoteImage,
+        state: ResizeJobState,
+        error: Option<String>,
+    ) {
+        EMOTE_IMAGE_PROGRESS
+            .publish(
+                pool,
+                &Self {
+                    emote_uuid: emote_image.emote_uuid,
+                    emote_image_uuid: emote_image.uuid,
+                    derivative_key: emote_image.derivative_key.clone(),
+                    state,
+                    error: error.map(|e| e.chars().take(Self::MAX_ERROR_CHARS).collect()),
+                },
+            )
+            .await;
+    }
+
+    pub fn subscribe() -> Receiver<Self> {
+        EMOTE_IMAGE_PROGRESS.subscribe()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum EmoteDirChangeKind {
+    Added,
+    Removed,
+    Renamed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, SimpleObject)]
+pub struct EmoteDirChange {
+    pub dir_uuid: Uuid,
+    pub kind: EmoteDirChangeKind,
+    pub emote_uuid: Uuid,
+    pub slug: String,
+    // only for renames
+    pub old_slug: Option<String>,
+}
+
+impl EmoteDirChange {
+    pub async fn publish(
+        pool: &PgPool,
+        emote: &Emote,
+        kind: EmoteDirChangeKind,
+        old_slug: Option<String>,
+    ) {
+        EMOTE_DIR_CHANGES
+            .publish(
+                pool,
+                &Self {
+                    dir_uuid: emote.emote_dir_uuid,
+                    kind,
+                    emote_uuid: emote.uuid,
+                    slug: emote.slug.clone(),
+                    old_slug,
+                },
+            )
+            .await;
+    }
+
+    pub fn subscribe() -> Receiver<Self> {
+        EMOTE_DIR_CHANGES.subscribe()
+    }
+}
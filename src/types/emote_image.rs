@@ -1,26 +1,34 @@
 use async_graphql::*;
 use chrono::{DateTime, Utc};
-use lazy_static::lazy_static;
-use libvips::VipsApp;
-use log::info;
+use futures_util::{future, stream, StreamExt};
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgQueryResult;
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
+use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
-use std::{fs::File, io::Read};
 use uuid::Uuid;
 
-use crate::{image::ImageProcessor, storage::STORAGE_PROVIDER};
+use crate::{
+    config::EMOTES_CONFIG,
+    image::{
+        has_metadata, upload_error, DisplayOptions, DuplicateAction, FrameInfo, ImageHashes,
+        ImageProcessor, ImageType, Normalize, Placeholder, UploadLimits, PIPELINE_VERSION,
+    },
+    storage::{ByteRange, ByteStream, STORAGE_PROVIDER},
+    types::{Blob, Emote, EmoteImageProgress, EmoteUser, ResizeJob, ResizeJobState},
+    worker_pool::WORKER_POOL,
+};
 
-lazy_static! {
-    static ref VIPS: VipsApp = {
-        let app = VipsApp::new("Emotes Vips Resizer", false).expect("failed to run vips");
-        app.concurrency_set(20);
-        app
-    };
+// How a request for an emote gets served
+pub enum Serve {
+    // the stored original already is what was asked for
+    Original(EmoteImage),
+    Resized(DisplayOptions),
 }
 
-#[derive(Serialize, Deserialize, Debug, SimpleObject)]
+#[derive(Serialize, Deserialize, Debug, Clone, SimpleObject)]
 pub struct EmoteImage {
     pub uuid: Uuid,
     pub width: i32,
@@ -35,23 +43,84 @@ pub struct EmoteImage {
     pub processing: bool,
     pub create_time: DateTime<Utc>,
     pub modify_time: Option<DateTime<Utc>>,
+    // DisplayOptions::derivative_key of what this was resized with, null for the original
+    pub derivative_key: Option<String>,
+    // Set when the image was made to fit under a number of bytes, the encode_* fields are what it took to get there
+    pub byte_budget: Option<i32>,
+    pub encode_quality: Option<i32>,
+    pub encode_colours: Option<i32>,
+    pub encode_frame_step: Option<i32>,
+    pub encode_scale: Option<f64>,
+    // Only set for originals. sha256 of the uploaded bytes, and a dHash for finding the same picture in another file.
+    pub content_hash: Option<String>,
+    pub perceptual_hash: Option<i64>,
+    // Only set for originals too, for showing something while the emote loads. The colours are "#rrggbb".
+    pub blurhash: Option<String>,
+    pub average_colour: Option<String>,
+    pub dominant_colour: Option<String>,
+    pub animated: Option<bool>,
+    // How it plays, for every image. NULL for images made before this was recorded.
+    pub frame_count: Option<i32>,
+    // ms, one per frame, as written in the file (browsers show 0 as 100ms)
+    pub frame_delays: Option<Vec<i32>>,
+    // of one loop in ms, 0 for stills
+    pub duration: Option<i32>,
+    // how many times it plays, 0 for forever, NULL for stills
+    pub loop_count: Option<i32>,
+    // the PIPELINE_VERSION it was resized with, null for originals and images resized before versions were kept
+    pub pipeline_version: Option<i32>,
+    // the Blob its bytes are in, null for images stored before blobs
+    pub blob_hash: Option<String>,
+    // Only set for originals. Whether the stored file has metadata in it, null if it was never checked.
+    pub has_metadata: Option<bool>,
 }
 
 impl EmoteImage {
-    pub fn get_emote_bytes(&self) -> anyhow::Result<Vec<u8>> {
-        Ok(STORAGE_PROVIDER.load(self.uuid)?)
+    // sizes every emote gets made in as soon as it's uploaded
+    const DEFAULT_WIDTHS: [u32; 5] = [24, 48, 64, 128, 256];
+    // how long a request waits for a missing size to be made before it's told to come back later
+    const WAIT_SECONDS: u64 = 10;
+    // how many images a backfill loads from the database at once
+    const BACKFILL_PAGE: i64 = 100;
+
+    // where its bytes are in the storage backend, images stored before blobs are under their own UUID
+    pub fn storage_key(&self) -> Uuid {
+        self.blob_hash
+            .as_deref()
+            .map_or(self.uuid, Blob::storage_key)
+    }
+
+    // the stored bytes, or just `range` of them, straight from the storage backend
+    pub async fn stream(&self, range: Option<ByteRange>) -> anyhow::Result<ByteStream> {
+        STORAGE_PROVIDER
+            .load_stream(self.storage_key(), range)
+            .await
+    }
+
+    // Lets go of whatever the image has stored, the bytes themselves only go once nothing else uses them. Returns
+    // what to delete from the storage backend after the transaction commits.
+    async fn release_storage(
+        conn: &mut PgConnection,
+        emote_image: &EmoteImage,
+    ) -> Result<Option<ReleasedStorage>> {
+        match &emote_image.blob_hash {
+            Some(hash) => Ok(Blob::release(conn, hash)
+                .await?
+                .then(|| ReleasedStorage::Blob(hash.clone()))),
+            // stored before blobs, unless it never got that far
+            None if !emote_image.processing => Ok(Some(ReleasedStorage::Image(emote_image.uuid))),
+            None => Ok(None),
+        }
     }
     pub async fn create_from_original(
         pool: Arc<PgPool>,
         emote_uuid: Uuid,
         content_type: String,
-        mut file: File,
+        file_vec: Vec<u8>,
+        limits: &UploadLimits,
     ) -> Result<EmoteImage> {
-        let mut file_vec: Vec<u8> = vec![];
-        file.read_to_end(&mut file_vec)?;
-
         // TODO lottie files are not implemented
-        let mut inserted_image = sqlx::query_as!(
+        let inserted_image = sqlx::query_as!(
                 EmoteImage,
                 "INSERT INTO emote_image (width, height, original, content_type, emote_uuid) VALUES ($1, $2, $3, $4, $5) RETURNING *",
             -1,
@@ -63,139 +132,940 @@ impl EmoteImage {
             .fetch_one(&*pool)
             .await?;
 
-        let proc = ImageProcessor::save(file_vec, inserted_image.uuid, &content_type)?;
+        let (image_uuid, image_content_type, limits) =
+            (inserted_image.uuid, content_type.clone(), *limits);
+        let (proc, original_bytes, has_metadata) = WORKER_POOL
+            .try_run(move || {
+                let proc = ImageProcessor::from_upload(
+                    file_vec,
+                    image_uuid,
+                    &image_content_type,
+                    &limits,
+                )?;
+                let original_bytes = proc.original_bytes();
+                let has_metadata = has_metadata(&original_bytes);
+                Ok((proc, original_bytes, has_metadata))
+            })
+            .await
+            .map_err(upload_error)?;
+
+        let mut tx = pool.begin().await?;
+        let blob_hash = Blob::put(&mut tx, &original_bytes).await?;
 
         // Update the image to say the processing is over
-        inserted_image.processing = sqlx::query!(
-            "UPDATE emote_image SET processing = ($1), width = ($2), height = ($3) WHERE uuid = ($4) RETURNING processing",
+        let image_hashes = proc.image_hashes.as_ref();
+        let placeholder = proc.placeholder.as_ref();
+        let frame_info = &proc.image_type_handler.frame_info;
+        let inserted_image = sqlx::query_as!(
+            EmoteImage,
+            "UPDATE emote_image SET processing = ($1), width = ($2), height = ($3), content_hash = ($4), perceptual_hash = ($5), blurhash = ($6), average_colour = ($7), dominant_colour = ($8), animated = ($9), frame_count = ($10), frame_delays = ($11), duration = ($12), loop_count = ($13), blob_hash = ($14), has_metadata = ($15) WHERE uuid = ($16) RETURNING *",
             false,
             proc.image_width as i32,
             proc.image_height as i32,
+            image_hashes.map(|x| x.content.clone()),
+            image_hashes.and_then(|x| x.perceptual),
+            placeholder.map(|x| x.blurhash.clone()),
+            placeholder.map(|x| x.average_colour.clone()),
+            placeholder.map(|x| x.dominant_colour.clone()),
+            placeholder.map(|x| x.animated),
+            frame_info.frame_count() as i32,
+            &frame_delays(frame_info),
+            frame_info.duration() as i32,
+            frame_info.loops.map(|x| x as i32),
+            blob_hash,
+            has_metadata,
             inserted_image.uuid
         )
-        .fetch_one(&*pool)
-        .await?
-        .processing;
+        .fetch_one(&mut tx)
+        .await?;
+        tx.commit().await?;
 
-        for size in [24, 48, 64, 128, 256] {
-            Self::resize_image(Arc::clone(&pool), emote_uuid, size, None).await?;
+        // check before resizing, so a rejected upload doesn't make a pile of images first
+        if let Some((duplicate, distance)) = inserted_image
+            .duplicates_in_dir(Arc::clone(&pool))
+            .await?
+            .first()
+        {
+            let duplicate_slug = Emote::by_uuid(Arc::clone(&pool), duplicate.emote_uuid)
+                .await?
+                .map(|emote| emote.slug)
+                .unwrap_or_default();
+            match EMOTES_CONFIG.duplicates.action {
+                DuplicateAction::Warn => info!(
+                    "Emote {} looks like {} (distance {})",
+                    emote_uuid, duplicate_slug, distance
+                ),
+                DuplicateAction::Reject => {
+                    Self::delete(Arc::clone(&pool), inserted_image.uuid).await?;
+                    return Err(Error::new(format!(
+                        "Upload rejected: it looks like the emote \"{}\" that's already in this dir",
+                        duplicate_slug
+                    ))
+                    .extend_with(|_, e| {
+                        e.set("code", "DUPLICATE_EMOTE");
+                        e.set("duplicate_of", duplicate_slug.clone());
+                        e.set("distance", *distance);
+                    }));
+                }
+            }
         }
 
+        Self::resize_default_widths(Arc::clone(&pool), emote_uuid).await?;
+
         Ok(inserted_image)
     }
 
+    // Throws away every resized image of an emote when the settings they were made with change.
+    // The default sizes are made again right away, anything else gets made again when it's asked for.
+    pub async fn regenerate_derivatives(pool: Arc<PgPool>, emote_uuid: Uuid) -> Result<()> {
+        for emote_image in sqlx::query!(
+            "SELECT uuid FROM emote_image WHERE emote_uuid = ($1) AND original = ($2)",
+            emote_uuid,
+            false
+        )
+        .fetch_all(&*pool)
+        .await?
+        {
+            Self::delete(Arc::clone(&pool), emote_image.uuid).await?;
+        }
+
+        Self::resize_default_widths(Arc::clone(&pool), emote_uuid).await?;
+
+        Ok(())
+    }
+
+    // Sizes the upscale policy turns into the original (or refuses) aren't made
+    async fn resize_default_widths(pool: Arc<PgPool>, emote_uuid: Uuid) -> Result<()> {
+        let emote = match Emote::by_uuid(Arc::clone(&pool), emote_uuid).await? {
+            Some(emote) => emote,
+            None => return Ok(()),
+        };
+
+        // capped sizes can come out the same
+        let mut derivative_keys = vec![];
+        for width in Self::DEFAULT_WIDTHS {
+            if let Ok(Serve::Resized(options)) = Self::serve(
+                Arc::clone(&pool),
+                &emote,
+                DisplayOptions::sized(width, None),
+            )
+            .await
+            {
+                if !derivative_keys.contains(&options.derivative_key()) {
+                    derivative_keys.push(options.derivative_key());
+                    Self::resize_image(Arc::clone(&pool), emote_uuid, options).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn by_uuid(pool: Arc<PgPool>, uuid: Uuid) -> Result<Option<EmoteImage>> {
+        Ok(sqlx::query_as!(
+            EmoteImage,
+            "SELECT * FROM emote_image WHERE uuid = ($1)",
+            uuid
+        )
+        .fetch_optional(&*pool)
+        .await?)
+    }
+
+    pub async fn original(pool: Arc<PgPool>, emote_uuid: Uuid) -> Result<Option<EmoteImage>> {
+        Ok(sqlx::query_as!(
+            EmoteImage,
+            "SELECT * FROM emote_image WHERE emote_uuid = ($1) AND original = ($2)",
+            emote_uuid,
+            true
+        )
+        .fetch_optional(&*pool)
+        .await?)
+    }
+
+    // Works out what a request for `options` really gets. Errors when the options can't be made or the upscale policy refuses them.
+    pub async fn serve(pool: Arc<PgPool>, emote: &Emote, options: DisplayOptions) -> Result<Serve> {
+        options.validate()?;
+        let original = match Self::original(Arc::clone(&pool), emote.uuid).await? {
+            Some(original) if !original.processing => original,
+            _ => return Ok(Serve::Resized(options)),
+        };
+
+        let mut options = options;
+        let (natural_width, _) = emote.natural_size(&original, &options.transforms);
+        EMOTES_CONFIG.upscale.apply(&mut options, natural_width)?;
+
+        if original.passes_through(emote, &options) {
+            Ok(Serve::Original(original))
+        } else {
+            Ok(Serve::Resized(options))
+        }
+    }
+
+    // Resizing to exactly the original's size with nothing else to do would only re-encode it.
+    // The resizer turns stills into PNGs and animations into GIFs, so only those can be served as they are,
+    // and only when they're known to be as clean as a resized image would be.
+    fn passes_through(&self, emote: &Emote, options: &DisplayOptions) -> bool {
+        let same_format = match &*self.content_type {
+            "image/gif" => true,
+            "image/png" => self.frame_count == Some(1),
+            _ => false,
+        };
+
+        same_format
+            && self.has_metadata == Some(false)
+            && options.width == self.width as u32
+            && options.height.is_none()
+            && options.byte_budget.is_none()
+            && !options.cover
+            && options.transforms.is_noop()
+            && options.animation.is_noop()
+            && options.output.is_noop()
+            && emote.normalize().is_noop()
+    }
+
     // If height isn't specified, resize to aspect ratio
+    // Queues the image to be made, false if it already was (or is being made)
     pub async fn resize_image(
         pool: Arc<PgPool>,
         emote_uuid: Uuid,
-        width: i32,
-        height: Option<i32>, // doesn't work yet
+        options: DisplayOptions, // height doesn't work yet
     ) -> Result<bool> {
-        actix_web::rt::spawn(async move {
-            let orig_emote_image = sqlx::query_as!(
-                EmoteImage,
-                "SELECT * FROM emote_image WHERE emote_uuid = ($1) AND original = ($2)",
-                emote_uuid,
-                true
+        let (_, inserted) = Self::insert_or_get(pool, emote_uuid, &options).await?;
+        Ok(inserted)
+    }
+
+    // Anyone with the URL can ask for a new size, so an emote only gets so many that way. The ones it already has are
+    // always served, and owners can still make more with dispatchEmoteImageResize.
+    pub async fn check_derivative_limit(
+        pool: Arc<PgPool>,
+        emote_uuid: Uuid,
+        options: &DisplayOptions,
+    ) -> Result<()> {
+        if Self::by_emote_and_options(Arc::clone(&pool), emote_uuid, options)
+            .await?
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        // requests at the same time can each get the last one, which only ever goes over by a few
+        let count = sqlx::query!(
+            "SELECT COUNT(uuid) AS \"count!\" FROM emote_image WHERE emote_uuid = ($1) AND original = ($2)",
+            emote_uuid,
+            false
+        )
+        .fetch_one(&*pool)
+        .await?
+        .count;
+        if count >= EMOTES_CONFIG.max_derivatives_per_emote {
+            return Err(format!(
+                "This emote already has {} resized images, ask for one of those",
+                count
             )
-            .fetch_one(&*pool)
-            .await?;
+            .into());
+        }
+        Ok(())
+    }
+
+    // The image for `options`, inserted as processing and queued if nobody asked for it before.
+    // (emote_uuid, derivative_key) is unique (unique_derivative_per_emote), so only one request ever inserts it and
+    // one job makes it, everyone else gets that same row back. true if this call inserted it.
+    pub async fn insert_or_get(
+        pool: Arc<PgPool>,
+        emote_uuid: Uuid,
+        options: &DisplayOptions,
+    ) -> Result<(EmoteImage, bool)> {
+        let orig_emote_image = Self::original(Arc::clone(&pool), emote_uuid)
+            .await?
+            .ok_or("This emote has no original image")?;
 
-            let proc = ImageProcessor::load(orig_emote_image.uuid, &orig_emote_image.content_type)?;
+        if let Some(resized_emote_image) = sqlx::query_as!(
+            EmoteImage,
+            "INSERT INTO emote_image (emote_uuid, width, height, original, content_type, processing, derivative_key, byte_budget) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (emote_uuid, derivative_key) DO NOTHING RETURNING *",
+            emote_uuid, options.width as i32, -1, false, orig_emote_image.content_type, true, options.derivative_key(), options.byte_budget.map(|x| x as i32)
+        ).fetch_optional(&*pool).await? {
+            ResizeJob::enqueue(Arc::clone(&pool), resized_emote_image.uuid).await?;
+            EmoteImageProgress::publish(&pool, &resized_emote_image, ResizeJobState::Queued, None)
+                .await;
+            info!("queued resizing image");
+            return Ok((resized_emote_image, true));
+        }
 
-            let resized_emote_image = sqlx::query_as!(
-                EmoteImage,
-                "INSERT INTO emote_image (emote_uuid, width, height, original, content_type, processing) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
-                emote_uuid, width, -1, false, orig_emote_image.content_type, true
-            ).fetch_one(&*pool).await?;
-
-            info!("Start resizing image; wait");
-
-            let (new_width, new_height) = proc.resize(
-                resized_emote_image.uuid,
-                width as u32,
-                height.map(|x| x as u32),
-                None,
-            )?;
-
-            // TODO change the content_type to actually be the extension BASED ON the emote, not the orig image content type
-            // any point in setting width?
-            let mut transact_fail = false;
-            if let Ok(res) = sqlx::query!(
-                "UPDATE emote_image SET processing = ($1), width = ($2), height = ($3) WHERE uuid = ($4)",
-                false,
-                new_width as u32,
-                new_height as u32,
-                resized_emote_image.uuid,
+        // somebody else got there first
+        let existing = Self::by_emote_and_options(Arc::clone(&pool), emote_uuid, options)
+            .await?
+            .ok_or("The resized image was deleted while it was being made")?;
+        Ok((existing, false))
+    }
+
+    // Waits up to WAIT_SECONDS for the resize job to finish the image. Returns it as it is then, which is still
+    // processing if it timed out or the job failed, and None if it was deleted in the meantime.
+    pub async fn wait_until_processed(pool: Arc<PgPool>, uuid: Uuid) -> Result<Option<EmoteImage>> {
+        // subscribed before looking, so a job that finishes in between isn't missed
+        let mut progress = EmoteImageProgress::subscribe().filter(move |progress| {
+            future::ready(
+                progress.emote_image_uuid == uuid
+                    && matches!(
+                        progress.state,
+                        ResizeJobState::Done | ResizeJobState::Failed
+                    ),
             )
-            .execute(&*pool)
-            .await {
-                let updated_images = res.rows_affected();
-                info!("# of images updated: {}", updated_images);
+        });
 
-                if updated_images == 0 {
-                    transact_fail = true;
-                }
-            }
-            else {
-                transact_fail = true;
+        let image = match Self::by_uuid(Arc::clone(&pool), uuid).await? {
+            Some(image) => image,
+            None => return Ok(None),
+        };
+        if !image.processing {
+            return Ok(Some(image));
+        }
+        if let Some(job) = ResizeJob::by_emote_image(Arc::clone(&pool), uuid).await? {
+            if job.state == ResizeJobState::Failed {
+                return Ok(Some(image));
             }
+        }
 
-            if transact_fail {
-                // this is a duplicate, or something went wrong, rollback
-                sqlx::query!(
-                    "DELETE FROM emote_image WHERE uuid = ($1)",
-                    resized_emote_image.uuid
-                )
-                .execute(&*pool)
-                .await?;
-                // TODO ADD DELETE FUNCTION TO ImageProcessor
-            }
+        // whether it finished, failed, timed out or the subscription was dropped for falling behind, the row says
+        // how it went
+        let _ = actix_web::rt::time::timeout(
+            std::time::Duration::from_secs(Self::WAIT_SECONDS),
+            progress.next(),
+        )
+        .await;
+        Self::by_uuid(pool, uuid).await
+    }
 
-            Ok::<(), async_graphql::Error>(())
-        });
-        info!("spawned resizing image");
+    // What a resize job runs. Errors leave the image processing so the job can try again.
+    pub async fn process_resize(pool: Arc<PgPool>, emote_image_uuid: Uuid) -> Result<()> {
+        let resized_emote_image = Self::by_uuid(Arc::clone(&pool), emote_image_uuid)
+            .await?
+            .ok_or("The image to resize was deleted")?;
+        let options = DisplayOptions::parse(
+            resized_emote_image
+                .derivative_key
+                .as_deref()
+                .ok_or("This image has no derivative key to resize by")?,
+            0,
+        )?;
 
-        Ok(true) // guess it always returns true...
+        let orig_emote_image = Self::original(Arc::clone(&pool), resized_emote_image.emote_uuid)
+            .await?
+            .ok_or("This emote has no original image")?;
+
+        let (normalize, optimize, kernel) =
+            match Emote::by_uuid(Arc::clone(&pool), resized_emote_image.emote_uuid).await? {
+                Some(emote) => (
+                    emote.normalize(),
+                    EMOTES_CONFIG.optimize.overridden_by(&emote),
+                    options.kernel.unwrap_or(emote.scale_kernel),
+                ),
+                None => (
+                    Normalize::default(),
+                    EMOTES_CONFIG.optimize,
+                    options.kernel.unwrap_or_default(),
+                ),
+            };
+
+        info!("Start resizing image; wait");
+
+        // only worth recording when something was actually turned down
+        let byte_budget = options.byte_budget;
+        let original_bytes = STORAGE_PROVIDER
+            .load(orig_emote_image.storage_key())
+            .await?;
+        let out_uuid = resized_emote_image.uuid;
+        let (new_width, new_height, new_type, encode, frame_info, image_bytes) = WORKER_POOL
+            .run(move || {
+                let proc = ImageProcessor::from_buffer(
+                    original_bytes,
+                    orig_emote_image.uuid,
+                    &orig_emote_image.content_type,
+                )?;
+                proc.resize(out_uuid, &options, &normalize, &optimize, kernel)
+            })
+            .await?;
+        let encode = byte_budget.map(|_| encode);
+
+        let mut tx = pool.begin().await?;
+        // what the row points at now, not when the resize started: a job that was taken over after its lease ran out
+        // can finish at the same time as the one that took it, and only one of them may let go of the old blob
+        let current = sqlx::query_as!(
+            EmoteImage,
+            "SELECT * FROM emote_image WHERE uuid = ($1) FOR UPDATE",
+            resized_emote_image.uuid
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or("The image to resize was deleted")?;
+        let new_hash = Blob::hash(&image_bytes);
+        let unchanged = current.blob_hash.as_deref() == Some(&*new_hash);
+        let blob_hash = if unchanged {
+            new_hash
+        } else {
+            Blob::put(&mut tx, &image_bytes).await?
+        };
+
+        // the resizer doesn't always output the same format as the original (eg. webp -> gif), so record what it made
+        let res = sqlx::query!(
+            "UPDATE emote_image SET processing = ($1), width = ($2), height = ($3), content_type = ($4), encode_quality = ($5), encode_colours = ($6), encode_frame_step = ($7), encode_scale = ($8), frame_count = ($9), frame_delays = ($10), duration = ($11), loop_count = ($12), pipeline_version = ($13), blob_hash = ($14) WHERE uuid = ($15)",
+            false,
+            new_width as u32,
+            new_height as u32,
+            new_type.content_type(),
+            encode.map(|x| x.quality as i32),
+            encode.map(|x| x.colours as i32),
+            encode.map(|x| x.frame_step as i32),
+            encode.map(|x| x.scale),
+            frame_info.frame_count() as i32,
+            &frame_delays(&frame_info),
+            frame_info.duration() as i32,
+            frame_info.loops.map(|x| x as i32),
+            PIPELINE_VERSION,
+            blob_hash,
+            resized_emote_image.uuid,
+        )
+        .execute(&mut tx)
+        .await?;
+        // made again by a reprocess, what it was before isn't needed anymore (unless it came out the same)
+        let released = if unchanged {
+            None
+        } else {
+            Self::release_storage(&mut tx, &current).await?
+        };
+        tx.commit().await?;
+        if let Some(released) = released {
+            released.delete(&pool).await;
+        }
+        info!("# of images updated: {}", res.rows_affected());
+
+        Ok(())
     }
 
-    // TODO add multiplier
-    pub async fn by_emote_and_size(
+    pub async fn by_emote_and_options(
         pool: Arc<PgPool>,
         emote_uuid: Uuid,
-        width: i32,
-        height: Option<i32>,
+        options: &DisplayOptions,
     ) -> Result<Option<EmoteImage>> {
-        if let Some(height) = height {
-            Ok(sqlx::query_as!(
+        Ok(sqlx::query_as!(
+            EmoteImage,
+            "SELECT * FROM emote_image WHERE emote_uuid = ($1) AND derivative_key = ($2)",
+            emote_uuid,
+            options.derivative_key()
+        )
+        .fetch_optional(&*pool)
+        .await?)
+    }
+
+    // None if the image hasn't been hashed
+    pub fn hashes(&self) -> Option<ImageHashes> {
+        Some(ImageHashes {
+            content: self.content_hash.clone()?,
+            perceptual: self.perceptual_hash,
+        })
+    }
+
+    pub fn distance_to(&self, other: &EmoteImage) -> Option<u32> {
+        self.hashes()?.distance(&other.hashes()?)
+    }
+
+    // Originals of the other emotes in the same dir that look like this one, closest first
+    pub async fn duplicates_in_dir(&self, pool: Arc<PgPool>) -> Result<Vec<(EmoteImage, u32)>> {
+        let max_distance = EMOTES_CONFIG.duplicates.max_distance;
+        // the database only hands back the ones that are close enough, the distances are worked out again here
+        let mut duplicates: Vec<(EmoteImage, u32)> = sqlx::query_as!(
+            EmoteImage,
+            "SELECT emote_image.* FROM emote_image INNER JOIN emote ON emote_image.emote_uuid = emote.uuid WHERE emote.emote_dir_uuid = (SELECT emote_dir_uuid FROM emote WHERE uuid = ($1)) AND emote_image.emote_uuid != ($1) AND emote_image.original = ($2) AND (emote_image.content_hash = ($3) OR hash_distance(emote_image.perceptual_hash, $4::BIGINT) <= $5::INTEGER)",
+            self.emote_uuid,
+            true,
+            self.content_hash,
+            self.perceptual_hash,
+            max_distance as i32
+        )
+        .fetch_all(&*pool)
+        .await?
+        .into_iter()
+        .filter_map(|other| {
+            let distance = self.distance_to(&other)?;
+            if distance <= max_distance {
+                Some((other, distance))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+        duplicates.sort_by_key(|(_, distance)| *distance);
+        Ok(duplicates)
+    }
+
+    // Originals the user can see (all of them for admins) that look like `hashes`, closest first
+    pub async fn similar_to(
+        pool: Arc<PgPool>,
+        hashes: &ImageHashes,
+        user: &EmoteUser,
+        max_distance: u32,
+    ) -> Result<Vec<(EmoteImage, u32)>> {
+        let candidates = if user.administrator {
+            sqlx::query_as!(
                 EmoteImage,
-                "SELECT * FROM emote_image WHERE emote_uuid = ($1) AND width = ($2) AND height = ($3)",
-                emote_uuid,
-                width,
-                height
+                "SELECT * FROM emote_image WHERE original = ($1) AND (content_hash = ($2) OR hash_distance(perceptual_hash, $3::BIGINT) <= $4::INTEGER)",
+                true,
+                hashes.content,
+                hashes.perceptual,
+                max_distance as i32
             )
-            .fetch_optional(&*pool)
-            .await?)
+            .fetch_all(&*pool)
+            .await?
         } else {
-            Ok(sqlx::query_as!(
+            sqlx::query_as!(
                 EmoteImage,
-                "SELECT * FROM emote_image WHERE emote_uuid = ($1) AND width = ($2)",
-                emote_uuid,
-                width,
+                "SELECT emote_image.* FROM emote_image INNER JOIN emote ON emote_image.emote_uuid = emote.uuid INNER JOIN emote_user_emote_dir e ON e.emote_dir_uuid = emote.emote_dir_uuid WHERE e.emote_user_uuid = ($1) AND emote_image.original = ($2) AND (emote_image.content_hash = ($3) OR hash_distance(emote_image.perceptual_hash, $4::BIGINT) <= $5::INTEGER)",
+                user.uuid,
+                true,
+                hashes.content,
+                hashes.perceptual,
+                max_distance as i32
             )
-            .fetch_optional(&*pool)
-            .await?)
+            .fetch_all(&*pool)
+            .await?
+        };
+
+        let mut similar: Vec<(EmoteImage, u32)> = candidates
+            .into_iter()
+            .filter_map(|candidate| {
+                let distance = hashes.distance(&candidate.hashes()?)?;
+                if distance <= max_distance {
+                    Some((candidate, distance))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        similar.sort_by_key(|(_, distance)| *distance);
+        Ok(similar)
+    }
+
+    // Every pair of originals across the instance that look alike, closest first
+    pub async fn all_duplicates(
+        pool: Arc<PgPool>,
+        max_distance: u32,
+    ) -> Result<Vec<(EmoteImage, EmoteImage, u32)>> {
+        // the pairs are found by the database, then only the images in them are loaded
+        let pairs = sqlx::query!(
+            "SELECT a.uuid AS first, b.uuid AS second FROM emote_image a INNER JOIN emote_image b ON a.uuid < b.uuid AND (a.content_hash = b.content_hash OR hash_distance(a.perceptual_hash, b.perceptual_hash) <= $1::INTEGER) WHERE a.original = ($2) AND b.original = ($2)",
+            max_distance as i32,
+            true
+        )
+        .fetch_all(&*pool)
+        .await?;
+        let uuids: Vec<Uuid> = pairs
+            .iter()
+            .flat_map(|pair| vec![pair.first, pair.second])
+            .collect();
+        let originals: HashMap<Uuid, EmoteImage> = sqlx::query_as!(
+            EmoteImage,
+            "SELECT * FROM emote_image WHERE uuid = ANY($1)",
+            &uuids
+        )
+        .fetch_all(&*pool)
+        .await?
+        .into_iter()
+        .map(|emote_image| (emote_image.uuid, emote_image))
+        .collect();
+
+        let mut duplicates = vec![];
+        for pair in pairs {
+            if let (Some(emote_image), Some(other)) =
+                (originals.get(&pair.first), originals.get(&pair.second))
+            {
+                match emote_image.distance_to(other) {
+                    Some(distance) if distance <= max_distance => {
+                        duplicates.push((emote_image.clone(), other.clone(), distance))
+                    }
+                    _ => (),
+                }
+            }
         }
+
+        duplicates.sort_by_key(|(_, _, distance)| *distance);
+        Ok(duplicates)
+    }
+
+    // Goes through the images a page at a time in uuid order: `page` gets the last uuid of the page before and returns
+    // up to BACKFILL_PAGE images after it, and `fill` does one image, returning whether it changed anything. Images
+    // that are filled in stop matching their page's query, so a backfill that was cut short carries on where it
+    // stopped when it's run again. The ones `fill` fails on are logged and left for the next run.
+    async fn backfill<P, PF, F, FF>(pool: Arc<PgPool>, what: &str, page: P, fill: F) -> Result<u64>
+    where
+        P: Fn(Arc<PgPool>, Uuid) -> PF,
+        PF: Future<Output = sqlx::Result<Vec<EmoteImage>>>,
+        F: Fn(Arc<PgPool>, EmoteImage) -> FF,
+        FF: Future<Output = anyhow::Result<bool>>,
+    {
+        let mut backfilled = 0;
+        let mut after = Uuid::nil();
+        loop {
+            let emote_images = page(Arc::clone(&pool), after).await?;
+            after = match emote_images.last() {
+                Some(emote_image) => emote_image.uuid,
+                None => return Ok(backfilled),
+            };
+
+            // as many at once as the worker pool has threads, that's where the decoding happens
+            let filled: Vec<_> = stream::iter(emote_images)
+                .map(|emote_image| {
+                    let uuid = emote_image.uuid;
+                    let filled = fill(Arc::clone(&pool), emote_image);
+                    async move { (uuid, filled.await) }
+                })
+                .buffer_unordered(WORKER_POOL.config.threads.max(1))
+                .collect()
+                .await;
+            for (uuid, filled) in filled {
+                match filled {
+                    Ok(true) => backfilled += 1,
+                    Ok(false) => (),
+                    Err(e) => info!("Failed to {} emote image {}: {}", what, uuid, e),
+                }
+            }
+        }
+    }
+
+    // Originals uploaded before hashes were a thing don't have any, returns how many were filled in
+    pub async fn backfill_hashes(pool: Arc<PgPool>) -> Result<u64> {
+        Self::backfill(
+            pool,
+            "hash",
+            |pool: Arc<PgPool>, after: Uuid| async move {
+                sqlx::query_as!(
+                    EmoteImage,
+                    "SELECT * FROM emote_image WHERE original = ($1) AND content_hash IS NULL AND uuid > ($2) ORDER BY uuid LIMIT ($3)",
+                    true,
+                    after,
+                    Self::BACKFILL_PAGE
+                )
+                .fetch_all(&*pool)
+                .await
+            },
+            Self::backfill_hash,
+        )
+        .await
+    }
+
+    async fn backfill_hash(pool: Arc<PgPool>, emote_image: EmoteImage) -> anyhow::Result<bool> {
+        let (uuid, content_type) = (emote_image.uuid, emote_image.content_type.clone());
+        let image_buffer = STORAGE_PROVIDER.load(emote_image.storage_key()).await?;
+        let image_hashes = WORKER_POOL
+            .run(move || {
+                let proc = ImageProcessor::from_buffer(image_buffer, uuid, &content_type)?;
+                Ok(ImageHashes::compute(
+                    &proc.image_type_handler.image_buffer,
+                    &*proc.image_type_handler.image_resizer,
+                ))
+            })
+            .await?;
+
+        sqlx::query!(
+            "UPDATE emote_image SET content_hash = ($1), perceptual_hash = ($2) WHERE uuid = ($3)",
+            image_hashes.content,
+            image_hashes.perceptual,
+            uuid
+        )
+        .execute(&*pool)
+        .await?;
+        Ok(true)
+    }
+
+    // Originals uploaded before placeholders were a thing don't have one, returns how many were filled in
+    pub async fn backfill_placeholders(pool: Arc<PgPool>) -> Result<u64> {
+        Self::backfill(
+            pool,
+            "make a placeholder for",
+            |pool: Arc<PgPool>, after: Uuid| async move {
+                sqlx::query_as!(
+                    EmoteImage,
+                    "SELECT * FROM emote_image WHERE original = ($1) AND blurhash IS NULL AND uuid > ($2) ORDER BY uuid LIMIT ($3)",
+                    true,
+                    after,
+                    Self::BACKFILL_PAGE
+                )
+                .fetch_all(&*pool)
+                .await
+            },
+            Self::backfill_placeholder,
+        )
+        .await
+    }
+
+    async fn backfill_placeholder(
+        pool: Arc<PgPool>,
+        emote_image: EmoteImage,
+    ) -> anyhow::Result<bool> {
+        let (uuid, content_type) = (emote_image.uuid, emote_image.content_type.clone());
+        let image_buffer = STORAGE_PROVIDER.load(emote_image.storage_key()).await?;
+        let placeholder = WORKER_POOL
+            .run(move || {
+                let proc = ImageProcessor::from_buffer(image_buffer, uuid, &content_type)?;
+                Placeholder::compute(
+                    &*proc.image_type_handler.image_resizer,
+                    proc.image_type_handler.frame_info.frame_count(),
+                )
+            })
+            .await?;
+
+        sqlx::query!(
+            "UPDATE emote_image SET blurhash = ($1), average_colour = ($2), dominant_colour = ($3), animated = ($4) WHERE uuid = ($5)",
+            placeholder.blurhash,
+            placeholder.average_colour,
+            placeholder.dominant_colour,
+            placeholder.animated,
+            uuid
+        )
+        .execute(&*pool)
+        .await?;
+        Ok(true)
+    }
+
+    // Derivatives made before the resizer reported its output type were all saved with the original's content type.
+    // This sniffs the stored bytes of every image and fixes the rows that lie, returning how many were fixed.
+    pub async fn repair_content_types(pool: Arc<PgPool>) -> Result<u64> {
+        Self::backfill(
+            pool,
+            "repair the content type of",
+            |pool: Arc<PgPool>, after: Uuid| async move {
+                sqlx::query_as!(
+                    EmoteImage,
+                    "SELECT * FROM emote_image WHERE processing = ($1) AND uuid > ($2) ORDER BY uuid LIMIT ($3)",
+                    false,
+                    after,
+                    Self::BACKFILL_PAGE
+                )
+                .fetch_all(&*pool)
+                .await
+            },
+            Self::repair_content_type,
+        )
+        .await
+    }
+
+    async fn repair_content_type(
+        pool: Arc<PgPool>,
+        emote_image: EmoteImage,
+    ) -> anyhow::Result<bool> {
+        let emote_bytes = STORAGE_PROVIDER.load(emote_image.storage_key()).await?;
+        let sniffed_type = match ImageType::sniff(&emote_bytes) {
+            Some(sniffed_type) => sniffed_type,
+            None => return Ok(false),
+        };
+        // libvips can only read APNGs as plain PNGs, so relabelling them would stop them from being resized
+        if sniffed_type == ImageType::APNG && emote_image.content_type == "image/png" {
+            return Ok(false);
+        }
+        if sniffed_type.content_type() == emote_image.content_type {
+            return Ok(false);
+        }
+
+        info!(
+            "Repairing content type of emote image {}: {} -> {}",
+            emote_image.uuid,
+            emote_image.content_type,
+            sniffed_type.content_type()
+        );
+        sqlx::query!(
+            "UPDATE emote_image SET content_type = ($1) WHERE uuid = ($2)",
+            sniffed_type.content_type(),
+            emote_image.uuid
+        )
+        .execute(&*pool)
+        .await?;
+        Ok(true)
+    }
+
+    // Images made before frame info was recorded, only the headers are read. Returns how many were filled in.
+    pub async fn backfill_frame_info(pool: Arc<PgPool>) -> Result<u64> {
+        Self::backfill(
+            pool,
+            "read the frame info of",
+            |pool: Arc<PgPool>, after: Uuid| async move {
+                sqlx::query_as!(
+                    EmoteImage,
+                    "SELECT * FROM emote_image WHERE processing = ($1) AND frame_count IS NULL AND uuid > ($2) ORDER BY uuid LIMIT ($3)",
+                    false,
+                    after,
+                    Self::BACKFILL_PAGE
+                )
+                .fetch_all(&*pool)
+                .await
+            },
+            Self::backfill_frame_info_of,
+        )
+        .await
+    }
+
+    async fn backfill_frame_info_of(
+        pool: Arc<PgPool>,
+        emote_image: EmoteImage,
+    ) -> anyhow::Result<bool> {
+        let emote_bytes = STORAGE_PROVIDER.load(emote_image.storage_key()).await?;
+        let frame_info = FrameInfo::probe(&emote_bytes).unwrap_or_else(FrameInfo::still);
+
+        sqlx::query!(
+            "UPDATE emote_image SET frame_count = ($1), frame_delays = ($2), duration = ($3), loop_count = ($4) WHERE uuid = ($5)",
+            frame_info.frame_count() as i32,
+            &frame_delays(&frame_info),
+            frame_info.duration() as i32,
+            frame_info.loops.map(|x| x as i32),
+            emote_image.uuid
+        )
+        .execute(&*pool)
+        .await?;
+        Ok(true)
+    }
+
+    // Images stored before blobs are under their own UUID. This moves them into blobs, so identical ones only get
+    // stored once. Returns how many were moved.
+    pub async fn backfill_blobs(pool: Arc<PgPool>) -> Result<u64> {
+        Self::backfill(
+            pool,
+            "move into a blob",
+            |pool: Arc<PgPool>, after: Uuid| async move {
+                sqlx::query_as!(
+                    EmoteImage,
+                    "SELECT * FROM emote_image WHERE processing = ($1) AND blob_hash IS NULL AND uuid > ($2) ORDER BY uuid LIMIT ($3)",
+                    false,
+                    after,
+                    Self::BACKFILL_PAGE
+                )
+                .fetch_all(&*pool)
+                .await
+            },
+            Self::backfill_blob,
+        )
+        .await
+    }
+
+    async fn backfill_blob(pool: Arc<PgPool>, emote_image: EmoteImage) -> anyhow::Result<bool> {
+        let emote_bytes = STORAGE_PROVIDER.load(emote_image.uuid).await?;
+
+        let mut tx = pool.begin().await?;
+        let blob_hash = Blob::put(&mut tx, &emote_bytes)
+            .await
+            .map_err(|e| anyhow::anyhow!(e.message))?;
+        // it might've been made again in the meantime, that already moved it
+        let res = sqlx::query!(
+            "UPDATE emote_image SET blob_hash = ($1) WHERE uuid = ($2) AND blob_hash IS NULL",
+            blob_hash,
+            emote_image.uuid
+        )
+        .execute(&mut tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            tx.rollback().await?;
+            return Ok(false);
+        }
+        tx.commit().await?;
+
+        STORAGE_PROVIDER.delete(emote_image.uuid).await?;
+        Ok(true)
     }
 
     pub async fn delete(pool: Arc<PgPool>, uuid: Uuid) -> Result<PgQueryResult> {
-        // delete from the storage backend
-        STORAGE_PROVIDER.delete(uuid)?;
+        let mut tx = pool.begin().await?;
+        // locked, so two deletes at once can't both let go of its blob
+        let emote_image = sqlx::query_as!(
+            EmoteImage,
+            "SELECT * FROM emote_image WHERE uuid = ($1) FOR UPDATE",
+            uuid
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let result = sqlx::query!("DELETE FROM emote_image WHERE uuid = ($1)", uuid)
+            .execute(&mut tx)
+            .await?;
+
+        // and from the storage backend, if this was the last image using the bytes
+        let released = match emote_image {
+            Some(emote_image) => Self::release_storage(&mut tx, &emote_image).await?,
+            None => None,
+        };
+        tx.commit().await?;
+        if let Some(released) = released {
+            released.delete(&pool).await;
+        }
+        Ok(result)
+    }
+}
+
+// Bytes nothing points at anymore
+enum ReleasedStorage {
+    Blob(String),
+    // stored under the image's own UUID, from before blobs
+    Image(Uuid),
+}
+
+impl ReleasedStorage {
+    // the rows are already gone by now, so a failure only leaves an orphan for the reconciler
+    async fn delete(self, pool: &PgPool) {
+        let deleted = match &self {
+            Self::Blob(hash) => Blob::delete_bytes(pool, hash).await,
+            Self::Image(uuid) => STORAGE_PROVIDER.delete(*uuid).await.map_err(Error::from),
+        };
+        if let Err(e) = deleted {
+            error!("Couldn't delete released storage: {}", e.message);
+        }
+    }
+}
+
+fn frame_delays(frame_info: &FrameInfo) -> Vec<i32> {
+    frame_info.delays.iter().map(|x| *x as i32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
 
-        Ok(
-            sqlx::query!("DELETE FROM emote_image WHERE uuid = ($1)", uuid)
-                .execute(&*pool)
-                .await?,
+    async fn ref_count(pool: &PgPool, emote_image_uuid: Uuid) -> i32 {
+        sqlx::query!(
+            "SELECT blob.ref_count FROM blob INNER JOIN emote_image ON emote_image.blob_hash = blob.hash WHERE emote_image.uuid = ($1)",
+            emote_image_uuid
         )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+        .ref_count
+    }
+
+    // a job taken over after its lease ran out can finish at the same time as the worker that took it
+    #[actix_web::test]
+    async fn overlapping_resizes_keep_one_reference() {
+        let pool = match test_support::pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let emote_uuid = test_support::emote(Arc::clone(&pool), test_support::png(64, 64))
+            .await
+            .uuid;
+        let options = DisplayOptions::sized(32, None);
+        let (image, _) = EmoteImage::insert_or_get(Arc::clone(&pool), emote_uuid, &options)
+            .await
+            .unwrap();
+
+        let (first, second) = futures_util::future::join(
+            EmoteImage::process_resize(Arc::clone(&pool), image.uuid),
+            EmoteImage::process_resize(Arc::clone(&pool), image.uuid),
+        )
+        .await;
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(ref_count(&pool, image.uuid).await, 1);
+
+        // and made again afterwards, like a reprocess
+        EmoteImage::process_resize(Arc::clone(&pool), image.uuid)
+            .await
+            .unwrap();
+        assert_eq!(ref_count(&pool, image.uuid).await, 1);
+
+        Emote::delete(Arc::clone(&pool), emote_uuid).await.unwrap();
     }
 }
@@ -1,11 +1,24 @@
+mod blob;
 mod emote;
 mod emote_dir;
+mod emote_events;
 mod emote_image;
 mod emote_token;
 mod emote_user;
+mod reconcile_report;
+mod reprocess_run;
+mod resize_job;
 
-pub use emote::{Emote, EmoteType};
+pub use blob::Blob;
+pub use emote::{
+    check_crop, check_slug, CropInput, Emote, EmoteDuplicate, EmoteType, FocalPointInput,
+    SimilarEmote,
+};
 pub use emote_dir::EmoteDir;
-pub use emote_image::EmoteImage;
+pub use emote_events::{listen_for_events, EmoteDirChange, EmoteDirChangeKind, EmoteImageProgress};
+pub use emote_image::{EmoteImage, Serve};
 pub use emote_token::{EmoteToken, SerializedEmoteToken};
 pub use emote_user::EmoteUser;
+pub use reconcile_report::{ReconcileReport, ReconcilerConfig};
+pub use reprocess_run::{ReprocessFilter, ReprocessProgress, ReprocessRun};
+pub use resize_job::{ResizeJob, ResizeJobState};
@@ -0,0 +1,210 @@
+use async_graphql::*;
+use chrono::{Duration, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    storage::STORAGE_PROVIDER,
+    types::{Blob, Emote, EmoteImage, ResizeJob, ResizeJobState},
+};
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct ReconcilerConfig {
+    // how often it runs by itself, 0 to only run it from the admin mutation
+    pub interval_minutes: u64,
+    // anything newer than this is left alone, it could still be in the middle of an upload or a resize
+    pub grace_minutes: i64,
+}
+
+impl Default for ReconcilerConfig {
+    fn default() -> Self {
+        Self {
+            interval_minutes: 60,
+            grace_minutes: 60,
+        }
+    }
+}
+
+// What the reconciler found between Postgres and the storage backend, and what it did about it (nothing on a dry run)
+#[derive(Serialize, Deserialize, Debug, Clone, Default, SimpleObject)]
+pub struct ReconcileReport {
+    pub dry_run: bool,
+    // resized images left processing with no job to finish them, they get queued again
+    pub requeued_images: Vec<Uuid>,
+    // processing resized images whose job failed, retrying them is up to an admin
+    pub failed_images: Vec<Uuid>,
+    // originals still processing long after the upload, their emotes, rows and files are deleted
+    pub abandoned_uploads: Vec<Uuid>,
+    // resized images whose file is gone, their rows are deleted so they get made again when asked for
+    pub missing_derivatives: Vec<Uuid>,
+    // originals whose file is gone, nothing can bring these back so they're only reported
+    pub missing_originals: Vec<Uuid>,
+    // stored files without an image or blob row, they're deleted once they're older than the grace period
+    pub orphaned_blobs: Vec<Uuid>,
+    // hashes of blobs whose ref_count didn't match the images pointing at them, it's set to what it should be
+    pub fixed_ref_counts: Vec<String>,
+    // hashes of blobs no image points at, they're deleted with their bytes
+    pub unreferenced_blobs: Vec<String>,
+}
+
+impl ReconcileReport {
+    pub async fn run(pool: Arc<PgPool>, dry_run: bool, grace_minutes: i64) -> Result<Self> {
+        // listed before the rows are read, so a file from an upload that's happening right now always has its row
+        let blobs: HashSet<Uuid> = STORAGE_PROVIDER.list().await?.into_iter().collect();
+
+        let images = sqlx::query_as!(EmoteImage, "SELECT * FROM emote_image")
+            .fetch_all(&*pool)
+            .await?;
+        // one query, so an image moving to a blob can't show up on one side and not the other
+        let blob_refs = sqlx::query!(
+            "SELECT blob.hash, blob.ref_count, blob.create_time, COUNT(emote_image.uuid) AS \"references!\" FROM blob LEFT JOIN emote_image ON emote_image.blob_hash = blob.hash GROUP BY blob.hash"
+        )
+        .fetch_all(&*pool)
+        .await?;
+        let jobs: HashMap<Uuid, ResizeJob> = ResizeJob::all(Arc::clone(&pool), None)
+            .await?
+            .into_iter()
+            .map(|job| (job.emote_image_uuid, job))
+            .collect();
+        let cutoff = Utc::now() - Duration::minutes(grace_minutes);
+
+        let mut report = Self {
+            dry_run,
+            ..Self::default()
+        };
+        let mut abandoned_emotes = Vec::new();
+        for image in &images {
+            if image.processing {
+                if image.create_time > cutoff {
+                    continue;
+                }
+                if image.original {
+                    report.abandoned_uploads.push(image.uuid);
+                    abandoned_emotes.push(image.emote_uuid);
+                    continue;
+                }
+                match jobs.get(&image.uuid) {
+                    Some(job) if job.state == ResizeJobState::Failed => {
+                        report.failed_images.push(image.uuid)
+                    }
+                    // a job that's done should've finished the image too
+                    Some(job) if job.state == ResizeJobState::Done => {
+                        report.requeued_images.push(image.uuid);
+                        if !dry_run {
+                            ResizeJob::requeue(Arc::clone(&pool), job.uuid).await?;
+                        }
+                    }
+                    Some(_) => (),
+                    None => {
+                        report.requeued_images.push(image.uuid);
+                        if !dry_run {
+                            ResizeJob::enqueue(Arc::clone(&pool), image.uuid).await?;
+                        }
+                    }
+                }
+            } else if !blobs.contains(&image.storage_key())
+                // stored since the list was taken, eg. a resize that just finished
+                && STORAGE_PROVIDER.head(image.storage_key()).await?.is_none()
+            {
+                if image.original {
+                    report.missing_originals.push(image.uuid);
+                } else {
+                    report.missing_derivatives.push(image.uuid);
+                }
+            }
+        }
+
+        let mut conn = pool.acquire().await?;
+        for blob in &blob_refs {
+            let references = blob.references as i32;
+            if references != blob.ref_count {
+                report.fixed_ref_counts.push(blob.hash.clone());
+                if !dry_run {
+                    // by the difference, so anything that changed it since the count was taken still counts
+                    Blob::adjust_ref_count(&mut conn, &blob.hash, references - blob.ref_count)
+                        .await?;
+                }
+            }
+            if references == 0 && blob.create_time <= cutoff {
+                report.unreferenced_blobs.push(blob.hash.clone());
+            }
+        }
+        drop(conn);
+
+        let rows: HashSet<Uuid> = images
+            .iter()
+            .map(|image| image.storage_key())
+            .chain(blob_refs.iter().map(|blob| Blob::storage_key(&blob.hash)))
+            .collect();
+        for &uuid in blobs.difference(&rows) {
+            // a blob being stored right now has its file before its row
+            match STORAGE_PROVIDER.head(uuid).await? {
+                Some(head) if head.last_modified.map_or(false, |time| time > cutoff) => (),
+                _ => report.orphaned_blobs.push(uuid),
+            }
+        }
+
+        if !dry_run {
+            for &uuid in &abandoned_emotes {
+                // an emote whose original never made it doesn't have anything else
+                Emote::discard(Arc::clone(&pool), uuid).await?;
+            }
+            for &uuid in &report.missing_derivatives {
+                // the row goes with its file, if there ever was one
+                EmoteImage::delete(Arc::clone(&pool), uuid).await?;
+            }
+            for hash in &report.unreferenced_blobs {
+                let mut tx = pool.begin().await?;
+                let deleted = Blob::delete_unreferenced(&mut tx, hash).await?;
+                tx.commit().await?;
+                if deleted {
+                    Blob::delete_bytes(&pool, hash).await?;
+                }
+            }
+            for &uuid in report
+                .abandoned_uploads
+                .iter()
+                .chain(&report.orphaned_blobs)
+            {
+                if blobs.contains(&uuid) {
+                    STORAGE_PROVIDER.delete(uuid).await?;
+                }
+            }
+        }
+
+        info!(
+            "Reconciled storage{}: {} requeued, {} failed, {} abandoned uploads, {} missing derivatives, {} missing originals, {} orphaned blobs, {} fixed ref counts, {} unreferenced blobs",
+            if dry_run { " (dry run)" } else { "" },
+            report.requeued_images.len(),
+            report.failed_images.len(),
+            report.abandoned_uploads.len(),
+            report.missing_derivatives.len(),
+            report.missing_originals.len(),
+            report.orphaned_blobs.len(),
+            report.fixed_ref_counts.len(),
+            report.unreferenced_blobs.len()
+        );
+        Ok(report)
+    }
+
+    // Reconciles every `interval_minutes` forever, unless it's 0
+    pub async fn work(pool: Arc<PgPool>, config: ReconcilerConfig) {
+        if config.interval_minutes == 0 {
+            return;
+        }
+        loop {
+            actix_web::rt::time::sleep(std::time::Duration::from_secs(
+                config.interval_minutes * 60,
+            ))
+            .await;
+            if let Err(e) = Self::run(Arc::clone(&pool), false, config.grace_minutes).await {
+                error!("Reconciler: {}", e.message);
+            }
+        }
+    }
+}
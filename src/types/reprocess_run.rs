@@ -0,0 +1,147 @@
+use async_graphql::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::image::PIPELINE_VERSION;
+use crate::types::{EmoteType, ResizeJobState};
+
+// Which resized images to make again, leaving everything out matches all of them
+#[derive(Debug, Clone, Default, InputObject)]
+pub struct ReprocessFilter {
+    pub dir_uuid: Option<Uuid>,
+    pub emote_type: Option<EmoteType>,
+    // of the resized image, eg. "image/gif"
+    pub content_type: Option<String>,
+    // only the ones made with an older PIPELINE_VERSION
+    #[graphql(default)]
+    pub outdated_only: bool,
+}
+
+// A batch of resized images being made again from their originals by the resize worker
+#[derive(Serialize, Deserialize, Debug, Clone, SimpleObject)]
+#[graphql(complex)]
+pub struct ReprocessRun {
+    pub uuid: Uuid,
+    pub pipeline_version: i32,
+    // how many images were queued
+    pub total: i32,
+    pub create_time: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, SimpleObject)]
+pub struct ReprocessProgress {
+    pub queued: u32,
+    pub running: u32,
+    pub done: u32,
+    pub failed: u32,
+    // done or failed out of the total
+    pub finished: bool,
+}
+
+#[ComplexObject]
+impl ReprocessRun {
+    // jobs that a later run queued again count towards that run instead
+    async fn progress(&self, ctx: &Context<'_>) -> Result<ReprocessProgress> {
+        let pool = ctx.data::<Arc<PgPool>>()?;
+
+        let mut progress = ReprocessProgress::default();
+        for row in sqlx::query!(
+            "SELECT state as \"state!: ResizeJobState\", COUNT(*) as \"count!\" FROM resize_job WHERE reprocess_run_uuid = ($1) GROUP BY state",
+            self.uuid
+        )
+        .fetch_all(&**pool)
+        .await?
+        {
+            let count = row.count as u32;
+            match row.state {
+                ResizeJobState::Queued => progress.queued = count,
+                ResizeJobState::Running => progress.running = count,
+                ResizeJobState::Done => progress.done = count,
+                ResizeJobState::Failed => progress.failed = count,
+            }
+        }
+        progress.finished = progress.queued == 0 && progress.running == 0;
+        Ok(progress)
+    }
+}
+
+impl ReprocessRun {
+    // Queues every resized image matching `filter` to be made again. They keep being served as they are until then.
+    // The run and its jobs go in together, so its total is always how many it queued.
+    pub async fn start(pool: Arc<PgPool>, filter: &ReprocessFilter) -> Result<ReprocessRun> {
+        let mut tx = pool.begin().await?;
+
+        let run = sqlx::query_as!(
+            ReprocessRun,
+            "INSERT INTO reprocess_run (pipeline_version, total) VALUES ($1, 0) RETURNING *",
+            PIPELINE_VERSION
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        // Jobs that are already queued or running get the current pipeline anyway, so they're left alone.
+        // Both of these have the same filter.
+        let restarted = sqlx::query!(
+            "UPDATE resize_job SET state = ($1), attempts = 0, last_error = NULL, run_after = current_timestamp, modify_time = current_timestamp, reprocess_run_uuid = ($2) FROM emote_image, emote WHERE resize_job.emote_image_uuid = emote_image.uuid AND emote.uuid = emote_image.emote_uuid AND resize_job.state IN ('done', 'failed') AND NOT emote_image.original AND NOT emote_image.processing AND ($3::UUID IS NULL OR emote.emote_dir_uuid = $3) AND ($4::EMOTE_TYPE IS NULL OR emote.emote_type = $4) AND ($5::TEXT IS NULL OR emote_image.content_type = $5) AND (NOT $6::BOOLEAN OR emote_image.pipeline_version IS DISTINCT FROM $7::INTEGER)",
+            ResizeJobState::Queued as ResizeJobState,
+            run.uuid,
+            filter.dir_uuid,
+            filter.emote_type as Option<EmoteType>,
+            filter.content_type,
+            filter.outdated_only,
+            PIPELINE_VERSION
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        // images resized before jobs were a thing don't have one
+        let created = sqlx::query!(
+            "INSERT INTO resize_job (emote_image_uuid, reprocess_run_uuid) SELECT emote_image.uuid, $1::UUID FROM emote_image JOIN emote ON emote.uuid = emote_image.emote_uuid WHERE NOT EXISTS (SELECT 1 FROM resize_job WHERE resize_job.emote_image_uuid = emote_image.uuid) AND NOT emote_image.original AND NOT emote_image.processing AND ($2::UUID IS NULL OR emote.emote_dir_uuid = $2) AND ($3::EMOTE_TYPE IS NULL OR emote.emote_type = $3) AND ($4::TEXT IS NULL OR emote_image.content_type = $4) AND (NOT $5::BOOLEAN OR emote_image.pipeline_version IS DISTINCT FROM $6::INTEGER) ON CONFLICT (emote_image_uuid) DO NOTHING",
+            run.uuid,
+            filter.dir_uuid,
+            filter.emote_type as Option<EmoteType>,
+            filter.content_type,
+            filter.outdated_only,
+            PIPELINE_VERSION
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        let run = sqlx::query_as!(
+            ReprocessRun,
+            "UPDATE reprocess_run SET total = ($1) WHERE uuid = ($2) RETURNING *",
+            (restarted + created) as i32,
+            run.uuid
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(run)
+    }
+
+    pub async fn by_uuid(pool: Arc<PgPool>, uuid: Uuid) -> Result<Option<ReprocessRun>> {
+        Ok(sqlx::query_as!(
+            ReprocessRun,
+            "SELECT * FROM reprocess_run WHERE uuid = ($1)",
+            uuid
+        )
+        .fetch_optional(&*pool)
+        .await?)
+    }
+
+    // newest first
+    pub async fn all(pool: Arc<PgPool>) -> Result<Vec<ReprocessRun>> {
+        Ok(sqlx::query_as!(
+            ReprocessRun,
+            "SELECT * FROM reprocess_run ORDER BY create_time DESC"
+        )
+        .fetch_all(&*pool)
+        .await?)
+    }
+}
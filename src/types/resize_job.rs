@@ -0,0 +1,286 @@
+use async_graphql::*;
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use uuid::Uuid;
+
+use crate::{
+    types::{EmoteImage, EmoteImageProgress},
+    worker_pool::WORKER_POOL,
+};
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Enum)]
+#[sqlx(type_name = "resize_job_state", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeJobState {
+    Queued,
+    Running,
+    Done,
+    // ran out of attempts, an admin has to retry it
+    Failed,
+}
+
+// Resizing an emote image that was inserted with processing = true, so it survives errors and restarts
+#[derive(Serialize, Deserialize, Debug, Clone, SimpleObject)]
+pub struct ResizeJob {
+    pub uuid: Uuid,
+    pub emote_image_uuid: Uuid,
+    pub state: ResizeJobState,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub run_after: DateTime<Utc>,
+    pub create_time: DateTime<Utc>,
+    pub modify_time: Option<DateTime<Utc>>,
+    // the reprocess run that last queued it
+    pub reprocess_run_uuid: Option<Uuid>,
+}
+
+impl ResizeJob {
+    pub const MAX_ATTEMPTS: i32 = 5;
+    // doubled after every failed attempt, so 30s, 1m, 2m, 4m
+    const BACKOFF_SECONDS: i64 = 30;
+    // how long the worker waits before looking again when there's nothing to do
+    const IDLE_MILLISECONDS: u64 = 500;
+    // a running job whose worker hasn't been heard from in this long is taken over by another one
+    const LEASE_SECONDS: i32 = 60;
+    const HEARTBEAT_SECONDS: u64 = 20;
+
+    pub async fn enqueue(pool: Arc<PgPool>, emote_image_uuid: Uuid) -> Result<ResizeJob> {
+        Ok(sqlx::query_as!(
+            ResizeJob,
+            "INSERT INTO resize_job (emote_image_uuid) VALUES ($1) RETURNING uuid, emote_image_uuid, state as \"state!: ResizeJobState\", attempts, last_error, run_after, create_time, modify_time, reprocess_run_uuid",
+            emote_image_uuid
+        )
+        .fetch_one(&*pool)
+        .await?)
+    }
+
+    pub async fn by_uuid(pool: Arc<PgPool>, uuid: Uuid) -> Result<Option<ResizeJob>> {
+        Ok(sqlx::query_as!(
+            ResizeJob,
+            "SELECT uuid, emote_image_uuid, state as \"state!: ResizeJobState\", attempts, last_error, run_after, create_time, modify_time, reprocess_run_uuid FROM resize_job WHERE uuid = ($1)",
+            uuid
+        )
+        .fetch_optional(&*pool)
+        .await?)
+    }
+
+    pub async fn by_emote_image(
+        pool: Arc<PgPool>,
+        emote_image_uuid: Uuid,
+    ) -> Result<Option<ResizeJob>> {
+        Ok(sqlx::query_as!(
+            ResizeJob,
+            "SELECT uuid, emote_image_uuid, state as \"state!: ResizeJobState\", attempts, last_error, run_after, create_time, modify_time, reprocess_run_uuid FROM resize_job WHERE emote_image_uuid = ($1)",
+            emote_image_uuid
+        )
+        .fetch_optional(&*pool)
+        .await?)
+    }
+
+    // newest first, all of them if there's no state
+    pub async fn all(pool: Arc<PgPool>, state: Option<ResizeJobState>) -> Result<Vec<ResizeJob>> {
+        Ok(match state {
+            Some(state) => sqlx::query_as!(
+                ResizeJob,
+                "SELECT uuid, emote_image_uuid, state as \"state!: ResizeJobState\", attempts, last_error, run_after, create_time, modify_time, reprocess_run_uuid FROM resize_job WHERE state = ($1) ORDER BY create_time DESC",
+                state as ResizeJobState
+            )
+            .fetch_all(&*pool)
+            .await?,
+            None => sqlx::query_as!(
+                ResizeJob,
+                "SELECT uuid, emote_image_uuid, state as \"state!: ResizeJobState\", attempts, last_error, run_after, create_time, modify_time, reprocess_run_uuid FROM resize_job ORDER BY create_time DESC"
+            )
+            .fetch_all(&*pool)
+            .await?,
+        })
+    }
+
+    // Takes the next job that's due, or one whose worker's lease ran out (it went down in the middle of it).
+    // SKIP LOCKED so more than one worker never gets the same job.
+    async fn claim(pool: Arc<PgPool>) -> Result<Option<ResizeJob>> {
+        Ok(sqlx::query_as!(
+            ResizeJob,
+            "UPDATE resize_job SET state = ($1), attempts = attempts + 1, locked_until = current_timestamp + $2::INTEGER * interval '1 second', modify_time = current_timestamp WHERE uuid = (SELECT uuid FROM resize_job WHERE (state = ($3) AND run_after <= current_timestamp) OR (state = ($1) AND locked_until <= current_timestamp) ORDER BY run_after LIMIT 1 FOR UPDATE SKIP LOCKED) RETURNING uuid, emote_image_uuid, state as \"state!: ResizeJobState\", attempts, last_error, run_after, create_time, modify_time, reprocess_run_uuid",
+            ResizeJobState::Running as ResizeJobState,
+            Self::LEASE_SECONDS,
+            ResizeJobState::Queued as ResizeJobState
+        )
+        .fetch_optional(&*pool)
+        .await?)
+    }
+
+    // Pushes the lease back while the job is running. The attempt is how a worker knows the job is still the one
+    // it claimed, and not one another worker took over after the lease ran out.
+    async fn heartbeat(&self, pool: Arc<PgPool>) -> Result<()> {
+        sqlx::query!(
+            "UPDATE resize_job SET locked_until = current_timestamp + $1::INTEGER * interval '1 second' WHERE uuid = ($2) AND state = ($3) AND attempts = ($4)",
+            Self::LEASE_SECONDS,
+            self.uuid,
+            ResizeJobState::Running as ResizeJobState,
+            self.attempts
+        )
+        .execute(&*pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn finish(&self, pool: Arc<PgPool>) -> Result<()> {
+        sqlx::query!(
+            "UPDATE resize_job SET state = ($1), last_error = NULL, locked_until = NULL, modify_time = current_timestamp WHERE uuid = ($2) AND attempts = ($3)",
+            ResizeJobState::Done as ResizeJobState,
+            self.uuid,
+            self.attempts
+        )
+        .execute(&*pool)
+        .await?;
+        Ok(())
+    }
+
+    // Puts the job back in the queue for later, or gives up on it after MAX_ATTEMPTS. Returns which one it was.
+    async fn fail(&self, pool: Arc<PgPool>, message: &str) -> Result<ResizeJobState> {
+        let (state, run_after) = if self.attempts < Self::MAX_ATTEMPTS {
+            let backoff = Self::BACKOFF_SECONDS << (self.attempts - 1).clamp(0, 16);
+            (
+                ResizeJobState::Queued,
+                Utc::now() + Duration::seconds(backoff),
+            )
+        } else {
+            (ResizeJobState::Failed, self.run_after)
+        };
+
+        sqlx::query!(
+            "UPDATE resize_job SET state = ($1), last_error = ($2), run_after = ($3), locked_until = NULL, modify_time = current_timestamp WHERE uuid = ($4) AND attempts = ($5)",
+            state as ResizeJobState,
+            message,
+            run_after,
+            self.uuid,
+            self.attempts
+        )
+        .execute(&*pool)
+        .await?;
+        Ok(state)
+    }
+
+    // Starts over with a full set of attempts, for failed jobs
+    pub async fn retry(pool: Arc<PgPool>, uuid: Uuid) -> Result<Option<ResizeJob>> {
+        Ok(sqlx::query_as!(
+            ResizeJob,
+            "UPDATE resize_job SET state = ($1), attempts = 0, run_after = current_timestamp, modify_time = current_timestamp WHERE uuid = ($2) AND state = ($3) RETURNING uuid, emote_image_uuid, state as \"state!: ResizeJobState\", attempts, last_error, run_after, create_time, modify_time, reprocess_run_uuid",
+            ResizeJobState::Queued as ResizeJobState,
+            uuid,
+            ResizeJobState::Failed as ResizeJobState
+        )
+        .fetch_optional(&*pool)
+        .await?)
+    }
+
+    // Like retry, whatever state the job is in
+    pub async fn requeue(pool: Arc<PgPool>, uuid: Uuid) -> Result<()> {
+        sqlx::query!(
+            "UPDATE resize_job SET state = ($1), attempts = 0, run_after = current_timestamp, modify_time = current_timestamp WHERE uuid = ($2)",
+            ResizeJobState::Queued as ResizeJobState,
+            uuid
+        )
+        .execute(&*pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn retry_failed(pool: Arc<PgPool>) -> Result<u64> {
+        Ok(sqlx::query!(
+            "UPDATE resize_job SET state = ($1), attempts = 0, run_after = current_timestamp, modify_time = current_timestamp WHERE state = ($2)",
+            ResizeJobState::Queued as ResizeJobState,
+            ResizeJobState::Failed as ResizeJobState
+        )
+        .execute(&*pool)
+        .await?
+        .rows_affected())
+    }
+
+    async fn run(&self, pool: Arc<PgPool>) -> Result<()> {
+        info!(
+            "Running resize job {} (attempt {})",
+            self.uuid, self.attempts
+        );
+        // for telling subscribers how it's going
+        let emote_image = EmoteImage::by_uuid(Arc::clone(&pool), self.emote_image_uuid).await?;
+        let publish = |state, error| {
+            let (pool, emote_image) = (Arc::clone(&pool), emote_image.clone());
+            async move {
+                if let Some(emote_image) = &emote_image {
+                    EmoteImageProgress::publish(&pool, emote_image, state, error).await;
+                }
+            }
+        };
+        publish(ResizeJobState::Running, None).await;
+
+        let heartbeat = {
+            let (job, pool) = (self.clone(), Arc::clone(&pool));
+            actix_web::rt::spawn(async move {
+                let period = std::time::Duration::from_secs(Self::HEARTBEAT_SECONDS);
+                loop {
+                    actix_web::rt::time::sleep(period).await;
+                    if let Err(e) = job.heartbeat(Arc::clone(&pool)).await {
+                        warn!("Resize job {} heartbeat failed: {}", job.uuid, e.message);
+                    }
+                }
+            })
+        };
+        let result = EmoteImage::process_resize(Arc::clone(&pool), self.emote_image_uuid).await;
+        heartbeat.abort();
+
+        match result {
+            Ok(()) => {
+                self.finish(Arc::clone(&pool)).await?;
+                publish(ResizeJobState::Done, None).await;
+            }
+            Err(e) => {
+                warn!("Resize job {} failed: {}", self.uuid, e.message);
+                let state = self.fail(Arc::clone(&pool), &e.message).await?;
+                publish(state, Some(e.message)).await;
+            }
+        }
+        Ok(())
+    }
+
+    // Runs queued jobs forever, as many at once as the worker pool has threads.
+    // The rest wait in the table, so a pile of uploads doesn't flood the pool and starve requests.
+    pub async fn work(pool: Arc<PgPool>) {
+        let running = Arc::new(AtomicUsize::new(0));
+        loop {
+            if running.load(Ordering::SeqCst) >= WORKER_POOL.config.threads.max(1) {
+                Self::idle().await;
+                continue;
+            }
+
+            match Self::claim(Arc::clone(&pool)).await {
+                Ok(Some(job)) => {
+                    running.fetch_add(1, Ordering::SeqCst);
+                    let (pool, running) = (Arc::clone(&pool), Arc::clone(&running));
+                    actix_web::rt::spawn(async move {
+                        if let Err(e) = job.run(pool).await {
+                            error!("Resize worker: {}", e.message);
+                        }
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Ok(None) => Self::idle().await,
+                Err(e) => {
+                    error!("Resize worker: {}", e.message);
+                    Self::idle().await;
+                }
+            }
+        }
+    }
+
+    async fn idle() {
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(Self::IDLE_MILLISECONDS)).await;
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::{anyhow, Result};
+use futures_channel::oneshot;
+use lazy_static::lazy_static;
+use log::error;
+use serde::Deserialize;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+    Arc, Mutex,
+};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::EMOTES_CONFIG;
+
+// Image work (decoding, resizing, hashing) and blocking storage IO go through this pool, so they never hold up
+// the async executor that's serving requests.
+lazy_static! {
+    pub static ref WORKER_POOL: WorkerPool = WorkerPool::new(EMOTES_CONFIG.workers);
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct WorkerPoolConfig {
+    // threads doing the work, libvips also splits each image over this many threads
+    pub threads: usize,
+    // how much work can wait for a thread, after that requests are turned away until it clears
+    pub queue_depth: usize,
+}
+
+impl Default for WorkerPoolConfig {
+    fn default() -> Self {
+        Self {
+            threads: 4,
+            queue_depth: 64,
+        }
+    }
+}
+
+// The queue was full, so the work was never started
+#[derive(Debug)]
+pub struct WorkerPoolFull;
+
+impl fmt::Display for WorkerPoolFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The server is too busy to do this right now, try again in a bit"
+        )
+    }
+}
+
+impl std::error::Error for WorkerPoolFull {}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct WorkerPool {
+    pub config: WorkerPoolConfig,
+    sender: Mutex<SyncSender<Job>>,
+    // jobs sent that no thread has picked up yet
+    queued: Arc<AtomicUsize>,
+}
+
+impl WorkerPool {
+    // how often work that has to wait for room checks again
+    const WAIT_MILLISECONDS: u64 = 50;
+
+    fn new(config: WorkerPoolConfig) -> Self {
+        let (sender, receiver) = sync_channel::<Job>(config.queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..config.threads.max(1) {
+            let receiver: Arc<Mutex<Receiver<Job>>> = Arc::clone(&receiver);
+            thread::Builder::new()
+                .name(format!("emotes-worker-{}", i))
+                .spawn(move || loop {
+                    // the lock is let go before the job runs, so the other threads can take the next one
+                    let job = match receiver.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    // a panicking job drops its result sender, which the caller sees as an error
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        error!("A job panicked in the worker pool");
+                    }
+                })
+                .expect("failed to start a worker thread");
+        }
+
+        Self {
+            config,
+            sender: Mutex::new(sender),
+            queued: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.queued() < self.config.queue_depth
+    }
+
+    // Runs `f` on a worker thread, or fails with WorkerPoolFull straight away if too much is waiting already.
+    // For requests, so a busy server says so instead of making everyone wait longer.
+    pub async fn try_run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        match self.send(f) {
+            Ok(receiver) => receiver
+                .await
+                .map_err(|_| anyhow!("The worker doing this panicked"))?,
+            Err(_) => Err(WorkerPoolFull.into()),
+        }
+    }
+
+    // Runs `f` on a worker thread, waiting for room in the queue first. For background work.
+    pub async fn run<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let receiver = loop {
+            match self.send(f) {
+                Ok(receiver) => break receiver,
+                Err(returned) => {
+                    f = returned;
+                    actix_web::rt::time::sleep(Duration::from_millis(Self::WAIT_MILLISECONDS))
+                        .await;
+                }
+            }
+        };
+        receiver
+            .await
+            .map_err(|_| anyhow!("The worker doing this panicked"))?
+    }
+
+    // hands `f` back when the queue is full
+    fn send<T, F>(&self, f: F) -> std::result::Result<oneshot::Receiver<Result<T>>, F>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        if !self.has_room() {
+            return Err(f);
+        }
+
+        let (result_sender, result_receiver) = oneshot::channel();
+        let queued = Arc::clone(&self.queued);
+        // a slot holding `f` so it can be taken back out if the job never gets sent
+        let slot = Arc::new(Mutex::new(Some(f)));
+        let job_slot = Arc::clone(&slot);
+        let job: Job = Box::new(move || {
+            queued.fetch_sub(1, Ordering::SeqCst);
+            let f = job_slot.lock().unwrap().take();
+            if let Some(f) = f {
+                // nobody is waiting for the result anymore if this fails
+                let _ = result_sender.send(f());
+            }
+        });
+
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        match self.sender.lock().unwrap().try_send(job) {
+            Ok(()) => Ok(result_receiver),
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                let f = slot.lock().unwrap().take();
+                Err(f.expect("an unsent job can't have run"))
+            }
+        }
+    }
+}